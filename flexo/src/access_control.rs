@@ -0,0 +1,167 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+/// Decides which clients are served, based on their IP address.
+#[derive(Debug, Clone, Default)]
+pub struct AccessControl {
+    /// If set, only clients within these ranges are permitted.
+    allowed: Option<Vec<IpNet>>,
+    /// Clients within these ranges are never permitted, even if they are also within an allowed range.
+    denied: Vec<IpNet>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidCidr(pub String);
+
+impl AccessControl {
+    /// Each entry is either a CIDR range, such as "192.168.1.0/24" or "fd00::/8", or a single IP address.
+    pub fn new(allowed: &Option<Vec<String>>, denied: &Option<Vec<String>>) -> Result<Self, InvalidCidr> {
+        let allowed = match allowed {
+            None => None,
+            Some(allowed) => Some(parse_ranges(allowed)?),
+        };
+        let denied = match denied {
+            None => vec![],
+            Some(denied) => parse_ranges(denied)?,
+        };
+        Ok(AccessControl {
+            allowed,
+            denied,
+        })
+    }
+
+    pub fn permits(&self, addr: IpAddr) -> bool {
+        // Clients connecting via IPv4 to a socket that listens on an IPv6 address have IPv4-mapped IPv6 addresses.
+        let addr = match addr {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(addr),
+            IpAddr::V4(_) => addr,
+        };
+        if self.denied.iter().any(|range| range.contains(&addr)) {
+            return false;
+        }
+        match &self.allowed {
+            None => true,
+            Some(allowed) => allowed.iter().any(|range| range.contains(&addr)),
+        }
+    }
+}
+
+fn parse_ranges(ranges: &[String]) -> Result<Vec<IpNet>, InvalidCidr> {
+    ranges.iter().map(|s| {
+        let s = s.trim();
+        match (s.parse::<IpNet>(), s.parse::<IpAddr>()) {
+            (Ok(range), _) => Ok(range.trunc()),
+            (_, Ok(addr)) => Ok(IpNet::from(addr)),
+            _ => Err(InvalidCidr(s.to_owned())),
+        }
+    }).collect()
+}
+
+/// Authenticates clients by one of the configured tokens, sent either as bearer token or as the password of HTTP
+/// Basic authentication.
+#[derive(Debug, Clone, Default)]
+pub struct TokenAuthentication {
+    tokens: Vec<String>,
+}
+
+impl TokenAuthentication {
+    pub fn new(tokens: &Option<Vec<String>>) -> Self {
+        let tokens = match tokens {
+            None => vec![],
+            Some(tokens) => tokens.iter().filter(|t| !t.is_empty()).cloned().collect(),
+        };
+        TokenAuthentication {
+            tokens,
+        }
+    }
+
+    /// Returns true if the value of the Authorization header contains one of the tokens, or if no tokens have been
+    /// configured.
+    pub fn authenticates(&self, authorization: Option<&str>) -> bool {
+        if self.tokens.is_empty() {
+            return true;
+        }
+        match authorization.and_then(token_from_authorization) {
+            None => false,
+            Some(token) => self.tokens.iter().any(|t| constant_time_eq(t.as_bytes(), token.as_bytes())),
+        }
+    }
+}
+
+fn token_from_authorization(authorization: &str) -> Option<String> {
+    let mut parts = authorization.trim().splitn(2, ' ');
+    let scheme = parts.next()?;
+    let credentials = parts.next()?.trim();
+    if scheme.eq_ignore_ascii_case("Bearer") {
+        Some(credentials.to_owned())
+    } else if scheme.eq_ignore_ascii_case("Basic") {
+        // The user name is ignored, only the password is compared with the tokens.
+        let decoded = String::from_utf8(base64::decode(credentials).ok()?).ok()?;
+        let mut user_password = decoded.splitn(2, ':');
+        let _user = user_password.next()?;
+        user_password.next().map(str::to_owned)
+    } else {
+        None
+    }
+}
+
+/// Compares the tokens without returning early, so that the time required does not reveal how much of the token
+/// was guessed correctly.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[test]
+fn test_permits_all_by_default() {
+    let access_control = AccessControl::new(&None, &None).unwrap();
+    assert!(access_control.permits("192.168.1.10".parse().unwrap()));
+    assert!(access_control.permits("::1".parse().unwrap()));
+}
+
+#[test]
+fn test_denied_overrides_allowed() {
+    let allowed = Some(vec!["192.168.0.0/16".to_owned(), "fd00::/8".to_owned()]);
+    let denied = Some(vec!["192.168.100.0/24".to_owned(), "192.168.1.13".to_owned()]);
+    let access_control = AccessControl::new(&allowed, &denied).unwrap();
+    assert!(access_control.permits("192.168.1.10".parse().unwrap()));
+    assert!(access_control.permits("fd12::1".parse().unwrap()));
+    assert!(!access_control.permits("192.168.1.13".parse().unwrap()));
+    assert!(!access_control.permits("192.168.100.7".parse().unwrap()));
+    assert!(!access_control.permits("10.0.0.1".parse().unwrap()));
+    assert!(!access_control.permits("::1".parse().unwrap()));
+}
+
+#[test]
+fn test_ipv4_mapped_address() {
+    let allowed = Some(vec!["127.0.0.0/8".to_owned()]);
+    let access_control = AccessControl::new(&allowed, &None).unwrap();
+    assert!(access_control.permits("::ffff:127.0.0.1".parse().unwrap()));
+}
+
+#[test]
+fn test_invalid_range() {
+    let denied = Some(vec!["192.168.1.0/33".to_owned()]);
+    let result = AccessControl::new(&None, &denied);
+    assert_eq!(result.unwrap_err(), InvalidCidr("192.168.1.0/33".to_owned()));
+}
+
+#[test]
+fn test_token_authentication() {
+    let authentication = TokenAuthentication::new(&Some(vec!["secret".to_owned()]));
+    assert!(authentication.authenticates(Some("Bearer secret")));
+    assert!(authentication.authenticates(Some("bearer  secret ")));
+    // base64 of "admin:secret"
+    assert!(authentication.authenticates(Some("Basic YWRtaW46c2VjcmV0")));
+    // base64 of "secret:wrong"
+    assert!(!authentication.authenticates(Some("Basic c2VjcmV0Ondyb25n")));
+    assert!(!authentication.authenticates(Some("Bearer secre")));
+    assert!(!authentication.authenticates(Some("secret")));
+    assert!(!authentication.authenticates(None));
+}
+
+#[test]
+fn test_no_tokens_configured() {
+    let authentication = TokenAuthentication::new(&Some(vec!["".to_owned()]));
+    assert!(authentication.authenticates(None));
+}
@@ -0,0 +1,149 @@
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::net::IpAddr;
+use std::sync::Mutex;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::json;
+
+use crate::event_loop::Exchange;
+use crate::mirror_config::AccessLogFormat;
+#[cfg(test)]
+use crate::PayloadOrigin;
+
+/// Writes one line per request, so that the requests can be analyzed separately from the application log.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    destination: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    /// The destination is either "stdout", or the path of a file which the lines are appended to.
+    pub fn new(destination: &str, format: AccessLogFormat) -> io::Result<Self> {
+        let destination: Box<dyn Write + Send> = match destination {
+            "stdout" => Box::new(io::stdout()),
+            path => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        };
+        Ok(AccessLog {
+            format,
+            destination: Mutex::new(destination),
+        })
+    }
+
+    pub fn log(&self, exchange: &Exchange) {
+        let line = match self.format {
+            AccessLogFormat::Combined => combined_log_line(exchange),
+            AccessLogFormat::Json => json_log_line(exchange),
+        };
+        // Write the line at once, so that lines are not interleaved if the file is shared with other processes.
+        let result = self.destination.lock().unwrap().write_all(format!("{}\n", line).as_bytes());
+        if let Err(e) = result {
+            error!("Unable to write to access log: {:?}", e);
+        }
+    }
+}
+
+fn peer_ip(exchange: &Exchange) -> Option<IpAddr> {
+    let ip = exchange.peer?.ip();
+    // Clients connecting via IPv4 to a socket that listens on an IPv6 address have IPv4-mapped IPv6 addresses.
+    match ip {
+        IpAddr::V6(v6) => Some(v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip)),
+        IpAddr::V4(_) => Some(ip),
+    }
+}
+
+/// Escapes the value of a quoted field.
+fn escape(value: &str) -> String {
+    value.chars().flat_map(|c| match c {
+        '"' | '\\' => vec!['\\', c],
+        c if c.is_control() => vec![],
+        c => vec![c],
+    }).collect()
+}
+
+/// The Combined Log Format, followed by the payload origin, the provider and the duration in seconds. Fields that
+/// are not available are logged as "-".
+fn combined_log_line(exchange: &Exchange) -> String {
+    let received: DateTime<Utc> = exchange.received.into();
+    let request_line = match &exchange.request {
+        None => "-".to_owned(),
+        Some((method, path)) => format!("{} {} HTTP/1.1", method, escape(path)),
+    };
+    format!("{} - - [{}] \"{}\" {} {} \"-\" \"{}\" {} \"{}\" {:.3}",
+            peer_ip(exchange).map(|ip| ip.to_string()).unwrap_or_else(|| "-".to_owned()),
+            received.format("%d/%b/%Y:%H:%M:%S %z"),
+            request_line,
+            exchange.reply.status.map(|s| s.to_string()).unwrap_or_else(|| "-".to_owned()),
+            exchange.reply.bytes_sent,
+            exchange.user_agent.as_deref().map(escape).unwrap_or_else(|| "-".to_owned()),
+            exchange.payload_origin.name(),
+            exchange.provider.as_deref().map(escape).unwrap_or_else(|| "-".to_owned()),
+            exchange.duration.as_secs_f64())
+}
+
+fn json_log_line(exchange: &Exchange) -> String {
+    let received: DateTime<Utc> = exchange.received.into();
+    let entry = json!({
+        "time": received.to_rfc3339_opts(SecondsFormat::Millis, true),
+        "peer": peer_ip(exchange).map(|ip| ip.to_string()),
+        "method": exchange.request.as_ref().map(|(method, _)| method),
+        "path": exchange.request.as_ref().map(|(_, path)| path),
+        "status": exchange.reply.status,
+        "bytes_sent": exchange.reply.bytes_sent,
+        "user_agent": exchange.user_agent,
+        "payload_origin": exchange.payload_origin.name(),
+        "provider": exchange.provider,
+        "duration_ms": exchange.duration.as_millis() as u64,
+    });
+    entry.to_string()
+}
+
+#[cfg(test)]
+fn test_exchange() -> Exchange {
+    use std::time::{Duration, Instant, SystemTime};
+    use crate::event_loop::Reply;
+    Exchange {
+        peer: Some("[::ffff:192.168.1.10]:43210".parse().unwrap()),
+        received: SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000),
+        request: Some(("GET", "/core/os/x86_64/core.db".to_owned())),
+        user_agent: Some("pacman/6.0.1 \"test\"".to_owned()),
+        payload_origin: PayloadOrigin::RemoteMirror,
+        provider: Some("https://mirror.example.org/".to_owned()),
+        reply: Reply { status: Some(200), bytes_sent: 1024 },
+        duration: Duration::from_millis(1500),
+        started: Instant::now(),
+    }
+}
+
+#[test]
+fn test_combined_log_line() {
+    let expected = "192.168.1.10 - - [13/Sep/2020:12:26:40 +0000] \"GET /core/os/x86_64/core.db HTTP/1.1\" 200 1024 \
+    \"-\" \"pacman/6.0.1 \\\"test\\\"\" remote_mirror \"https://mirror.example.org/\" 1.500";
+    assert_eq!(combined_log_line(&test_exchange()), expected);
+}
+
+#[test]
+fn test_combined_log_line_invalid_request() {
+    let exchange = Exchange {
+        peer: None,
+        request: None,
+        user_agent: None,
+        payload_origin: PayloadOrigin::NoPayload,
+        provider: None,
+        ..test_exchange()
+    };
+    let expected = "- - - [13/Sep/2020:12:26:40 +0000] \"-\" 200 1024 \"-\" \"-\" no_payload \"-\" 1.500";
+    assert_eq!(combined_log_line(&exchange), expected);
+}
+
+#[test]
+fn test_json_log_line() {
+    let line: serde_json::Value = serde_json::from_str(&json_log_line(&test_exchange())).unwrap();
+    assert_eq!(line["time"], "2020-09-13T12:26:40.000Z");
+    assert_eq!(line["peer"], "192.168.1.10");
+    assert_eq!(line["path"], "/core/os/x86_64/core.db");
+    assert_eq!(line["status"], 200);
+    assert_eq!(line["payload_origin"], "remote_mirror");
+    assert_eq!(line["duration_ms"], 1500);
+}
@@ -0,0 +1,1174 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::io::prelude::*;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::str;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
+
+use crossbeam::channel::{Receiver, Sender, unbounded};
+use flexo::{ProgressNotifier, ProgressSubscription};
+use libc::off64_t;
+use mio::{Events, Interest, Poll, Token, Waker};
+use mio::unix::SourceFd;
+#[cfg(test)]
+use tempfile::tempfile;
+
+use crate::PayloadOrigin;
+use crate::mirror_flexo::{ClientError, DownloadOrder, DownloadProvider, Request, parse_client_header};
+use crate::tls::{TlsAcceptor, TlsStream};
+
+// man 2 read: read() (and similar system calls) will transfer at most 0x7ffff000 bytes.
+#[cfg(not(test))]
+const MAX_SENDFILE_COUNT: usize = 0x7fff_f000;
+
+// Choose a smaller size in test, this makes it easier to have fast tests.
+#[cfg(test)]
+const MAX_SENDFILE_COUNT: usize = 128;
+
+const WAKER: Token = Token(0);
+
+const READ_CHUNK_SIZE: usize = 4096;
+
+/// Payloads sent over TLS are read from the file in chunks of this size, which is the maximum size of a TLS record.
+const WRITE_CHUNK_SIZE: usize = 16384;
+
+/// Interval in which we check for transfers that are waiting for a download that does not make any progress.
+const STALL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Processes the requests received by the event loop. Unless noted otherwise, methods are called from the worker
+/// threads, so they may block.
+pub trait Handler: Send + Sync + 'static {
+    /// Called by the event loop for each new connection, so it must not block. Requests from clients that are not
+    /// permitted are rejected with ClientError::Forbidden. Not called for clients connected via Unix domain sockets,
+    /// since access to those is restricted by the file permissions of the socket.
+    fn permits(&self, peer: &SocketAddr) -> bool;
+
+    /// Serves the given request, which was received by a listener with the given policy. Small replies are written
+    /// to the client stream directly, while payloads are returned as a Transfer, so that they can be sent by the
+    /// event loop.
+    fn serve(
+        &self,
+        client_stream: &mut ClientStream,
+        request: Request,
+        policy: ListenerPolicy,
+    ) -> Result<Response, ClientError>;
+
+    /// Called if the request could not be served. The connection is closed afterwards.
+    fn handle_error(&self, client_stream: &mut ClientStream, error: ClientError);
+
+    /// Called if the job that downloads the file has finished before all bytes required by the transfer were
+    /// available, e.g. because the remote mirror has failed. Returns true if the file is being downloaded again, or
+    /// if it has been completed in the meantime.
+    fn reattach(&self, growing_file: &mut GrowingFile) -> bool;
+
+    /// Called by the event loop for each new connection, so it must not block.
+    fn connection_opened(&self);
+
+    fn connection_closed(&self, cache_tainted: bool);
+
+    /// Called once the reply to a request has been sent, or once the connection has been closed before the reply
+    /// was complete. May be called by the event loop, so it must not block for long.
+    fn request_completed(&self, exchange: &Exchange);
+}
+
+/// Settings that apply to all clients connected via the same listener.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ListenerPolicy {
+    /// If false, the admin endpoints are not available to clients of this listener.
+    pub admin_endpoints: bool,
+}
+
+pub enum ListenerSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl ListenerSocket {
+    /// Returns the new connection and, unless the client is connected via a Unix domain socket, its address.
+    fn accept(&self) -> io::Result<(ClientSocket, Option<SocketAddr>)> {
+        match self {
+            ListenerSocket::Tcp(tcp) => {
+                let (stream, addr) = tcp.accept()?;
+                Ok((ClientSocket::Plain(stream), Some(addr)))
+            }
+            ListenerSocket::Unix(unix) => {
+                let (stream, _) = unix.accept()?;
+                Ok((ClientSocket::Unix(stream), None))
+            }
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            ListenerSocket::Tcp(tcp) => tcp.set_nonblocking(nonblocking),
+            ListenerSocket::Unix(unix) => unix.set_nonblocking(nonblocking),
+        }
+    }
+}
+
+impl AsRawFd for ListenerSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            ListenerSocket::Tcp(tcp) => tcp.as_raw_fd(),
+            ListenerSocket::Unix(unix) => unix.as_raw_fd(),
+        }
+    }
+}
+
+/// A socket on which the event loop accepts clients.
+pub struct Listener {
+    socket: ListenerSocket,
+    /// Set if clients connecting to this socket need to use TLS.
+    tls: Option<Arc<TlsAcceptor>>,
+    policy: ListenerPolicy,
+}
+
+impl Listener {
+    pub fn plain(socket: ListenerSocket, policy: ListenerPolicy) -> Self {
+        Listener { socket, tls: None, policy }
+    }
+
+    pub fn tls(socket: TcpListener, acceptor: Arc<TlsAcceptor>, policy: ListenerPolicy) -> Self {
+        Listener { socket: ListenerSocket::Tcp(socket), tls: Some(acceptor), policy }
+    }
+}
+
+/// The connection to a client, either unencrypted or encrypted with TLS.
+enum ClientSocket {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+    Unix(UnixStream),
+}
+
+/// The connection to a client. Keeps track of the reply to the current request, for the access log.
+pub struct ClientStream {
+    socket: ClientSocket,
+    reply: Reply,
+}
+
+/// What has been sent to the client in reply to a single request.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Reply {
+    /// The status code, or None if no reply has been sent.
+    pub status: Option<u16>,
+    /// The number of bytes sent, including the header.
+    pub bytes_sent: u64,
+}
+
+impl Reply {
+    fn record(&mut self, bytes: &[u8]) {
+        if self.bytes_sent == 0 {
+            // Each reply starts with the status line, e.g. "HTTP/1.1 200 OK".
+            self.status = bytes.get(9..12)
+                .and_then(|status| str::from_utf8(status).ok())
+                .and_then(|status| status.parse::<u16>().ok());
+        }
+        self.bytes_sent += bytes.len() as u64;
+    }
+}
+
+impl ClientStream {
+    fn new(socket: ClientSocket) -> Self {
+        ClientStream {
+            socket,
+            reply: Reply::default(),
+        }
+    }
+
+    /// Returns None for clients connected via Unix domain sockets, which do not have an IP address.
+    pub fn peer_addr(&self) -> io::Result<Option<SocketAddr>> {
+        match &self.socket {
+            ClientSocket::Plain(tcp) => tcp.peer_addr().map(Some),
+            ClientSocket::Tls(tls) => tls.tcp().peer_addr().map(Some),
+            ClientSocket::Unix(_) => Ok(None),
+        }
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        match &mut self.socket {
+            ClientSocket::Plain(tcp) => tcp.shutdown(how),
+            ClientSocket::Tls(tls) => tls.shutdown(how),
+            ClientSocket::Unix(unix) => unix.shutdown(how),
+        }
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match &self.socket {
+            ClientSocket::Plain(tcp) => tcp.set_nonblocking(nonblocking),
+            ClientSocket::Tls(tls) => tls.tcp().set_nonblocking(nonblocking),
+            ClientSocket::Unix(unix) => unix.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// The events to wait for while the connection waits for the next request. Pending TLS records need to be sent
+    /// before the client continues with the handshake, so we also wait until the socket becomes writable.
+    fn read_interest(&self) -> Interest {
+        match &self.socket {
+            ClientSocket::Tls(tls) if tls.wants_write() => Interest::READABLE | Interest::WRITABLE,
+            _ => Interest::READABLE,
+        }
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match &self.socket {
+            ClientSocket::Plain(tcp) => tcp.set_nodelay(nodelay),
+            ClientSocket::Tls(tls) => tls.tcp().set_nodelay(nodelay),
+            // Unix domain sockets do not delay small segments.
+            ClientSocket::Unix(_) => Ok(()),
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.socket {
+            ClientSocket::Plain(tcp) => tcp.read(buf),
+            ClientSocket::Tls(tls) => tls.read(buf),
+            ClientSocket::Unix(unix) => unix.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = match &mut self.socket {
+            ClientSocket::Plain(tcp) => tcp.write(buf),
+            ClientSocket::Tls(tls) => tls.write(buf),
+            ClientSocket::Unix(unix) => unix.write(buf),
+        }?;
+        self.reply.record(&buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.socket {
+            ClientSocket::Plain(tcp) => tcp.flush(),
+            ClientSocket::Tls(tls) => tls.flush(),
+            ClientSocket::Unix(unix) => unix.flush(),
+        }
+    }
+}
+
+impl AsRawFd for ClientStream {
+    fn as_raw_fd(&self) -> RawFd {
+        match &self.socket {
+            ClientSocket::Plain(tcp) => tcp.as_raw_fd(),
+            ClientSocket::Tls(tls) => tls.tcp().as_raw_fd(),
+            ClientSocket::Unix(unix) => unix.as_raw_fd(),
+        }
+    }
+}
+
+pub struct Response {
+    pub payload: Option<Payload>,
+    /// Set if a new file has been stored in the cache while serving the request.
+    pub cache_tainted: bool,
+    pub payload_origin: PayloadOrigin,
+}
+
+/// A request and the reply sent to the client, see Handler::request_completed.
+#[derive(Debug)]
+pub struct Exchange {
+    /// None for clients connected via Unix domain sockets.
+    pub peer: Option<SocketAddr>,
+    pub received: SystemTime,
+    /// The method and the path of the request, or None if the request could not be parsed.
+    pub request: Option<(&'static str, String)>,
+    pub user_agent: Option<String>,
+    pub payload_origin: PayloadOrigin,
+    /// The provider of the payload, if the payload was downloaded while it was sent to the client.
+    pub provider: Option<String>,
+    pub reply: Reply,
+    /// The time from receiving the request until the reply was complete.
+    pub duration: Duration,
+    pub started: Instant,
+}
+
+impl Exchange {
+    fn new(peer: Option<SocketAddr>, request: Option<&Request>) -> Self {
+        Exchange {
+            peer,
+            received: SystemTime::now(),
+            request: request.map(|r| (r.method.as_str(), format!("/{}", r.path.to_str()))),
+            user_agent: request.and_then(|r| r.user_agent.clone()),
+            payload_origin: PayloadOrigin::NoPayload,
+            provider: None,
+            reply: Reply::default(),
+            duration: Duration::default(),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// The part of a reply that is sent by the event loop, see Handler::serve.
+pub enum Payload {
+    Transfer(Box<Transfer>),
+    Deferred(DeferredReply),
+}
+
+/// A reply that can only be created once a job has finished, e.g. because the payload needs to be downloaded
+/// completely before it can be sent. The event loop waits for the job, so that no worker thread is blocked in the
+/// meantime.
+pub struct DeferredReply {
+    progress: ProgressNotifier,
+    serve: Box<ServeDeferred>,
+}
+
+type ServeDeferred = dyn FnOnce(&mut ClientStream) -> Result<Response, ClientError> + Send;
+
+impl DeferredReply {
+    /// The given function is called by a worker thread once the job with the given progress has finished, it serves
+    /// the request in the same way as Handler::serve.
+    pub fn new<F>(progress: ProgressNotifier, serve: F) -> Self
+        where F: FnOnce(&mut ClientStream) -> Result<Response, ClientError> + Send + 'static
+    {
+        DeferredReply {
+            progress,
+            serve: Box::new(serve),
+        }
+    }
+}
+
+/// A file that is sent to the client while it is still being downloaded.
+pub struct GrowingFile {
+    pub order: DownloadOrder,
+    pub custom_provider: Option<DownloadProvider>,
+    pub progress: ProgressNotifier,
+}
+
+enum Segment {
+    Bytes(Vec<u8>, usize),
+    File { next: u64, end: u64 },
+}
+
+/// The data sent to the client in reply to a single request, consisting of headers and ranges of a file.
+pub struct Transfer {
+    file: File,
+    /// Set if the file is still being downloaded, in which case we need to wait until the requested bytes are
+    /// available.
+    growing_file: Option<GrowingFile>,
+    segments: VecDeque<Segment>,
+    /// The last time bytes of the file were sent, or the transfer was (re)attached to a download.
+    last_progress: Instant,
+    /// A transfer is only reattached once, to avoid keeping the client waiting while all mirrors are failing.
+    reattached: bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum TransferStatus {
+    Completed,
+    WouldBlock,
+    /// The file has the given size, but more bytes are required.
+    WaitingForData(u64),
+}
+
+impl Transfer {
+    pub fn new(file: File, growing_file: Option<GrowingFile>) -> Self {
+        Transfer {
+            file,
+            growing_file,
+            segments: VecDeque::new(),
+            last_progress: Instant::now(),
+            reattached: false,
+        }
+    }
+
+    pub fn push_bytes(&mut self, bytes: Vec<u8>) {
+        if !bytes.is_empty() {
+            self.segments.push_back(Segment::Bytes(bytes, 0));
+        }
+    }
+
+    /// Appends the bytes of the file from offset first (inclusive) to offset end (exclusive).
+    pub fn push_file_range(&mut self, first: u64, end: u64) {
+        if first < end {
+            self.segments.push_back(Segment::File { next: first, end });
+        }
+    }
+
+    /// Sends as much as possible without blocking.
+    fn resume(&mut self, client_stream: &mut ClientStream) -> io::Result<TransferStatus> {
+        while let Some(segment) = self.segments.front_mut() {
+            let segment_completed = match segment {
+                Segment::Bytes(bytes, bytes_sent) => {
+                    match client_stream.write(&bytes[*bytes_sent..]) {
+                        Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+                        Ok(size) => *bytes_sent += size,
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(TransferStatus::WouldBlock),
+                        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                        Err(e) => return Err(e),
+                    }
+                    *bytes_sent == bytes.len()
+                }
+                Segment::File { next, end } => {
+                    let available = match self.growing_file {
+                        Some(_) => self.file.metadata()?.len().min(*end),
+                        None => *end,
+                    };
+                    if *next >= available {
+                        return Ok(TransferStatus::WaitingForData(available));
+                    }
+                    let offset = match &mut client_stream.socket {
+                        // The payload needs to be encrypted, so sendfile cannot be used.
+                        ClientSocket::Tls(tls) => write_payload(&self.file, available, *next, tls)?,
+                        _ => send_payload(&mut self.file, available, *next as i64, client_stream)? as u64,
+                    };
+                    if offset > *next {
+                        self.last_progress = Instant::now();
+                        // The payload bypasses ClientStream::write.
+                        client_stream.reply.bytes_sent += offset - *next;
+                    }
+                    *next = offset;
+                    if *next < available {
+                        return Ok(TransferStatus::WouldBlock);
+                    }
+                    *next == *end
+                }
+            };
+            if segment_completed {
+                self.segments.pop_front();
+            }
+        }
+        match client_stream.flush() {
+            Ok(()) => Ok(TransferStatus::Completed),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(TransferStatus::WouldBlock),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// See ProgressNotifier::notify_on_progress.
+    fn notify_on_progress<F>(&self, size: u64, callback: F) -> ProgressSubscription
+        where F: FnOnce() + Send + 'static
+    {
+        match &self.growing_file {
+            Some(growing_file) => growing_file.progress.notify_on_progress(size, callback),
+            None => ProgressSubscription::Finished,
+        }
+    }
+
+    fn can_reattach(&self) -> bool {
+        self.growing_file.is_some() && !self.reattached
+    }
+
+    fn reattach<H: Handler>(&mut self, handler: &H) -> bool {
+        match &mut self.growing_file {
+            Some(growing_file) if !self.reattached => {
+                self.reattached = true;
+                self.last_progress = Instant::now();
+                handler.reattach(growing_file)
+            }
+            _ => false,
+        }
+    }
+
+    fn is_stalled(&self, timeout: Duration) -> bool {
+        self.last_progress.elapsed() >= timeout
+    }
+
+    fn provider(&self) -> Option<String> {
+        let growing_file = self.growing_file.as_ref()?;
+        growing_file.progress.provider().map(|provider| provider.identifier)
+    }
+}
+
+enum ConnectionState {
+    /// Waiting for the client to send a request.
+    Reading(Vec<u8>),
+    Transferring(Box<Transfer>),
+    /// Waiting for a job to finish before the reply can be served.
+    Waiting(DeferredReply),
+}
+
+struct Connection {
+    stream: ClientStream,
+    state: ConnectionState,
+    cache_tainted: bool,
+    permitted: bool,
+    policy: ListenerPolicy,
+    /// Set while a request is being served, until it is reported to the handler.
+    exchange: Option<Exchange>,
+}
+
+impl Connection {
+    /// Reports the current request to the handler, unless it has been reported already.
+    fn complete_exchange<H: Handler + ?Sized>(&mut self, handler: &H) {
+        if let Some(mut exchange) = self.exchange.take() {
+            exchange.reply = self.stream.reply;
+            exchange.duration = exchange.started.elapsed();
+            if let ConnectionState::Transferring(transfer) = &self.state {
+                exchange.provider = transfer.provider();
+            }
+            handler.request_completed(&exchange);
+        }
+    }
+}
+
+enum Task {
+    Serve(Token, Connection, Result<Request, ClientError>),
+    /// Attach the transfer to a new download, after the previous download has failed.
+    Reattach(Token, Connection),
+    /// Serve the deferred reply, after the job it has been waiting for has finished.
+    ServeDeferred(Token, Connection),
+    Close(Connection),
+}
+
+/// Asks the event loop to shut down, see EventLoop::run.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    tx_shutdown: Sender<Instant>,
+    waker: Arc<Waker>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self, deadline: Instant) {
+        let _ = self.tx_shutdown.send(deadline);
+        let _ = self.waker.wake();
+    }
+}
+
+/// Sent by the worker threads after a request has been processed.
+struct Processed {
+    token: Token,
+    connection: Connection,
+    keep_alive: bool,
+}
+
+/// Accepts clients and sends the payloads to all clients from a single thread. Requests are processed by a bounded
+/// pool of worker threads, because serving a request may involve blocking operations, like waiting for a remote
+/// mirror.
+pub struct EventLoop {
+    poll: Poll,
+    /// The listener with index i is registered with the token i + 1.
+    listeners: Vec<Listener>,
+    connections: HashMap<Token, Connection>,
+    handler: Arc<dyn Handler>,
+    /// Connections with transfers that will be resumed as soon as the download makes progress, and the identifier of
+    /// the subscription.
+    subscribed: HashMap<Token, u64>,
+    next_subscription: u64,
+    /// Transfers are aborted if they have been waiting for the download for this duration.
+    stall_timeout: Duration,
+    last_stall_check: Instant,
+    /// The number of connections that have been handed over to a worker thread to serve a request.
+    num_processing: usize,
+    /// Set once shutdown has been requested.
+    shutdown_deadline: Option<Instant>,
+    first_connection_token: usize,
+    next_token: usize,
+    waker: Arc<Waker>,
+    workers: Vec<JoinHandle<()>>,
+    tx_task: Sender<Task>,
+    rx_processed: Receiver<Processed>,
+    tx_shutdown: Sender<Instant>,
+    rx_shutdown: Receiver<Instant>,
+    /// Receives the connections whose transfers can be resumed because the download has made progress.
+    tx_progress: Sender<(Token, u64)>,
+    rx_progress: Receiver<(Token, u64)>,
+}
+
+impl EventLoop {
+    pub fn new<H: Handler>(
+        listeners: Vec<Listener>,
+        num_worker_threads: usize,
+        stall_timeout: Duration,
+        handler: H,
+    ) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        for (i, listener) in listeners.iter().enumerate() {
+            listener.socket.set_nonblocking(true)?;
+            poll.registry().register(&mut SourceFd(&listener.socket.as_raw_fd()), Token(i + 1), Interest::READABLE)?;
+        }
+        let first_connection_token = listeners.len() + 1;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (tx_task, rx_task) = unbounded::<Task>();
+        let (tx_processed, rx_processed) = unbounded::<Processed>();
+        let (tx_progress, rx_progress) = unbounded::<(Token, u64)>();
+        let (tx_shutdown, rx_shutdown) = unbounded::<Instant>();
+        let handler = Arc::new(handler);
+        let mut workers = Vec::with_capacity(num_worker_threads);
+        for _ in 0..num_worker_threads {
+            let rx_task = rx_task.clone();
+            let tx_processed = tx_processed.clone();
+            let waker = waker.clone();
+            let handler = handler.clone();
+            let worker = std::thread::spawn(move || {
+                for task in rx_task {
+                    match task {
+                        Task::Serve(token, connection, request) => {
+                            let policy = connection.policy;
+                            let processed = process(&*handler, token, connection, |client_stream| {
+                                request.and_then(|request| handler.serve(client_stream, request, policy))
+                            });
+                            tx_processed.send(processed).unwrap();
+                            waker.wake().unwrap();
+                        }
+                        Task::Reattach(token, mut connection) => {
+                            let keep_alive = match &mut connection.state {
+                                ConnectionState::Transferring(transfer) => transfer.reattach(&*handler),
+                                ConnectionState::Reading(_) | ConnectionState::Waiting(_) => true,
+                            };
+                            if !keep_alive {
+                                warn!("Unable to continue the download: Closing connection to the client.");
+                            }
+                            tx_processed.send(Processed { token, connection, keep_alive }).unwrap();
+                            waker.wake().unwrap();
+                        }
+                        Task::ServeDeferred(token, mut connection) => {
+                            let state = std::mem::replace(&mut connection.state, ConnectionState::Reading(Vec::new()));
+                            let processed = match state {
+                                ConnectionState::Waiting(deferred) => {
+                                    process(&*handler, token, connection, deferred.serve)
+                                }
+                                state => {
+                                    connection.state = state;
+                                    Processed { token, connection, keep_alive: true }
+                                }
+                            };
+                            tx_processed.send(processed).unwrap();
+                            waker.wake().unwrap();
+                        }
+                        Task::Close(mut connection) => {
+                            connection.complete_exchange(&*handler);
+                            let mut stream = connection.stream;
+                            let _ = stream.shutdown(Shutdown::Both);
+                            drop(stream);
+                            handler.connection_closed(connection.cache_tainted);
+                        }
+                    }
+                }
+            });
+            workers.push(worker);
+        }
+        Ok(EventLoop {
+            poll,
+            listeners,
+            connections: HashMap::new(),
+            handler,
+            subscribed: HashMap::new(),
+            next_subscription: 0,
+            stall_timeout,
+            last_stall_check: Instant::now(),
+            num_processing: 0,
+            shutdown_deadline: None,
+            first_connection_token,
+            next_token: first_connection_token,
+            waker,
+            workers,
+            tx_task,
+            rx_processed,
+            tx_shutdown,
+            rx_shutdown,
+            tx_progress,
+            rx_progress,
+        })
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle {
+            tx_shutdown: self.tx_shutdown.clone(),
+            waker: self.waker.clone(),
+        }
+    }
+
+    /// Serves clients until shutdown is requested via a ShutdownHandle. During shutdown, no new connections are
+    /// accepted and no further requests are read, but the requests received before are still served. Returns the
+    /// shutdown deadline as soon as all connections are closed, or once the deadline has passed.
+    pub fn run(mut self) -> io::Result<Instant> {
+        let mut events = Events::with_capacity(1024);
+        loop {
+            let stall_check_timeout = match self.subscribed.is_empty() {
+                true => None,
+                false => Some(STALL_CHECK_INTERVAL.checked_sub(self.last_stall_check.elapsed()).unwrap_or_default()),
+            };
+            let shutdown_timeout = self.shutdown_deadline.map(|d| d.saturating_duration_since(Instant::now()));
+            let timeout = match (stall_check_timeout, shutdown_timeout) {
+                (Some(t1), Some(t2)) => Some(t1.min(t2)),
+                (t1, t2) => t1.or(t2),
+            };
+            match self.poll.poll(&mut events, timeout) {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            for event in events.iter() {
+                match event.token() {
+                    WAKER => {}
+                    Token(i) if i < self.first_connection_token => self.accept(i - 1),
+                    token => self.connection_ready(token),
+                }
+            }
+            while let Ok(processed) = self.rx_processed.try_recv() {
+                self.processed(processed);
+            }
+            while let Ok((token, subscription_id)) = self.rx_progress.try_recv() {
+                if self.subscribed.get(&token) == Some(&subscription_id) {
+                    self.subscribed.remove(&token);
+                    match self.connections.get(&token).map(|c| &c.state) {
+                        Some(ConnectionState::Waiting(_)) => self.resume_deferred(token),
+                        _ => self.resume_transfer(token),
+                    }
+                }
+            }
+            if self.last_stall_check.elapsed() >= STALL_CHECK_INTERVAL {
+                self.last_stall_check = Instant::now();
+                self.close_stalled_transfers();
+            }
+            if let Ok(deadline) = self.rx_shutdown.try_recv() {
+                self.begin_shutdown(deadline);
+            }
+            if let Some(deadline) = self.shutdown_deadline {
+                if self.connections.is_empty() && self.num_processing == 0 {
+                    info!("All requests have been served.");
+                    // The remaining tasks only close connections, so the workers terminate quickly.
+                    drop(self.tx_task);
+                    for worker in self.workers {
+                        let _ = worker.join();
+                    }
+                    return Ok(deadline);
+                } else if Instant::now() >= deadline {
+                    warn!("Shutdown deadline has passed: Closing {} connections.",
+                          self.connections.len() + self.num_processing);
+                    return Ok(deadline);
+                }
+            }
+        }
+    }
+
+    /// Stops accepting new connections and closes the connections that are waiting for the next request.
+    fn begin_shutdown(&mut self, deadline: Instant) {
+        if self.shutdown_deadline.is_some() {
+            return;
+        }
+        self.shutdown_deadline = Some(deadline);
+        for listener in self.listeners.drain(..) {
+            let _ = self.poll.registry().deregister(&mut SourceFd(&listener.socket.as_raw_fd()));
+        }
+        let idle = self.connections.iter()
+            .filter(|(_, c)| matches!(c.state, ConnectionState::Reading(_)))
+            .map(|(token, _)| *token)
+            .collect::<Vec<Token>>();
+        for token in idle {
+            self.close(token);
+        }
+        info!("No longer accepting connections, {} transfers and {} requests in progress.",
+              self.connections.len(), self.num_processing);
+    }
+
+    /// Closes the connections to clients that have been waiting too long for the download, so that they can continue
+    /// with another server.
+    fn close_stalled_transfers(&mut self) {
+        let stalled = self.subscribed.keys().filter(|token| {
+            match self.connections.get(token).map(|c| &c.state) {
+                Some(ConnectionState::Transferring(transfer)) => transfer.is_stalled(self.stall_timeout),
+                _ => false,
+            }
+        }).copied().collect::<Vec<Token>>();
+        for token in stalled {
+            warn!("Download has not made any progress for {:?}: Closing connection to the client.", self.stall_timeout);
+            self.close(token);
+        }
+    }
+
+    fn accept(&mut self, listener_index: usize) {
+        let (tls, policy) = match self.listeners.get(listener_index) {
+            Some(listener) => (listener.tls.clone(), listener.policy),
+            // The listeners are removed during shutdown.
+            None => return,
+        };
+        loop {
+            match self.listeners[listener_index].socket.accept() {
+                Ok((socket, addr)) => {
+                    let socket = match (socket, &tls) {
+                        (ClientSocket::Plain(tcp), Some(acceptor)) => match acceptor.accept(tcp) {
+                            Ok(tls) => ClientSocket::Tls(Box::new(tls)),
+                            Err(e) => {
+                                error!("Unable to establish TLS session: {}", e);
+                                continue;
+                            }
+                        },
+                        (socket, _) => socket,
+                    };
+                    let permitted = match addr {
+                        None => {
+                            debug!("Established connection with client via Unix domain socket.");
+                            true
+                        }
+                        Some(addr) => {
+                            debug!("Established connection with client {}.", addr);
+                            let permitted = self.handler.permits(&addr);
+                            if !permitted {
+                                info!("Client {} is not permitted, the request will be rejected.", addr);
+                            }
+                            permitted
+                        }
+                    };
+                    let connection = Connection {
+                        stream: ClientStream::new(socket),
+                        state: ConnectionState::Reading(Vec::new()),
+                        cache_tainted: false,
+                        permitted,
+                        policy,
+                        exchange: None,
+                    };
+                    let token = self.next_token();
+                    self.handler.connection_opened();
+                    if let Err(e) = self.register(token, connection, Interest::READABLE) {
+                        warn!("Unable to register connection: {:?}", e);
+                        self.close(token);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    warn!("Unable to accept connection: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Token {
+        let token = Token(self.next_token);
+        self.next_token = self.next_token.checked_add(1).unwrap_or(self.first_connection_token);
+        token
+    }
+
+    /// The connection is added even if registering fails, so that the caller can close it.
+    fn register(&mut self, token: Token, connection: Connection, interest: Interest) -> io::Result<()> {
+        let result = connection.stream.set_nonblocking(true).and_then(|()| {
+            self.poll.registry().register(&mut SourceFd(&connection.stream.as_raw_fd()), token, interest)
+        });
+        self.connections.insert(token, connection);
+        result
+    }
+
+    fn reregister(&mut self, token: Token, interest: Interest) -> io::Result<()> {
+        let connection = self.connections.get(&token).unwrap();
+        self.poll.registry().reregister(&mut SourceFd(&connection.stream.as_raw_fd()), token, interest)
+    }
+
+    /// Removes the connection from the event loop, so that it can be handed over to a worker thread.
+    fn deregister(&mut self, token: Token) -> Option<Connection> {
+        let connection = self.connections.remove(&token)?;
+        self.subscribed.remove(&token);
+        let _ = self.poll.registry().deregister(&mut SourceFd(&connection.stream.as_raw_fd()));
+        Some(connection)
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(connection) = self.deregister(token) {
+            self.tx_task.send(Task::Close(connection)).unwrap();
+        }
+    }
+
+    fn connection_ready(&mut self, token: Token) {
+        match self.connections.get(&token).map(|c| &c.state) {
+            Some(ConnectionState::Reading(_)) => self.read_request(token),
+            Some(ConnectionState::Transferring(_)) => self.resume_transfer(token),
+            // The connection is resumed once the job has finished.
+            Some(ConnectionState::Waiting(_)) | None => {}
+        }
+    }
+
+    fn read_request(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(c) => c,
+            None => return,
+        };
+        let buf = match &mut connection.state {
+            ConnectionState::Reading(buf) => buf,
+            ConnectionState::Transferring(_) | ConnectionState::Waiting(_) => return,
+        };
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        let result = loop {
+            match connection.stream.read(&mut chunk) {
+                Ok(0) => {
+                    // The client has closed the socket.
+                    self.close(token);
+                    return;
+                }
+                Ok(size) => {
+                    buf.extend_from_slice(&chunk[..size]);
+                    match parse_client_header(buf) {
+                        Ok(None) => {}
+                        Ok(Some(request)) => break Ok(request),
+                        Err(e) => break Err(e),
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    let interest = connection.stream.read_interest();
+                    if self.reregister(token, interest).is_err() {
+                        self.close(token);
+                    }
+                    return;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == ErrorKind::TimedOut => break Err(ClientError::TimedOut),
+                Err(e) => break Err(ClientError::Other(e.kind())),
+            }
+        };
+        if let Some(mut connection) = self.deregister(token) {
+            let peer = connection.stream.peer_addr().unwrap_or(None);
+            connection.exchange = Some(Exchange::new(peer, result.as_ref().ok()));
+            connection.stream.reply = Reply::default();
+            let result = if connection.permitted { result } else { Err(ClientError::Forbidden) };
+            connection.state = ConnectionState::Reading(Vec::new());
+            self.num_processing += 1;
+            self.tx_task.send(Task::Serve(token, connection, result)).unwrap();
+        }
+    }
+
+    fn processed(&mut self, processed: Processed) {
+        let Processed { token, connection, keep_alive } = processed;
+        self.num_processing -= 1;
+        let is_transfer = matches!(connection.state, ConnectionState::Transferring(_));
+        let is_waiting = matches!(connection.state, ConnectionState::Waiting(_));
+        if !keep_alive || (!is_transfer && !is_waiting && self.shutdown_deadline.is_some()) {
+            self.tx_task.send(Task::Close(connection)).unwrap();
+            return;
+        }
+        let interest = if is_transfer { Interest::WRITABLE } else { Interest::READABLE };
+        if let Err(e) = self.register(token, connection, interest) {
+            warn!("Unable to register connection: {:?}", e);
+            self.close(token);
+        } else if is_transfer {
+            self.resume_transfer(token);
+        } else if is_waiting {
+            self.resume_deferred(token);
+        } else {
+            // The client may have sent the next request while the previous request was being processed.
+            self.read_request(token);
+        }
+    }
+
+    fn resume_transfer(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(c) => c,
+            None => return,
+        };
+        let transfer = match &mut connection.state {
+            ConnectionState::Transferring(transfer) => transfer,
+            ConnectionState::Reading(_) | ConnectionState::Waiting(_) => return,
+        };
+        let mut job_finished = false;
+        let result = loop {
+            let result = transfer.resume(&mut connection.stream);
+            if self.subscribed.contains_key(&token) {
+                break result;
+            }
+            if let Ok(TransferStatus::WaitingForData(size)) = result {
+                let subscription_id = self.next_subscription;
+                self.next_subscription += 1;
+                let tx_progress = self.tx_progress.clone();
+                let waker = self.waker.clone();
+                let subscription = transfer.notify_on_progress(size, move || {
+                    let _ = tx_progress.send((token, subscription_id));
+                    let _ = waker.wake();
+                });
+                match subscription {
+                    ProgressSubscription::Subscribed => {
+                        self.subscribed.insert(token, subscription_id);
+                    }
+                    ProgressSubscription::ProgressMade => continue,
+                    ProgressSubscription::Finished => job_finished = true,
+                }
+            }
+            break result;
+        };
+        if let Ok(TransferStatus::Completed) | Ok(TransferStatus::WaitingForData(_)) = result {
+            if let Ok(TransferStatus::WaitingForData(_)) = result {
+                // Encrypted payloads may still be buffered.
+                let _ = connection.stream.flush();
+            }
+            // Enabling and then disabling the nodelay option results in a flush.
+            // For some reason, receiver.flush() does not have this effect.
+            let _ = connection.stream.set_nodelay(true);
+            let _ = connection.stream.set_nodelay(false);
+        }
+        match result {
+            Ok(TransferStatus::Completed) => {
+                debug!("Payload has been sent to the client.");
+                connection.complete_exchange(&*self.handler);
+                connection.state = ConnectionState::Reading(Vec::new());
+                self.subscribed.remove(&token);
+                if self.shutdown_deadline.is_some() {
+                    self.close(token);
+                    return;
+                }
+                match self.reregister(token, Interest::READABLE) {
+                    Ok(()) => self.read_request(token),
+                    Err(_) => self.close(token),
+                }
+            }
+            Ok(TransferStatus::WouldBlock) => {
+                // We will be notified when the client is ready to receive more data.
+            }
+            Ok(TransferStatus::WaitingForData(_)) if job_finished && transfer.can_reattach() => {
+                // The download has failed, so the file will only grow if the order is scheduled again.
+                debug!("Download has finished before the file was complete, attempt to reattach the transfer.");
+                if let Some(connection) = self.deregister(token) {
+                    self.num_processing += 1;
+                    self.tx_task.send(Task::Reattach(token, connection)).unwrap();
+                }
+            }
+            Ok(TransferStatus::WaitingForData(_)) if job_finished => {
+                warn!("Download has finished before the file was complete: Closing connection to the client.");
+                self.close(token);
+            }
+            Ok(TransferStatus::WaitingForData(_)) => {
+                // We will be notified when the download has made progress.
+            }
+            Err(e) => {
+                if e.kind() == ErrorKind::BrokenPipe || e.kind() == ErrorKind::ConnectionReset {
+                    debug!("Broken Pipe or Connection reset. Connection closed by client?");
+                } else {
+                    error!("Failed to send payload: An unexpected I/O error has occurred: {:?}", e);
+                }
+                self.close(token);
+            }
+        }
+    }
+
+    /// Hands the connection over to a worker thread to serve the deferred reply, as soon as the job it is waiting for
+    /// has finished.
+    fn resume_deferred(&mut self, token: Token) {
+        let deferred = match self.connections.get(&token).map(|c| &c.state) {
+            Some(ConnectionState::Waiting(deferred)) => deferred,
+            _ => return,
+        };
+        let subscription_id = self.next_subscription;
+        self.next_subscription += 1;
+        let tx_progress = self.tx_progress.clone();
+        let waker = self.waker.clone();
+        // Only the end of the job is of interest: The callback is also invoked whenever the job makes progress, in
+        // which case we subscribe again.
+        let subscription = deferred.progress.notify_on_progress(u64::MAX, move || {
+            let _ = tx_progress.send((token, subscription_id));
+            let _ = waker.wake();
+        });
+        match subscription {
+            ProgressSubscription::Subscribed => {
+                self.subscribed.insert(token, subscription_id);
+            }
+            ProgressSubscription::ProgressMade | ProgressSubscription::Finished => {
+                if let Some(connection) = self.deregister(token) {
+                    self.num_processing += 1;
+                    self.tx_task.send(Task::ServeDeferred(token, connection)).unwrap();
+                }
+            }
+        }
+    }
+}
+
+/// Serves the request with the given function, and prepares the connection for the event loop.
+fn process<H, F>(handler: &H, token: Token, mut connection: Connection, serve: F) -> Processed
+    where H: Handler,
+          F: FnOnce(&mut ClientStream) -> Result<Response, ClientError>
+{
+    let result = connection.stream.set_nonblocking(false)
+        .map_err(ClientError::from)
+        .and_then(|()| serve(&mut connection.stream));
+    let keep_alive = match result {
+        Ok(response) => {
+            connection.cache_tainted |= response.cache_tainted;
+            if let Some(exchange) = &mut connection.exchange {
+                exchange.payload_origin = response.payload_origin;
+            }
+            match response.payload {
+                Some(Payload::Transfer(transfer)) => connection.state = ConnectionState::Transferring(transfer),
+                Some(Payload::Deferred(deferred)) => connection.state = ConnectionState::Waiting(deferred),
+                None => connection.complete_exchange(handler),
+            }
+            true
+        }
+        Err(e) => {
+            handler.handle_error(&mut connection.stream, e);
+            connection.complete_exchange(handler);
+            false
+        }
+    };
+    Processed {
+        token,
+        connection,
+        keep_alive,
+    }
+}
+
+/// Like send_payload, but for receivers that do not support sendfile, e.g. because the payload needs to be encrypted.
+fn write_payload<T: Write>(source: &File, filesize: u64, bytes_sent: u64, receiver: &mut T) -> io::Result<u64> {
+    let mut buf = vec![0; WRITE_CHUNK_SIZE];
+    let mut offset = bytes_sent;
+    while offset < filesize {
+        let count = WRITE_CHUNK_SIZE.min((filesize - offset) as usize);
+        let size = source.read_at(&mut buf[..count], offset)?;
+        if size == 0 {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof));
+        }
+        match receiver.write(&buf[..size]) {
+            Ok(0) => return Err(io::Error::from(ErrorKind::WriteZero)),
+            Ok(written) => offset += written as u64,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(offset)
+}
+
+/// Sends the file, starting at the offset bytes_sent, until the offset filesize is reached or the receiver would
+/// block. Returns the offset of the first byte that has not been sent.
+fn send_payload<T>(source: &mut File, filesize: u64, bytes_sent: i64, receiver: &mut T) -> io::Result<i64>
+    where T: AsRawFd {
+    let fd = source.as_raw_fd();
+    let sfd = receiver.as_raw_fd();
+    let size = unsafe {
+        let mut offset = bytes_sent as off64_t;
+        while (offset as u64) < filesize {
+            let count = MAX_SENDFILE_COUNT.min((filesize - offset as u64) as usize);
+            let size: isize = libc::sendfile64(sfd, fd, &mut offset, count);
+            if size == -1 {
+                let error = std::io::Error::last_os_error();
+                match error.kind() {
+                    ErrorKind::WouldBlock => break,
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(error),
+                }
+            } else if size == 0 {
+                return Err(std::io::Error::from(ErrorKind::UnexpectedEof));
+            }
+        }
+        offset
+    };
+
+    Ok(size)
+}
+
+#[test]
+fn test_filesize_exceeds_sendfile_count() {
+    let mut source: File = tempfile().unwrap();
+    let mut receiver: File = tempfile().unwrap();
+    let array: [u8; MAX_SENDFILE_COUNT * 3] = [b'a'; MAX_SENDFILE_COUNT * 3];
+    source.write(&array).unwrap();
+    source.flush().unwrap();
+    let filesize = source.metadata().unwrap().len();
+    let size = send_payload(&mut source, filesize, 0, &mut receiver).unwrap();
+    assert_eq!(size, (MAX_SENDFILE_COUNT * 3) as i64);
+}
+
+#[test]
+fn test_payload_bounded_by_filesize() {
+    let mut source: File = tempfile().unwrap();
+    let mut receiver: File = tempfile().unwrap();
+    let array: [u8; MAX_SENDFILE_COUNT * 3] = [b'a'; MAX_SENDFILE_COUNT * 3];
+    source.write_all(&array).unwrap();
+    source.flush().unwrap();
+    let size = send_payload(&mut source, 200, 100, &mut receiver).unwrap();
+    assert_eq!(size, 200);
+    assert_eq!(receiver.metadata().unwrap().len(), 100);
+}
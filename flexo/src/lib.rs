@@ -7,10 +7,11 @@ use std::collections::hash_map::Entry;
 use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
 use std::{thread, fmt};
 use std::thread::JoinHandle;
-use serde::Serialize;
-use std::time::{Instant, Duration};
-use crossbeam::channel::{Receiver, Sender, unbounded};
+use serde::{Deserialize, Serialize};
+use std::time::{Instant, Duration, SystemTime};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Sender, unbounded};
 use crate::provider_guards::{ProviderGuards, ProviderChoice, ProviderGuard};
+use std::cmp::Reverse;
 use std::fmt::{Display, Formatter};
 
 const NUM_MAX_ATTEMPTS: i32 = 25;
@@ -21,6 +22,12 @@ const TIMEOUT_ALL_RETRIES: Duration = Duration::from_secs(4);
 
 pub const LOGICAL_CLOCK_INITIAL_VALUE: u32 = 1;
 
+// Smaller downloads are dominated by latency, so they say little about the throughput of a provider.
+const MIN_THROUGHPUT_SAMPLE_SIZE: u64 = 1024 * 1024;
+
+// The weight of the most recent download in the throughput estimate of a provider.
+const THROUGHPUT_SMOOTHING_FACTOR: f64 = 0.3;
+
 #[derive(Debug)]
 pub struct JobPartiallyCompleted<J> where J: Job {
     pub channel: J::C,
@@ -72,7 +79,7 @@ pub enum JobResult<J> where J: Job {
     UnexpectedInternalError,
 }
 
-#[derive(PartialEq, Eq, Clone, Debug)]
+#[derive(PartialEq, Clone, Debug)]
 pub enum JobOutcome <J> where J: Job {
     Success(J::P),
     Error(HashMap<ProviderIdentifier, ProviderMetrics>),
@@ -99,10 +106,17 @@ pub trait Provider where
     /// A unique identifier
     fn identifier(&self) -> ProviderIdentifier;
 
-    fn punish(&self, mut provider_metrics: MutexGuard<HashMap<ProviderIdentifier, ProviderMetrics>>) {
-        provider_metrics.entry(self.identifier())
-            .and_modify(|p| p.num_failures += 1)
-            .or_insert_with(ProviderMetrics::default);
+    fn punish(
+        &self,
+        mut provider_metrics: MutexGuard<HashMap<ProviderIdentifier, ProviderMetrics>>,
+        failure_policy: &FailurePolicy,
+    ) {
+        let metrics = provider_metrics.entry(self.identifier()).or_default();
+        if metrics.record_failure(SystemTime::now(), failure_policy) {
+            warn!("{} has failed {} times in a row: Do not use it for {}.",
+                  self.identifier(), metrics.consecutive_failures,
+                  humantime::format_duration(failure_policy.circuit_breaker_cooldown));
+        }
     }
 }
 
@@ -139,7 +153,7 @@ pub trait Job where Self: std::marker::Sized + std::fmt::Debug + std::marker::Se
     fn get_channel(
         &self,
         channels: &Arc<Mutex<HashMap<Self::P, Self::C>>>,
-        tx: Sender<FlexoProgress>,
+        tx: ProgressNotifier,
         last_chance: bool
     ) -> Result<(Self::C, ChannelEstablishment), Self::OE> {
         let mut channels = channels.lock().unwrap();
@@ -167,14 +181,14 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
     fn new_channel(
         self,
         properties: <<Self as Order>::J as Job>::PR,
-        tx: Sender<FlexoProgress>,
+        tx: ProgressNotifier,
         last_chance: bool,
     ) -> Result<<<Self as Order>::J as Job>::C, <<Self as Order>::J as Job>::OE>;
 
     fn reuse_channel(
         self,
         properties: <<Self as Order>::J as Job>::PR,
-        tx: Sender<FlexoProgress>,
+        tx: ProgressNotifier,
         last_chance: bool,
         channel: <<Self as Order>::J as Job>::C,
     ) -> Result<<<Self as Order>::J as Job>::C, <<Self as Order>::J as Job>::OE>;
@@ -194,9 +208,10 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
         custom_provider: Option<<<Self as Order>::J as Job>::P>,
         channels: Arc<Mutex<HashMap<<<Self as Order>::J as Job>::P, <<Self as Order>::J as Job>::C>>>,
         tx_integration_test: Sender<IntegrationTestMessage>,
-        tx_progress: Sender<FlexoProgress>,
+        tx_progress: ProgressNotifier,
         properties: <<Self as Order>::J as Job>::PR,
         cached_size: u64,
+        failure_policy: FailurePolicy,
     ) -> JobResult<Self::J> {
         let mut num_attempt = 0;
         let mut punished_providers = Vec::new();
@@ -208,21 +223,32 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
             if num_attempt > 1 && start_time.elapsed() > TIMEOUT_ALL_RETRIES {
                 warn!("Unable to complete attempt number {}: The timeout has elapsed.", num_attempt);
             }
-            let (provider_guard, is_last_provider) = self.select_provider(
+            let (provider_guard, is_last_provider, is_trial) = self.select_provider(
                 &provider_guards,
                 &mut provider_metrics.lock().unwrap(),
                 &custom_provider,
                 &unsuccessful_providers,
+                &failure_policy,
             );
+            let _trial_guard = match is_trial {
+                true => Some(TrialGuard {
+                    provider: provider_guard.guarded_provider.identifier(),
+                    provider_metrics: Arc::clone(provider_metrics),
+                }),
+                false => None,
+            };
             debug!("Trying to serve {} via {}", &self.description(), provider_guard.guarded_provider.identifier());
             debug!("No providers are left after this provider? {}", is_last_provider);
             let last_chance = num_attempt >= NUM_MAX_ATTEMPTS || is_last_provider || !self.retryable();
+            tx_progress.next_provider(provider_guard.guarded_provider.identifier());
             send(
                 IntegrationTestMessage::ProviderSelected(provider_guard.guarded_provider.identifier()),
                 &tx_integration_test
             );
             let self_cloned: Self = self.clone();
             let job = provider_guard.guarded_provider.new_job(&properties, self_cloned);
+            let size_before_attempt = tx_progress.size().max(cached_size);
+            let attempt_start = Instant::now();
             debug!("Attempt to establish new connection");
             let channel_result = job.get_channel(&channels, tx_progress.clone(), last_chance);
             let result = match channel_result {
@@ -239,27 +265,45 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
                         IntegrationTestMessage::OrderError,
                         &tx_integration_test
                     );
-                    let _ = tx_progress.send(FlexoProgress::OrderError);
+                    tx_progress.send(FlexoProgress::OrderError);
                     job.handle_error(e)
                 }
             };
+            // The progress lags behind the size of the file if the last chunk is still buffered.
+            let size_after_attempt = match &result {
+                JobResult::Complete(c) => tx_progress.size().max(c.size.max(0) as u64),
+                _ => tx_progress.size(),
+            };
+            Self::record_attempt(
+                provider_guard.guarded_provider.identifier(),
+                &result,
+                size_after_attempt.saturating_sub(size_before_attempt),
+                attempt_start.elapsed(),
+                tx_progress.throughput(),
+                provider_metrics.lock().unwrap(),
+            );
             match &result {
                 JobResult::Complete(_) => {
                     debug!("Job completed with provider {}", provider_guard.guarded_provider.identifier());
+                    let provider = provider_guard.guarded_provider.identifier();
+                    Self::record_success(provider, provider_metrics.lock().unwrap());
                 },
                 JobResult::Partial(partial_job) => {
-                    provider_guard.guarded_provider.punish(provider_metrics.lock().unwrap());
+                    provider_guard.guarded_provider.punish(provider_metrics.lock().unwrap(), &failure_policy);
                     punished_providers.push(provider_guard.guarded_provider.identifier());
                     debug!("Job only partially finished until size {:?}", partial_job.continue_at);
                 },
                 JobResult::Error(e) => {
-                    provider_guard.guarded_provider.punish(provider_metrics.lock().unwrap());
+                    provider_guard.guarded_provider.punish(provider_metrics.lock().unwrap(), &failure_policy);
                     punished_providers.push(provider_guard.guarded_provider.identifier());
                     info!("Error: {:?}, try again", e)
                 },
                 JobResult::Unavailable(_) => {
                     info!("{} is not available at {}",
                           &self.description(), provider_guard.guarded_provider.identifier());
+                    // The provider has replied, so it is reachable.
+                    let provider = provider_guard.guarded_provider.identifier();
+                    Self::record_success(provider, provider_metrics.lock().unwrap());
                 },
                 JobResult::ClientError => {
                     warn!("Unable to finish job: {:?}", &result);
@@ -273,12 +317,16 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
             if result.is_success() || last_chance {
                 break result;
             }
+            if tx_progress.is_cancelled() {
+                info!("{} has been cancelled.", &self.description());
+                break result;
+            }
             if !result.is_success() {
                 unsuccessful_providers.insert(provider_guard.guarded_provider.identifier());
             }
         };
         if !result.is_success() {
-            Self::pardon(punished_providers, provider_metrics.lock().unwrap());
+            Self::pardon(punished_providers, provider_metrics.lock().unwrap(), &failure_policy);
         }
 
         result
@@ -290,45 +338,103 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
         provider_metrics: &'a mut HashMap<ProviderIdentifier, ProviderMetrics>,
         custom_provider: &'a Option<<<Self as Order>::J as Job>::P>,
         exclude_providers: &HashSet<ProviderIdentifier>,
-    ) -> (ProviderGuard<<<Self as Order>:: J as Job>::P>, bool) {
+        failure_policy: &FailurePolicy,
+    ) -> (ProviderGuard<<<Self as Order>:: J as Job>::P>, bool, bool) {
         match custom_provider {
-            Some(p) => (ProviderGuard::new(p.clone()), true),
+            Some(p) => (ProviderGuard::new(p.clone()), true, false),
             None => {
-                let (provider_guard, num_remaining) = provider_guards.get_provider_guard(|p| {
-                    if exclude_providers.contains(&p.identifier()) {
+                let now = SystemTime::now();
+                let choose_provider = |respect_circuit_breakers: bool| provider_guards.get_provider_guard(|p| {
+                    let metric = *(provider_metrics.get(&p.identifier())).unwrap_or(&ProviderMetrics::default());
+                    let circuit_open = respect_circuit_breakers && !metric.permits_attempt(now, failure_policy);
+                    if exclude_providers.contains(&p.identifier()) || circuit_open {
                         ProviderChoice::Exclude
                     } else {
-                        let metric = *(provider_metrics.get(&p.identifier())).unwrap_or(&ProviderMetrics::default());
                         let score = DynamicScore {
-                            num_failures: metric.num_failures,
+                            // Rounded, so that failures which have mostly decayed do not outweigh the initial score.
+                            failure_penalty: metric.failure_penalty(now, failure_policy).round() as u32,
+                            // Providers whose throughput has not been measured yet are preferred, so that each
+                            // provider is attempted at least once before we settle for the fastest one.
+                            throughput_class: metric.throughput
+                                .map(ProviderMetrics::throughput_class)
+                                .unwrap_or(Reverse(u32::MAX)),
                             initial_score: p.initial_score(),
                         };
                         ProviderChoice::Include(score)
                     }
                 });
+                let (provider_guard, num_remaining) = match choose_provider(true) {
+                    Some(choice) => choice,
+                    None => {
+                        // Attempting a provider whose circuit breaker is open is better than not attempting at all.
+                        debug!("The circuit breakers of all remaining providers are open: Ignore them.");
+                        choose_provider(false).unwrap()
+                    }
+                };
                 debug!("Selected provider: {:?}", provider_guard);
-                provider_metrics.entry(provider_guard.guarded_provider.identifier())
-                    .and_modify(|e| {
-                        e.num_usages += 1;
-                    })
-                    .or_insert(ProviderMetrics {
-                        num_usages: 1,
-                        num_failures: 0
-                    });
-                (provider_guard, num_remaining <= 1)
+                let metric = provider_metrics.entry(provider_guard.guarded_provider.identifier()).or_default();
+                metric.num_usages += 1;
+                let is_trial = metric.circuit_state(now, failure_policy) == CircuitState::HalfOpen &&
+                    !metric.trial_in_progress;
+                if is_trial {
+                    info!("Cooldown of {} has elapsed: Attempt a trial order.",
+                          provider_guard.guarded_provider.identifier());
+                    metric.trial_in_progress = true;
+                }
+                (provider_guard, num_remaining <= 1, is_trial)
             }
         }
     }
 
+    /// Closes the circuit breaker of a provider that has fulfilled an order.
+    fn record_success(
+        provider: ProviderIdentifier,
+        mut provider_metrics: MutexGuard<HashMap<ProviderIdentifier, ProviderMetrics>>,
+    ) {
+        let metrics = provider_metrics.entry(provider.clone()).or_default();
+        if metrics.circuit_opened.is_some() {
+            info!("{} is available again.", provider);
+        }
+        metrics.record_success();
+    }
+
+    /// Updates the throughput estimate and the counters that are used for monitoring only.
+    fn record_attempt(
+        provider: ProviderIdentifier,
+        result: &JobResult<<Self as Order>::J>,
+        size_downloaded: u64,
+        duration: Duration,
+        throughput: Option<f64>,
+        mut provider_metrics: MutexGuard<HashMap<ProviderIdentifier, ProviderMetrics>>,
+    ) {
+        let metrics = provider_metrics.entry(provider).or_default();
+        if let Some(throughput) = throughput {
+            metrics.record_throughput(throughput);
+        }
+        match result {
+            JobResult::Partial(_) => metrics.num_partials += 1,
+            JobResult::Error(_) => metrics.num_errors += 1,
+            JobResult::Unavailable(_) => metrics.num_unavailable += 1,
+            JobResult::Complete(_) | JobResult::ClientError | JobResult::UnexpectedInternalError => {}
+        }
+        // Attempts that did not download anything would distort the throughput.
+        if size_downloaded > 0 {
+            metrics.size_downloaded += size_downloaded;
+            metrics.download_time_millis += duration.as_millis() as u64;
+        }
+    }
+
     fn pardon(
         punished_providers: Vec<ProviderIdentifier>,
         mut provider_metrics: MutexGuard<HashMap<ProviderIdentifier, ProviderMetrics>>,
+        failure_policy: &FailurePolicy,
     ) {
+        let now = SystemTime::now();
         for not_guilty in punished_providers {
             match (*provider_metrics).entry(not_guilty.clone()) {
                 Entry::Occupied(mut value) => {
                     let value = value.get_mut();
-                    value.num_failures -= 1;
+                    value.pardon(now, failure_policy);
                 },
                 Entry::Vacant(_) => {},
             }
@@ -336,10 +442,29 @@ pub trait Order where Self: std::marker::Sized + std::clone::Clone + std::cmp::E
     }
 }
 
+/// Allows another trial order once the trial order has ended, including trial orders that have ended without any
+/// indication whether the provider works, e.g. because the job has panicked.
+struct TrialGuard {
+    provider: ProviderIdentifier,
+    provider_metrics: Arc<Mutex<HashMap<ProviderIdentifier, ProviderMetrics>>>,
+}
+
+impl Drop for TrialGuard {
+    fn drop(&mut self) {
+        // A poisoned lock is ignored, so that dropping the guard while the job is unwinding cannot panic again.
+        if let Ok(mut provider_metrics) = self.provider_metrics.lock() {
+            if let Some(metrics) = provider_metrics.get_mut(&self.provider) {
+                metrics.trial_in_progress = false;
+            }
+        }
+    }
+}
+
 /// A score that incorporates information that we have gained while using this provider.
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 struct DynamicScore <S> where S: Ord {
-    num_failures: u32,
+    failure_penalty: u32,
+    throughput_class: Reverse<u32>,
     initial_score: S,
 }
 pub trait Channel where Self: std::marker::Sized + std::fmt::Debug + std::marker::Send + 'static {
@@ -360,7 +485,7 @@ pub struct JobState<J> where J: Job {
     /// reason for using Optional (rather than just JS) is that this way, drop() will called on the JS as soon as we
     /// reset the state to None, so that acquired resources are released as soon as possible.
     pub job_resources: Option<J::JS>,
-    pub tx: Sender<FlexoProgress>,
+    pub tx: ProgressNotifier,
 }
 
 impl <J> JobState<J> where J: Job {
@@ -387,16 +512,186 @@ pub enum OrderState {
 pub struct JobContext<J> where J: Job {
     provider_guards: Arc<ProviderGuards<J::P>>,
     channels: Arc<Mutex<HashMap<J::P, J::C>>>,
-    orders_in_progress: Arc<Mutex<HashSet<J::O>>>,
+    /// Clients waiting for an order in progress subscribe to its ProgressNotifier.
+    orders_in_progress: Arc<Mutex<HashMap<J::O, ProgressNotifier>>>,
     provider_metrics: Arc<Mutex<HashMap<ProviderIdentifier, ProviderMetrics>>>,
+    connectivity: Arc<Mutex<Connectivity>>,
+    failure_policy: FailurePolicy,
     panic_monitor: Vec<Arc<Mutex<i32>>>,
     pub properties: J::PR,
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug, Copy, Default, Serialize)]
+/// Keeps track of whether the providers are reachable. While offline, orders that are not cached are rejected
+/// instead of being fetched from the providers.
+#[derive(Debug, Default)]
+struct Connectivity {
+    offline: bool,
+    consecutive_failures: u32,
+    offline_after_failures: Option<u32>,
+}
+
+impl Connectivity {
+    fn record_job_result<J>(&mut self, result: &JobResult<J>) where J: Job {
+        match result {
+            JobResult::Complete(_) | JobResult::Unavailable(_) => {
+                self.consecutive_failures = 0;
+            }
+            JobResult::Partial(_) | JobResult::Error(_) => {
+                self.consecutive_failures += 1;
+                match self.offline_after_failures {
+                    Some(max_failures) if !self.offline && self.consecutive_failures >= max_failures => {
+                        warn!("Unable to reach any provider for {} consecutive orders: Switch to offline mode.",
+                              self.consecutive_failures);
+                        self.offline = true;
+                    }
+                    _ => {}
+                }
+            }
+            JobResult::ClientError | JobResult::UnexpectedInternalError => {}
+        }
+    }
+}
+
+#[derive(PartialEq, Clone, Debug, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ProviderMetrics {
     pub num_usages: u32,
+    /// Failures are pardoned if no other provider was able to fulfil the order.
     pub num_failures: u32,
+    /// Used to rank the providers: Increased by one for each failure that is not pardoned, and decays over time.
+    pub failure_penalty: f64,
+    /// The time at which failure_penalty had the stored value.
+    pub failure_penalty_updated: Option<SystemTime>,
+    pub consecutive_failures: u32,
+    /// Set while the circuit breaker is open or half-open.
+    pub circuit_opened: Option<SystemTime>,
+    /// Set while the single order that is permitted in the half-open state is in progress.
+    #[serde(skip)]
+    pub trial_in_progress: bool,
+    /// Exponentially weighted moving average of the throughput in bytes per second, used to rank the providers.
+    pub throughput: Option<f64>,
+    // The following counters are only used for monitoring, they are never decreased.
+    pub num_errors: u32,
+    pub num_partials: u32,
+    /// The number of orders that were not available at this provider.
+    pub num_unavailable: u32,
+    pub size_downloaded: u64,
+    pub download_time_millis: u64,
+}
+
+/// Determines how failures affect whether a provider is selected for subsequent orders.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FailurePolicy {
+    /// The failure penalty of a provider halves within this duration.
+    pub penalty_half_life: Duration,
+    /// After this number of consecutive failures, the provider is not selected until the cooldown has elapsed. The
+    /// circuit breaker is disabled if set to 0.
+    pub circuit_breaker_threshold: u32,
+    pub circuit_breaker_cooldown: Duration,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        Self {
+            penalty_half_life: Duration::from_secs(60 * 60),
+            circuit_breaker_threshold: 5,
+            circuit_breaker_cooldown: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum CircuitState {
+    /// The provider is selected as usual.
+    Closed,
+    /// The provider has failed repeatedly and is not selected until the cooldown has elapsed.
+    Open,
+    /// The cooldown has elapsed: A single trial order decides whether the circuit is closed or opened again.
+    HalfOpen,
+}
+
+impl ProviderMetrics {
+    pub fn failure_penalty(&self, now: SystemTime, failure_policy: &FailurePolicy) -> f64 {
+        let elapsed = self.failure_penalty_updated
+            .and_then(|updated| now.duration_since(updated).ok())
+            .unwrap_or_default();
+        let half_life = failure_policy.penalty_half_life.as_secs_f64();
+        if half_life > 0.0 {
+            self.failure_penalty * 0.5_f64.powf(elapsed.as_secs_f64() / half_life)
+        } else {
+            0.0
+        }
+    }
+
+    pub fn circuit_state(&self, now: SystemTime, failure_policy: &FailurePolicy) -> CircuitState {
+        match self.circuit_opened {
+            None => CircuitState::Closed,
+            Some(opened) => match now.duration_since(opened) {
+                Ok(elapsed) if elapsed < failure_policy.circuit_breaker_cooldown => CircuitState::Open,
+                // If the clock was set back, we do not keep the circuit open for longer than the cooldown.
+                _ => CircuitState::HalfOpen,
+            }
+        }
+    }
+
+    fn permits_attempt(&self, now: SystemTime, failure_policy: &FailurePolicy) -> bool {
+        match self.circuit_state(now, failure_policy) {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => !self.trial_in_progress,
+        }
+    }
+
+    fn set_failure_penalty(&mut self, failure_penalty: f64, now: SystemTime) {
+        self.failure_penalty = failure_penalty;
+        self.failure_penalty_updated = Some(now);
+    }
+
+    /// Returns true if the circuit breaker has been opened by this failure.
+    fn record_failure(&mut self, now: SystemTime, failure_policy: &FailurePolicy) -> bool {
+        self.num_failures += 1;
+        self.set_failure_penalty(self.failure_penalty(now, failure_policy) + 1.0, now);
+        self.consecutive_failures += 1;
+        self.trial_in_progress = false;
+        let threshold = failure_policy.circuit_breaker_threshold;
+        let trip = threshold > 0 && self.consecutive_failures >= threshold;
+        // A failed trial order opens the circuit again.
+        if trip && self.circuit_state(now, failure_policy) != CircuitState::Open {
+            self.circuit_opened = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn record_throughput(&mut self, throughput: f64) {
+        self.throughput = Some(match self.throughput {
+            None => throughput,
+            Some(previous) => THROUGHPUT_SMOOTHING_FACTOR * throughput + (1.0 - THROUGHPUT_SMOOTHING_FACTOR) * previous,
+        });
+    }
+
+    /// Providers whose throughput differs by less than a factor of about 1.4 are in the same class, so that they are
+    /// ranked by their initial score. Lower is better.
+    fn throughput_class(throughput: f64) -> Reverse<u32> {
+        Reverse((throughput.max(1.0).log2() * 2.0) as u32)
+    }
+
+    fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.circuit_opened = None;
+        self.trial_in_progress = false;
+    }
+
+    /// Reverts a failure that was not caused by this provider.
+    fn pardon(&mut self, now: SystemTime, failure_policy: &FailurePolicy) {
+        self.num_failures = self.num_failures.saturating_sub(1);
+        self.set_failure_penalty((self.failure_penalty(now, failure_policy) - 1.0).max(0.0), now);
+        self.consecutive_failures = self.consecutive_failures.saturating_sub(1);
+        if self.consecutive_failures < failure_policy.circuit_breaker_threshold {
+            self.circuit_opened = None;
+        }
+    }
 }
 
 impl <J> JobContext<J> where J: Job {
@@ -404,25 +699,101 @@ impl <J> JobContext<J> where J: Job {
         return self.provider_metrics.lock().unwrap().clone();
     }
 
+    /// Returns the orders that are currently being fetched from a provider, along with their progress.
+    pub fn orders_in_progress(&self) -> Vec<(J::O, ProgressNotifier)> {
+        self.orders_in_progress.lock().unwrap()
+            .iter()
+            .map(|(order, progress)| (order.clone(), progress.clone()))
+            .collect()
+    }
+
+    /// The providers used for new orders.
+    pub fn providers(&self) -> Vec<J::P> {
+        self.provider_guards.providers()
+    }
+
+    /// Restores the metrics of a previous run. Metrics of providers that are not in use are discarded, and metrics
+    /// that have already been recorded during this run are retained.
+    pub fn restore_provider_metrics(&mut self, provider_metrics: HashMap<ProviderIdentifier, ProviderMetrics>) {
+        let identifiers = self.provider_guards.providers().iter()
+            .map(|p| p.identifier())
+            .collect::<HashSet<ProviderIdentifier>>();
+        let mut current = self.provider_metrics.lock().unwrap();
+        for (identifier, metrics) in provider_metrics {
+            if identifiers.contains(&identifier) {
+                current.entry(identifier).or_insert(metrics);
+            }
+        }
+    }
+
     pub fn reset_provider_metrics(&mut self) {
         self.provider_metrics.lock().unwrap().clear();
     }
+
+    pub fn is_offline(&self) -> bool {
+        self.connectivity.lock().unwrap().offline
+    }
+
+    pub fn set_offline(&mut self, offline: bool) {
+        let mut connectivity = self.connectivity.lock().unwrap();
+        if connectivity.offline != offline {
+            info!("Offline mode {}.", if offline { "enabled" } else { "disabled" });
+        }
+        connectivity.offline = offline;
+        connectivity.consecutive_failures = 0;
+    }
+
+    pub fn set_failure_policy(&mut self, failure_policy: FailurePolicy) {
+        self.failure_policy = failure_policy;
+    }
+
+    /// Switch to offline mode automatically once the given number of consecutive orders could not be fetched
+    /// from any provider.
+    pub fn set_offline_after_failures(&mut self, num_failures: Option<u32>) {
+        self.connectivity.lock().unwrap().offline_after_failures = num_failures;
+    }
 }
 pub struct ScheduledItem<J> where J: Job {
     pub join_handle: JoinHandle<JobOutcome<J>>,
     pub rx_integration_test: Receiver<IntegrationTestMessage>,
     pub rx_progress: Receiver<FlexoProgress>,
+    pub progress: ProgressNotifier,
 }
 
 pub enum ScheduleOutcome<J> where J: Job {
-    /// The order is already in progress, no new order was scheduled.
-    AlreadyInProgress,
+    /// The order is already in progress, no new order was scheduled. The notifier can be used to follow the progress
+    /// of the job that is already in progress.
+    AlreadyInProgress(ProgressNotifier),
     /// The order has to be fetched from a provider.
     Scheduled(ScheduledItem<J>),
     /// The order is already available in the cache.
     Cached,
     /// the order cannot be cached
     Uncacheable(ProviderGuard<J::P>),
+    /// The order would have to be fetched from a provider, but we are in offline mode.
+    Offline,
+}
+
+/// Describes how an order would be handled if it was scheduled, see JobContext::peek.
+pub enum PeekOutcome<J> where J: Job {
+    /// The order is currently being fetched from a provider.
+    InProgress,
+    /// The order is already available in the cache.
+    Cached,
+    /// the order cannot be cached
+    Uncacheable(ProviderGuard<J::P>),
+    /// The order is neither cached nor in progress, so it would have to be fetched from the given provider.
+    NotCached(ProviderGuard<J::P>),
+    /// The order would have to be fetched from a provider, but we are in offline mode.
+    Offline,
+}
+
+enum CacheDecision<J> where J: Job {
+    Cached,
+    Uncacheable(ProviderGuard<J::P>),
+    /// The order needs to be fetched from a provider, starting after the given number of bytes that are already
+    /// cached.
+    Fetch(u64),
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
@@ -443,6 +814,185 @@ pub enum FlexoProgress {
     OrderError,
 }
 
+/// Broadcasts the progress of a job to all clients that are interested in the order.
+#[derive(Clone, Default)]
+pub struct ProgressNotifier {
+    state: Arc<Mutex<ProgressState>>,
+}
+
+#[derive(Default)]
+struct ProgressState {
+    /// All messages except for FlexoProgress::Progress, so that they can be replayed to late subscribers.
+    history: Vec<FlexoProgress>,
+    size: u64,
+    finished: bool,
+    subscribers: Vec<Sender<FlexoProgress>>,
+    callbacks: Vec<Box<dyn FnOnce() + Send>>,
+    /// Set if a client has given up waiting for the current provider to reply.
+    provider_abandoned: bool,
+    /// Set if the job should stop as soon as possible, e.g. because Flexo is shutting down.
+    cancelled: bool,
+    /// The provider that the job has attempted most recently.
+    provider: Option<ProviderIdentifier>,
+    /// The time the first data was received from the current provider, and the size received since then.
+    transfer: Option<(Instant, u64)>,
+}
+
+/// Returned by ProgressNotifier::notify_on_progress.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ProgressSubscription {
+    /// The callback will be invoked as soon as progress is made.
+    Subscribed,
+    /// Progress has been made already, the callback was not registered.
+    ProgressMade,
+    /// The job has finished, no more progress will be made.
+    Finished,
+}
+
+impl ProgressNotifier {
+    pub fn send(&self, progress: FlexoProgress) {
+        let callbacks = {
+            let mut state = self.state.lock().unwrap();
+            match progress {
+                FlexoProgress::Progress(size) => state.size = state.size.max(size),
+                _ => state.history.push(progress.clone()),
+            }
+            state.subscribers.retain(|tx| tx.send(progress.clone()).is_ok());
+            std::mem::take(&mut state.callbacks)
+        };
+        callbacks.into_iter().for_each(|f| f());
+    }
+
+    /// Returns a receiver for all messages sent from now on. Messages other than FlexoProgress::Progress that have
+    /// been sent before are received first. The receiver is disconnected once the job has finished.
+    pub fn subscribe(&self) -> Receiver<FlexoProgress> {
+        let mut state = self.state.lock().unwrap();
+        let (tx, rx) = unbounded::<FlexoProgress>();
+        for progress in state.history.iter() {
+            let _ = tx.send(progress.clone());
+        }
+        if !state.finished {
+            state.subscribers.push(tx);
+        }
+        rx
+    }
+
+    /// Registers a callback that is invoked once, as soon as more than the given size is available or the job has
+    /// finished.
+    pub fn notify_on_progress<F>(&self, size: u64, callback: F) -> ProgressSubscription
+        where F: FnOnce() + Send + 'static
+    {
+        let mut state = self.state.lock().unwrap();
+        if state.finished {
+            ProgressSubscription::Finished
+        } else if state.size > size {
+            ProgressSubscription::ProgressMade
+        } else {
+            state.callbacks.push(Box::new(callback));
+            ProgressSubscription::Subscribed
+        }
+    }
+
+    /// Asks the job to stop waiting for the given provider and to continue with the next provider instead. Jobs are
+    /// expected to ignore this request if the provider has already replied. The request is ignored if the job has
+    /// already moved on to another provider.
+    pub fn abandon_provider(&self, provider: &ProviderIdentifier) {
+        let mut state = self.state.lock().unwrap();
+        if state.provider.as_ref() == Some(provider) {
+            state.provider_abandoned = true;
+        }
+    }
+
+    /// Returns true if the current provider should be abandoned, see abandon_provider.
+    pub fn is_provider_abandoned(&self) -> bool {
+        self.state.lock().unwrap().provider_abandoned
+    }
+
+    /// Asks the job to stop as soon as possible, without attempting any other providers. The data received so far is
+    /// kept, so that the order can be resumed later.
+    pub fn cancel(&self) {
+        self.state.lock().unwrap().cancelled = true;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.lock().unwrap().cancelled
+    }
+
+    /// Blocks until the job has finished. Returns false if the job has not finished before the deadline.
+    pub fn wait_until_finished(&self, deadline: Instant) -> bool {
+        let rx_progress = self.subscribe();
+        loop {
+            match rx_progress.recv_deadline(deadline) {
+                Ok(_) => {}
+                Err(RecvTimeoutError::Disconnected) => return true,
+                Err(RecvTimeoutError::Timeout) => return false,
+            }
+        }
+    }
+
+    /// The size that is available so far.
+    fn size(&self) -> u64 {
+        self.state.lock().unwrap().size
+    }
+
+    /// The provider that the order is fetched from, or has been fetched from if the job has finished.
+    pub fn provider(&self) -> Option<ProviderIdentifier> {
+        self.state.lock().unwrap().provider.clone()
+    }
+
+    /// Called before the job attempts to fetch the order from another provider.
+    fn next_provider(&self, provider: ProviderIdentifier) {
+        let mut state = self.state.lock().unwrap();
+        state.provider_abandoned = false;
+        state.provider = Some(provider);
+        state.transfer = None;
+    }
+
+    /// Called by the job for each chunk of data received from the provider, so that the throughput of the provider
+    /// can be measured.
+    pub fn record_transfer(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        match &mut state.transfer {
+            // The time to receive the first chunk is mostly latency, so we start measuring once it has arrived.
+            None => state.transfer = Some((Instant::now(), 0)),
+            Some((_, transferred)) => *transferred += size,
+        }
+    }
+
+    /// The throughput of the current provider in bytes per second, if enough data has been received to measure it.
+    fn throughput(&self) -> Option<f64> {
+        let (started, size) = self.state.lock().unwrap().transfer?;
+        let elapsed = started.elapsed().as_secs_f64();
+        if size >= MIN_THROUGHPUT_SAMPLE_SIZE && elapsed > 0.0 {
+            Some(size as f64 / elapsed)
+        } else {
+            None
+        }
+    }
+
+    /// Called when the job has finished, successfully or not.
+    fn finish(&self) {
+        let callbacks = {
+            let mut state = self.state.lock().unwrap();
+            state.finished = true;
+            state.subscribers.clear();
+            std::mem::take(&mut state.callbacks)
+        };
+        callbacks.into_iter().for_each(|f| f());
+    }
+}
+
+impl fmt::Debug for ProgressNotifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+        f.debug_struct("ProgressNotifier")
+            .field("history", &state.history)
+            .field("size", &state.size)
+            .field("finished", &state.finished)
+            .finish()
+    }
+}
+
 #[derive(PartialEq, Eq, Hash, Clone, Debug, Copy)]
 pub enum ChannelEstablishment {
     NewChannel,
@@ -454,20 +1004,41 @@ impl <J> JobContext<J> where J: Job {
         Self::check_duplicates(&initial_providers);
         let provider_guards = Arc::new(ProviderGuards::new(initial_providers));
         let channels: Arc<Mutex<HashMap<J::P, J::C>>> = Arc::new(Mutex::new(HashMap::new()));
-        let orders_in_progress: Arc<Mutex<HashSet<J::O>>> = Arc::new(Mutex::new(HashSet::new()));
+        let orders_in_progress: Arc<Mutex<HashMap<J::O, ProgressNotifier>>> = Arc::new(Mutex::new(HashMap::new()));
         let provider_metrics: Arc<Mutex<HashMap<ProviderIdentifier, ProviderMetrics>>> =
             Arc::new(Mutex::new(HashMap::new()));
+        let connectivity = Arc::new(Mutex::new(Connectivity::default()));
         let thread_mutexes: Vec<Arc<Mutex<i32>>> = Vec::new();
         Self {
             provider_guards,
             channels,
             orders_in_progress,
             provider_metrics,
+            connectivity,
+            failure_policy: FailurePolicy::default(),
             panic_monitor: thread_mutexes,
             properties,
         }
     }
 
+    /// Replaces the providers used for new orders. Orders in progress continue with their current provider.
+    pub fn set_providers(&mut self, providers: Vec<J::P>) {
+        Self::check_duplicates(&providers);
+        // The providers are matched by their identifier, since the other attributes (e.g. the latency test results)
+        // may have changed if the same provider was selected again. Established channels are kept for those providers.
+        let by_identifier = providers.iter()
+            .map(|p| (p.identifier(), p))
+            .collect::<HashMap<ProviderIdentifier, &J::P>>();
+        let mut channels = self.channels.lock().unwrap();
+        *channels = channels.drain()
+            .filter_map(|(provider, channel)| {
+                by_identifier.get(&provider.identifier()).map(|p| ((*p).clone(), channel))
+            })
+            .collect();
+        drop(channels);
+        self.provider_guards = Arc::new(ProviderGuards::new(providers));
+    }
+
     fn check_duplicates(providers: &[J::P]) {
         let mut identifiers: HashSet<ProviderIdentifier> = HashSet::new();
         for p in providers.iter() {
@@ -487,7 +1058,7 @@ impl <J> JobContext<J> where J: Job {
                 // among all available providers.
                 let (guard, _) = self.provider_guards.get_provider_guard(|g| {
                     ProviderChoice::Include(g.initial_score())
-                });
+                }).expect("At least one provider is required");
                 guard
             }
             Some(p) => {
@@ -508,43 +1079,84 @@ impl <J> JobContext<J> where J: Job {
     ) -> ScheduleOutcome<J>
         where <J as Job>::P: Sync
     {
-        let resume_from = resume_from.unwrap_or(0);
-        let cached_size: u64 = {
+        let (cached_size, progress) = {
             let mut orders_in_progress = self.orders_in_progress.lock().unwrap();
-            let cached_size = if orders_in_progress.contains(&order) {
+            if let Some(progress) = orders_in_progress.get(&order) {
                 debug!("order {:?} already in progress: nothing to do.", &order);
-                return ScheduleOutcome::AlreadyInProgress;
-            } else {
-                let cache_state_result = if order.is_cacheable() {
-                    J::cache_state(&order, &self.properties)
-                } else {
-                    None
-                };
-                match cache_state_result {
-                    None if resume_from > 0 => {
-                        // Cannot store this order in cache: See issue #7
-                        return ScheduleOutcome::Uncacheable(self.best_provider(custom_provider));
-                    },
-                    None => 0,
-                    Some(CachedItem { cached_size, .. }) if cached_size < resume_from => {
-                        // Cannot serve this order from cache: See issue #7
-                        return ScheduleOutcome::Uncacheable(self.best_provider(custom_provider));
-                    },
-                    Some(CachedItem { complete_size: Some(c), cached_size }) if c == cached_size => {
-                        debug!("Order {:?} is already cached.", &order);
-                        return ScheduleOutcome::Cached;
-                    },
-                    Some(CachedItem { cached_size, .. }) => cached_size,
-                }
+                return ScheduleOutcome::AlreadyInProgress(progress.clone());
+            }
+            let cached_size = match self.cache_decision(&order, custom_provider.clone(), resume_from) {
+                CacheDecision::Cached => return ScheduleOutcome::Cached,
+                _ if self.is_offline() => return ScheduleOutcome::Offline,
+                CacheDecision::Uncacheable(guard) => return ScheduleOutcome::Uncacheable(guard),
+                CacheDecision::Fetch(cached_size) => cached_size,
             };
-            orders_in_progress.insert(order.clone());
-            cached_size
+            let progress = ProgressNotifier::default();
+            orders_in_progress.insert(order.clone(), progress.clone());
+            (cached_size, progress)
         };
-        self.schedule(order, custom_provider, cached_size)
+        self.schedule(order, custom_provider, cached_size, progress)
+    }
+
+    /// Return info on how the order would be handled by try_schedule, without scheduling anything.
+    pub fn peek(
+        &self,
+        order: &J::O,
+        custom_provider: Option<J::P>,
+        resume_from: Option<u64>,
+    ) -> PeekOutcome<J> {
+        let orders_in_progress = self.orders_in_progress.lock().unwrap();
+        if orders_in_progress.contains_key(order) {
+            return PeekOutcome::InProgress;
+        }
+        match self.cache_decision(order, custom_provider.clone(), resume_from) {
+            CacheDecision::Cached => PeekOutcome::Cached,
+            _ if self.is_offline() => PeekOutcome::Offline,
+            CacheDecision::Uncacheable(guard) => PeekOutcome::Uncacheable(guard),
+            CacheDecision::Fetch(_) => PeekOutcome::NotCached(self.best_provider(custom_provider)),
+        }
+    }
+
+    fn cache_decision(
+        &self,
+        order: &J::O,
+        custom_provider: Option<J::P>,
+        resume_from: Option<u64>,
+    ) -> CacheDecision<J> {
+        let resume_from = resume_from.unwrap_or(0);
+        let cache_state_result = if order.is_cacheable() {
+            J::cache_state(order, &self.properties)
+        } else {
+            None
+        };
+        match cache_state_result {
+            None if resume_from > 0 => {
+                // Cannot store this order in cache: See issue #7
+                CacheDecision::Uncacheable(self.best_provider(custom_provider))
+            },
+            None => CacheDecision::Fetch(0),
+            Some(CachedItem { complete_size: Some(c), cached_size }) if c == cached_size => {
+                // The complete file is available, so any range requested by the client can be served from
+                // cache (or rejected, if it exceeds the file size).
+                debug!("Order {:?} is already cached.", &order);
+                CacheDecision::Cached
+            },
+            Some(CachedItem { cached_size, .. }) if cached_size < resume_from => {
+                // Cannot serve this order from cache: See issue #7
+                CacheDecision::Uncacheable(self.best_provider(custom_provider))
+            },
+            Some(CachedItem { cached_size, .. }) => CacheDecision::Fetch(cached_size),
+        }
     }
 
     /// Schedules the job so that the order will be fetched from the provider.
-    fn schedule(&mut self, order: J::O, custom_provider: Option<J::P>, cached_size: u64) -> ScheduleOutcome<J>
+    fn schedule(
+        &mut self,
+        order: J::O,
+        custom_provider: Option<J::P>,
+        cached_size: u64,
+        progress: ProgressNotifier,
+    ) -> ScheduleOutcome<J>
         where <J as Job>::P: Sync
     {
         let mutex = Arc::new(Mutex::new(0));
@@ -565,13 +1177,16 @@ impl <J> JobContext<J> where J: Job {
         self.panic_monitor.push(mutex);
 
         let (tx_integration_test, rx_integration_test) = unbounded();
-        let (tx_progress, rx_progress) = unbounded::<FlexoProgress>();
+        let rx_progress = progress.subscribe();
+        let tx_progress = progress.clone();
         let channels_cloned = Arc::clone(&self.channels);
         let mut provider_metrics_cloned = Arc::clone(&self.provider_metrics);
         let order_states = Arc::clone(&self.orders_in_progress);
+        let connectivity = Arc::clone(&self.connectivity);
         let provider_guards = Arc::clone(&self.provider_guards);
         let order_cloned = order.clone();
         let properties = self.properties.clone();
+        let failure_policy = self.failure_policy;
 
         let thread = thread::spawn(move || {
             let _lock = mutex_cloned.lock().unwrap();
@@ -582,12 +1197,14 @@ impl <J> JobContext<J> where J: Job {
                 custom_provider,
                 channels_cloned.clone(),
                 tx_integration_test,
-                tx_progress,
+                tx_progress.clone(),
                 properties,
                 cached_size,
+                failure_policy,
             );
             order_states.lock().unwrap().remove(&order_cloned);
-            match result {
+            connectivity.lock().unwrap().record_job_result(&result);
+            let outcome = match result {
                 JobResult::Complete(mut complete_job) => {
                     complete_job.channel.job_state().release_job_resources();
                     let mut channels_cloned = channels_cloned.lock().unwrap();
@@ -618,7 +1235,10 @@ impl <J> JobContext<J> where J: Job {
                     let provider_metrics = provider_metrics_cloned.lock().unwrap().clone();
                     JobOutcome::Error(provider_metrics)
                 }
-            }
+            };
+            // The job resources have been released, so all data has been written.
+            tx_progress.finish();
+            outcome
         });
 
         ScheduleOutcome::Scheduled(
@@ -626,6 +1246,7 @@ impl <J> JobContext<J> where J: Job {
                 join_handle: thread,
                 rx_integration_test,
                 rx_progress,
+                progress,
             }
         )
     }
@@ -647,11 +1268,13 @@ fn send(
 #[test]
 fn test_no_failures_preferred() {
     let s1 = DynamicScore {
-        num_failures: 2,
+        failure_penalty: 2,
+        throughput_class: Reverse(0),
         initial_score: 0,
     };
     let s2 = DynamicScore {
-        num_failures: 0,
+        failure_penalty: 0,
+        throughput_class: Reverse(0),
         initial_score: -1,
     };
     assert!(s2 < s1);
@@ -660,13 +1283,113 @@ fn test_no_failures_preferred() {
 #[test]
 fn test_initial_score_lower_is_better() {
     let s1 = DynamicScore {
-        num_failures: 0,
+        failure_penalty: 0,
+        throughput_class: Reverse(0),
         initial_score: 0,
     };
     let s2 = DynamicScore {
-        num_failures: 0,
+        failure_penalty: 0,
+        throughput_class: Reverse(0),
         initial_score: -1,
     };
     assert!(s2 < s1);
 }
 
+#[test]
+fn test_higher_throughput_preferred() {
+    let s1 = DynamicScore {
+        failure_penalty: 0,
+        throughput_class: ProviderMetrics::throughput_class(10_000_000.0),
+        initial_score: 0,
+    };
+    let s2 = DynamicScore {
+        failure_penalty: 0,
+        throughput_class: ProviderMetrics::throughput_class(1_000_000.0),
+        initial_score: -1,
+    };
+    assert!(s1 < s2);
+    assert_eq!(ProviderMetrics::throughput_class(10_000_000.0), ProviderMetrics::throughput_class(11_000_000.0));
+}
+
+#[test]
+fn test_throughput_moving_average() {
+    let mut metrics = ProviderMetrics::default();
+    metrics.record_throughput(1000.0);
+    assert_eq!(metrics.throughput, Some(1000.0));
+    metrics.record_throughput(2000.0);
+    assert_eq!(metrics.throughput, Some(1300.0));
+}
+
+#[test]
+fn test_failure_penalty_decays() {
+    let failure_policy = FailurePolicy::default();
+    let now = SystemTime::now();
+    let mut metrics = ProviderMetrics::default();
+    metrics.record_failure(now, &failure_policy);
+    metrics.record_failure(now, &failure_policy);
+    assert_eq!(metrics.failure_penalty(now, &failure_policy), 2.0);
+    let later = now + failure_policy.penalty_half_life;
+    assert_eq!(metrics.failure_penalty(later, &failure_policy), 1.0);
+    metrics.record_failure(later, &failure_policy);
+    assert_eq!(metrics.failure_penalty(later, &failure_policy), 2.0);
+}
+
+#[test]
+fn test_circuit_breaker_half_open_after_cooldown() {
+    let failure_policy = FailurePolicy::default();
+    let now = SystemTime::now();
+    let mut metrics = ProviderMetrics::default();
+    for _ in 0..failure_policy.circuit_breaker_threshold - 1 {
+        assert!(!metrics.record_failure(now, &failure_policy));
+    }
+    assert_eq!(metrics.circuit_state(now, &failure_policy), CircuitState::Closed);
+    assert!(metrics.record_failure(now, &failure_policy));
+    assert!(!metrics.permits_attempt(now, &failure_policy));
+    let after_cooldown = now + failure_policy.circuit_breaker_cooldown;
+    assert_eq!(metrics.circuit_state(after_cooldown, &failure_policy), CircuitState::HalfOpen);
+    assert!(metrics.permits_attempt(after_cooldown, &failure_policy));
+    metrics.trial_in_progress = true;
+    assert!(!metrics.permits_attempt(after_cooldown, &failure_policy));
+    // The trial order has failed.
+    assert!(metrics.record_failure(after_cooldown, &failure_policy));
+    assert_eq!(metrics.circuit_state(after_cooldown, &failure_policy), CircuitState::Open);
+    metrics.record_success();
+    assert_eq!(metrics.circuit_state(after_cooldown, &failure_policy), CircuitState::Closed);
+}
+
+#[test]
+fn test_pardon_closes_circuit() {
+    let failure_policy = FailurePolicy { circuit_breaker_threshold: 1, ..FailurePolicy::default() };
+    let now = SystemTime::now();
+    let mut metrics = ProviderMetrics::default();
+    metrics.record_failure(now, &failure_policy);
+    assert_eq!(metrics.circuit_state(now, &failure_policy), CircuitState::Open);
+    metrics.pardon(now, &failure_policy);
+    assert_eq!(metrics.circuit_state(now, &failure_policy), CircuitState::Closed);
+    assert_eq!(metrics.failure_penalty(now, &failure_policy), 0.0);
+    assert_eq!(metrics.num_failures, 0);
+}
+
+#[test]
+fn test_progress_notifier_replays_to_late_subscribers() {
+    let progress = ProgressNotifier::default();
+    progress.send(FlexoProgress::JobSize(10));
+    progress.send(FlexoProgress::Progress(5));
+    let rx_progress = progress.subscribe();
+    progress.finish();
+    assert_eq!(rx_progress.iter().collect::<Vec<FlexoProgress>>(), vec![FlexoProgress::JobSize(10)]);
+    assert_eq!(progress.notify_on_progress(5, || {}), ProgressSubscription::Finished);
+}
+
+#[test]
+fn test_wait_until_finished() {
+    let progress = ProgressNotifier::default();
+    assert!(!progress.wait_until_finished(Instant::now() + Duration::from_millis(10)));
+    let progress_cloned = progress.clone();
+    let join_handle = thread::spawn(move || {
+        progress_cloned.send(FlexoProgress::Progress(5));
+        progress_cloned.finish();
+    });
+    assert!(progress.wait_until_finished(Instant::now() + Duration::from_secs(10)));
+    join_handle.join().unwrap();
+}
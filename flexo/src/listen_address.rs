@@ -0,0 +1,198 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::mem;
+use std::net::{SocketAddr, TcpListener, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+
+use socket2::{Domain, Socket, Type};
+
+use crate::event_loop::ListenerSocket;
+
+/// The first file descriptor passed by systemd, see sd_listen_fds(3).
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+const LISTEN_BACKLOG: i32 = 128;
+
+/// The address of a listener, as specified in the listeners setting.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ListenAddress {
+    /// An IP address and a port, such as "127.0.0.1:7878" or "[::]:7878".
+    Tcp(String),
+    /// The path of a Unix domain socket, specified as "unix:/run/flexo/flexo.sock".
+    Unix(PathBuf),
+    /// Sockets passed by systemd socket activation. Specified as "systemd" to use all sockets, or as
+    /// "systemd:<name>" to use only the sockets with the given FileDescriptorName.
+    Systemd(Option<String>),
+}
+
+impl ListenAddress {
+    pub fn parse(s: &str) -> Self {
+        let s = s.trim();
+        if let Some(path) = s.strip_prefix("unix:") {
+            ListenAddress::Unix(PathBuf::from(path))
+        } else if s == "systemd" {
+            ListenAddress::Systemd(None)
+        } else if let Some(name) = s.strip_prefix("systemd:") {
+            ListenAddress::Systemd(Some(name.to_owned()))
+        } else {
+            ListenAddress::Tcp(s.to_owned())
+        }
+    }
+}
+
+impl Display for ListenAddress {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddress::Systemd(None) => write!(f, "systemd"),
+            ListenAddress::Systemd(Some(name)) => write!(f, "systemd:{}", name),
+        }
+    }
+}
+
+/// The sockets passed by systemd that have not been assigned to a listener yet.
+pub struct InheritedSockets {
+    /// The FileDescriptorName and the file descriptor of each socket.
+    sockets: Vec<(String, RawFd)>,
+}
+
+impl InheritedSockets {
+    /// Takes the sockets from the environment variables set by systemd, see sd_listen_fds(3). The variables are
+    /// removed, so that they are not inherited by child processes.
+    pub fn from_env() -> Self {
+        let listen_pid = std::env::var("LISTEN_PID").ok();
+        let listen_fds = std::env::var("LISTEN_FDS").ok();
+        let listen_fdnames = std::env::var("LISTEN_FDNAMES").ok();
+        for key in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            std::env::remove_var(key);
+        }
+        let sockets = inherited_sockets(
+            listen_pid.as_deref(),
+            listen_fds.as_deref(),
+            listen_fdnames.as_deref(),
+            std::process::id(),
+        );
+        for (_, fd) in &sockets {
+            // Do not pass the sockets on to child processes.
+            unsafe {
+                libc::fcntl(*fd, libc::F_SETFD, libc::FD_CLOEXEC);
+            }
+        }
+        InheritedSockets {
+            sockets,
+        }
+    }
+
+    fn take(&mut self, name: &Option<String>) -> Vec<RawFd> {
+        let (taken, remaining) = mem::take(&mut self.sockets)
+            .into_iter()
+            .partition::<Vec<(String, RawFd)>, _>(|(n, _)| name.as_ref().map(|name| name == n).unwrap_or(true));
+        self.sockets = remaining;
+        taken.into_iter().map(|(_, fd)| fd).collect()
+    }
+}
+
+fn inherited_sockets(
+    listen_pid: Option<&str>,
+    listen_fds: Option<&str>,
+    listen_fdnames: Option<&str>,
+    pid: u32,
+) -> Vec<(String, RawFd)> {
+    // If LISTEN_PID does not match, the variables were meant for another process, e.g. our parent process.
+    if listen_pid.and_then(|p| p.trim().parse::<u32>().ok()) != Some(pid) {
+        return vec![];
+    }
+    let num_fds = match listen_fds.and_then(|n| n.trim().parse::<RawFd>().ok()) {
+        None => return vec![],
+        Some(n) => n,
+    };
+    let mut names = listen_fdnames.map(|names| names.split(':').collect::<Vec<&str>>()).unwrap_or_default();
+    names.resize(num_fds.max(0) as usize, "unknown");
+    (0..num_fds).zip(names).map(|(i, name)| (name.to_owned(), SD_LISTEN_FDS_START + i)).collect()
+}
+
+/// Returns the sockets for the given address. All addresses other than systemd addresses result in a single socket.
+pub fn bind(address: &ListenAddress, inherited: &mut InheritedSockets) -> io::Result<Vec<ListenerSocket>> {
+    match address {
+        ListenAddress::Tcp(addr) => {
+            let addr = addr.to_socket_addrs()?.next().ok_or_else(|| io::Error::from(ErrorKind::AddrNotAvailable))?;
+            Ok(vec![ListenerSocket::Tcp(bind_tcp(addr)?)])
+        }
+        ListenAddress::Unix(path) => {
+            // A socket file left over from a previous run would prevent us from binding to the path.
+            if let Ok(metadata) = fs::symlink_metadata(path) {
+                if metadata.file_type().is_socket() {
+                    fs::remove_file(path)?;
+                }
+            }
+            Ok(vec![ListenerSocket::Unix(UnixListener::bind(path)?)])
+        }
+        ListenAddress::Systemd(name) => {
+            let fds = inherited.take(name);
+            if fds.is_empty() {
+                return Err(io::Error::new(ErrorKind::NotFound, "No matching sockets have been passed by systemd"));
+            }
+            fds.into_iter().map(inherited_socket).collect()
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        // Accept both IPv4 and IPv6 clients, regardless of the system-wide default (net.ipv6.bindv6only).
+        socket.set_only_v6(false)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(LISTEN_BACKLOG)?;
+    Ok(socket.into())
+}
+
+fn inherited_socket(fd: RawFd) -> io::Result<ListenerSocket> {
+    let family = unsafe {
+        let mut addr: libc::sockaddr_storage = mem::zeroed();
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        addr.ss_family as libc::c_int
+    };
+    match family {
+        libc::AF_UNIX => Ok(ListenerSocket::Unix(unsafe { UnixListener::from_raw_fd(fd) })),
+        libc::AF_INET | libc::AF_INET6 => Ok(ListenerSocket::Tcp(unsafe { TcpListener::from_raw_fd(fd) })),
+        _ => Err(io::Error::new(ErrorKind::InvalidInput, format!("Unsupported address family {}", family))),
+    }
+}
+
+#[test]
+fn test_parse_listen_address() {
+    assert_eq!(ListenAddress::parse("[::]:7878"), ListenAddress::Tcp("[::]:7878".to_owned()));
+    assert_eq!(ListenAddress::parse("unix:/run/flexo.sock"), ListenAddress::Unix(PathBuf::from("/run/flexo.sock")));
+    assert_eq!(ListenAddress::parse("systemd"), ListenAddress::Systemd(None));
+    assert_eq!(ListenAddress::parse("systemd:admin"), ListenAddress::Systemd(Some("admin".to_owned())));
+}
+
+#[test]
+fn test_inherited_sockets() {
+    let sockets = inherited_sockets(Some("42"), Some("3"), Some("http:admin"), 42);
+    let expected = vec![("http".to_owned(), 3), ("admin".to_owned(), 4), ("unknown".to_owned(), 5)];
+    assert_eq!(sockets, expected);
+    let mut inherited = InheritedSockets { sockets };
+    assert_eq!(inherited.take(&Some("admin".to_owned())), vec![4]);
+    assert_eq!(inherited.take(&None), vec![3, 5]);
+    assert!(inherited.take(&None).is_empty());
+}
+
+#[test]
+fn test_inherited_sockets_of_other_process() {
+    assert!(inherited_sockets(Some("41"), Some("2"), None, 42).is_empty());
+    assert!(inherited_sockets(None, Some("2"), None, 42).is_empty());
+}
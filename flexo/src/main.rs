@@ -10,48 +10,89 @@ use std::fs::File;
 use std::io;
 use std::io::ErrorKind;
 use std::io::prelude::*;
-use std::net::{TcpListener, TcpStream};
-use std::os::unix::io::AsRawFd;
+use std::net::SocketAddr;
 use std::path;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::{Arc, Mutex};
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 
-use crossbeam::channel::Receiver;
+use crossbeam::channel::{unbounded, Receiver, Sender};
 use crossbeam::channel::RecvTimeoutError;
 use glob::glob;
 use humantime::format_duration;
-use libc::off64_t;
-#[cfg(test)]
-use tempfile::tempfile;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 use uuid::Uuid;
 
 use flexo::*;
 use mirror_flexo::*;
 
+use crate::access_control::{AccessControl, TokenAuthentication};
+use crate::access_log::AccessLog;
+use crate::event_loop::{
+    ClientStream, DeferredReply, EventLoop, Exchange, GrowingFile, Handler, Listener, ListenerPolicy, ListenerSocket,
+    Payload, Response, ShutdownHandle, Transfer,
+};
+use crate::listen_address::{InheritedSockets, ListenAddress};
+use crate::metrics::{Metrics, Snapshot};
 use crate::mirror_cache::{DemarshallError, TimestampedDownloadProviders};
-use crate::mirror_config::{CustomRepo, MirrorConfig, MirrorSelectionMethod};
+use crate::mirror_config::{ConfigError, CustomRepo, MirrorConfig, MirrorSelectionMethod};
 use crate::mirror_fetch::{Mirror, MirrorFetchError};
-use crate::mirror_flexo::RequestMethod::Post;
+use crate::mirror_config::UncacheableFilesMethod::{Proxy, Redirect};
+use crate::mirror_flexo::RequestMethod::{Head, Post};
 use crate::str_path::StrPath;
+use crate::tls::TlsAcceptor;
 
+mod access_control;
+mod access_log;
+mod event_loop;
+mod listen_address;
+mod metrics;
 mod mirror_config;
 mod mirror_fetch;
 mod mirror_cache;
 mod mirror_flexo;
 mod str_path;
+mod tls;
 
-// man 2 read: read() (and similar system calls) will transfer at most 0x7ffff000 bytes.
-#[cfg(not(test))]
-const MAX_SENDFILE_COUNT: usize = 0x7fff_f000;
+/// Endpoints that are only available to clients permitted by admin_allowed_clients and admin_denied_clients, and only
+/// on listeners with admin_endpoints enabled.
+const ADMIN_ENDPOINTS: [&str; 6] = [
+    "metrics", "metrics/prometheus", "reset-metrics", "enable-offline-mode", "disable-offline-mode", "reload-config"
+];
 
-// Choose a smaller size in test, this makes it easier to have fast tests.
-#[cfg(test)]
-const MAX_SENDFILE_COUNT: usize = 128;
+/// All endpoints other than package downloads. If admin_tokens is set, clients need to authenticate for these
+/// endpoints.
+const NON_PACKAGE_ENDPOINTS: [&str; 7] = [
+    "status", "metrics", "metrics/prometheus", "reset-metrics", "enable-offline-mode", "disable-offline-mode",
+    "reload-config"
+];
 
 const TIMEOUT_RECEIVE_CONTENT_LENGTH: Duration = Duration::from_secs(7);
 
+/// If the content length was not received in time, the job is asked to try another provider. The client gives up
+/// after this many providers have been abandoned.
+const MAX_ABANDONED_PROVIDERS: u32 = 3;
+
+/// On shutdown, downloads that have been cancelled are given this duration to write the received data to disk.
+const TIMEOUT_CANCEL_DOWNLOADS: Duration = Duration::from_secs(5);
+
+/// How often the provider metrics are written to disk, if they have changed. They are also written on shutdown.
+const PROVIDER_METRICS_STORE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The minimum time between two attempts to refresh the latency test results, e.g. if the previous attempt has failed
+/// or if the latency tests cannot be run because Flexo is offline.
+const LATENCY_TEST_REFRESH_MIN_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Requests with more ranges than this are answered with the complete file, so that a single request cannot cause
+/// an excessive number of parts to be sent.
+const MAX_BYTE_RANGES: usize = 16;
+
+/// Held while the configuration is reloaded, so that a reload triggered by SIGHUP and a reload triggered by
+/// the reload-config endpoint do not select the mirrors at the same time.
+static CONFIG_RELOAD_MUTEX: Mutex<()> = Mutex::new(());
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 enum PayloadOrigin {
     Cache,
@@ -59,6 +100,17 @@ enum PayloadOrigin {
     NoPayload,
 }
 
+impl PayloadOrigin {
+    /// The name used in the access log and the metrics.
+    fn name(&self) -> &'static str {
+        match self {
+            PayloadOrigin::Cache => "cache",
+            PayloadOrigin::RemoteMirror => "remote_mirror",
+            PayloadOrigin::NoPayload => "no_payload",
+        }
+    }
+}
+
 fn main() {
     env_logger::builder().format_timestamp_millis().init();
 
@@ -78,57 +130,562 @@ fn main() {
             info!("Will switch mirror if download speed falls below {}/s", size_to_human_readable(limit.into()));
         }
     }
-    let job_context: Arc<Mutex<JobContext<DownloadJob>>> = match initialize_job_context(properties.clone()) {
-        Ok(jc) => Arc::new(Mutex::new(jc)),
+    let stored_provider_metrics = stored_provider_metrics(&properties);
+    // Latency tests are only required if mirrors are selected automatically.
+    let interim_providers = match properties.mirror_selection_method {
+        MirrorSelectionMethod::Auto if !properties.offline() => interim_providers(&properties),
+        _ => vec![],
+    };
+    let select_in_background = !interim_providers.is_empty();
+    let mut jc = if select_in_background {
+        info!("Mirrors are selected in the background. In the meantime, requests are served by {} interim mirrors. \
+        Primary mirror: {:#?}", interim_providers.len(), interim_providers[0].uri);
+        JobContext::new(interim_providers, properties.clone())
+    } else {
+        match initialize_job_context(properties.clone()) {
+            Ok(jc) => jc,
+            Err(ProviderSelectionError::NoProviders) => {
+                error!("Unable to find remote mirrors that match the selected criteria. Please \
+                adapt your flexo.toml configuration file. See \
+                https://github.com/nroi/flexo/blob/master/mirror_selection.md for more information.");
+                std::process::exit(1);
+            }
+        }
+    };
+    jc.restore_provider_metrics(stored_provider_metrics.clone());
+    jc.set_offline(properties.offline());
+    jc.set_offline_after_failures(properties.offline_after_failures);
+    jc.set_failure_policy(properties.failure_policy());
+    let job_context: Arc<Mutex<JobContext<DownloadJob>>> = Arc::new(Mutex::new(jc));
+    let listeners = listeners(&properties);
+    let num_worker_threads = properties.num_worker_threads();
+    let stalled_transfer_timeout = properties.stalled_transfer_timeout();
+    let shutdown_timeout = properties.shutdown_timeout();
+    let client_access = match AccessControl::new(&properties.allowed_clients, &properties.denied_clients) {
+        Ok(a) => a,
+        Err(e) => panic!("Invalid setting allowed_clients or denied_clients: {:?}", e),
+    };
+    let admin_access = match AccessControl::new(&properties.admin_allowed_clients, &properties.admin_denied_clients) {
+        Ok(a) => a,
+        Err(e) => panic!("Invalid setting admin_allowed_clients or admin_denied_clients: {:?}", e),
+    };
+    let authentication = TokenAuthentication::new(&properties.admin_tokens);
+    let access_log = properties.access_log.as_ref().map(|destination| {
+        match AccessLog::new(destination, properties.access_log_format()) {
+            Ok(a) => a,
+            Err(e) => panic!("Unable to open access log {}: {:?}", destination, e),
+        }
+    });
+    let handler = FlexoHandler {
+        job_context: job_context.clone(),
+        cache_purger: CachePurger::new(),
+        client_access,
+        admin_access,
+        authentication,
+        access_log,
+        metrics: Metrics::default(),
+    };
+    let event_loop = match EventLoop::new(listeners, num_worker_threads, stalled_transfer_timeout, handler) {
+        Ok(e) => e,
+        Err(e) => panic!("Unable to initialize event loop: {:?}", e),
+    };
+    handle_signals(job_context.clone(), event_loop.shutdown_handle(), shutdown_timeout);
+    store_provider_metrics_periodically(job_context.clone(), properties.clone());
+    if select_in_background {
+        select_providers_in_background(job_context.clone(), properties.clone(), stored_provider_metrics);
+    }
+    refresh_providers_periodically(job_context.clone());
+    let shutdown_deadline = match event_loop.run() {
+        Ok(d) => d,
+        Err(e) => panic!("Event loop has failed: {:?}", e),
+    };
+    finish_downloads(&job_context, shutdown_deadline);
+    store_provider_metrics(&job_context.lock().unwrap().provider_metrics(), &properties);
+    info!("Shutdown complete.");
+}
+
+/// Reloads the configuration on SIGHUP. Shuts down gracefully on SIGTERM or SIGINT, and exits immediately if one of
+/// those signals is received again.
+fn handle_signals(
+    job_context: Arc<Mutex<JobContext<DownloadJob>>>,
+    shutdown_handle: ShutdownHandle,
+    shutdown_timeout: Duration,
+) {
+    let mut signals = match Signals::new([SIGTERM, SIGINT, SIGHUP]) {
+        Ok(s) => s,
+        Err(e) => panic!("Unable to register signal handler: {:?}", e),
+    };
+    std::thread::spawn(move || {
+        let mut shutdown_requested = false;
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                info!("Received signal {}: Reloading configuration.", signal);
+                reload_config_in_background(job_context.clone());
+            } else if shutdown_requested {
+                warn!("Received signal {} again: Exit immediately.", signal);
+                std::process::exit(1);
+            } else {
+                info!("Received signal {}: Shutting down within {}.", signal, format_duration(shutdown_timeout));
+                shutdown_handle.shutdown(Instant::now() + shutdown_timeout);
+                shutdown_requested = true;
+            }
+        }
+    });
+}
+
+/// Returns the provider metrics of the previous run, or an empty map if they are not available.
+fn stored_provider_metrics(properties: &MirrorConfig) -> HashMap<ProviderIdentifier, ProviderMetrics> {
+    match mirror_cache::fetch_provider_metrics(properties) {
+        Ok(provider_metrics) => {
+            debug!("Restore metrics of {} providers from previous run", provider_metrics.len());
+            provider_metrics
+        }
+        Err(DemarshallError::IoError(e)) if e.kind() == ErrorKind::NotFound => {
+            debug!("No provider metrics have been stored yet.");
+            HashMap::new()
+        }
+        Err(e) => {
+            warn!("Unable to restore provider metrics from previous run, will start from scratch: {:?}", e);
+            HashMap::new()
+        }
+    }
+}
+
+/// Selects the mirrors and replaces the interim providers once the selection has completed.
+fn select_providers_in_background(
+    job_context: Arc<Mutex<JobContext<DownloadJob>>>,
+    properties: MirrorConfig,
+    stored_provider_metrics: HashMap<ProviderIdentifier, ProviderMetrics>,
+) {
+    std::thread::spawn(move || {
+        replace_providers(&job_context, &properties, stored_provider_metrics);
+    });
+}
+
+/// Runs the latency tests again once their results are older than refresh_latency_tests_after, so that a
+/// long-running instance does not keep using the same mirrors indefinitely.
+fn refresh_providers_periodically(job_context: Arc<Mutex<JobContext<DownloadJob>>>) {
+    std::thread::spawn(move || loop {
+        // The current properties are used, since the configuration may have been reloaded in the meantime.
+        let properties = job_context.lock().unwrap().properties.clone();
+        std::thread::sleep(time_until_latency_tests_refresh(&properties).max(LATENCY_TEST_REFRESH_MIN_INTERVAL));
+        let properties = job_context.lock().unwrap().properties.clone();
+        if properties.mirror_selection_method != MirrorSelectionMethod::Auto || properties.offline() {
+            continue;
+        }
+        // The latency tests may have been run in the meantime, e.g. because the mirror settings were changed.
+        if time_until_latency_tests_refresh(&properties) > Duration::from_secs(0) {
+            continue;
+        }
+        info!("The latency test results have expired: Selecting mirrors.");
+        replace_providers(&job_context, &properties, HashMap::new());
+    });
+}
+
+/// Returns the time until the latency test results expire, or zero if they have already expired.
+fn time_until_latency_tests_refresh(properties: &MirrorConfig) -> Duration {
+    let refresh_latency_tests_after = properties.refresh_latency_tests_after();
+    let timestamp = match mirror_cache::fetch_download_providers(properties) {
+        Ok(download_providers) => download_providers.timestamp,
+        Err(_) => return refresh_latency_tests_after,
+    };
+    let last_check = match chrono::DateTime::parse_from_rfc3339(&timestamp) {
+        Ok(dt) => dt.with_timezone(&chrono::offset::Utc),
+        Err(e) => {
+            error!("Unable to convert timestamp {:?}: {:?}", &timestamp, e);
+            return refresh_latency_tests_after;
+        }
+    };
+    let age = (chrono::Utc::now() - last_check).to_std().unwrap_or_default();
+    refresh_latency_tests_after.checked_sub(age).unwrap_or_default()
+}
+
+/// Selects the mirrors and replaces the providers in use once the selection has completed. Orders in progress
+/// continue with their current provider, and the metrics of providers that are still in use are retained. The
+/// stored metrics are restored for providers that do not have any metrics yet.
+fn replace_providers(
+    job_context: &Arc<Mutex<JobContext<DownloadJob>>>,
+    properties: &MirrorConfig,
+    stored_provider_metrics: HashMap<ProviderIdentifier, ProviderMetrics>,
+) {
+    // Selecting the mirrors may involve latency tests, so we do not hold the lock in the meantime.
+    let providers = match select_providers(properties) {
+        Ok(providers) => providers,
         Err(ProviderSelectionError::NoProviders) => {
-            error!("Unable to find remote mirrors that match the selected criteria. Please \
-            adapt your flexo.toml configuration file. See \
+            error!("Unable to find remote mirrors that match the selected criteria, continue with the current \
+            mirrors. Please adapt your flexo.toml configuration file. See \
             https://github.com/nroi/flexo/blob/master/mirror_selection.md for more information.");
-            std::process::exit(1);
-        }
-    };
-    let port = job_context.lock().unwrap().properties.port;
-    let listen_ip_address =
-        job_context.lock().unwrap().properties.listen_ip_address.clone().unwrap_or_else(|| "0.0.0.0".to_owned());
-    debug!("Listen on address {}", listen_ip_address);
-    let addr = format!("{}:{}", listen_ip_address, port);
-    let listener = match TcpListener::bind(&addr) {
-        Ok(l) => l,
-        Err(e) => panic!("Unable to listen on address {}: {:?}", &addr, e),
-    };
-    // Synchronize file system access: We only want one cache purging process running at any given time.
-    let cache_purge_mutex = Arc::new(Mutex::new(()));
-
-    for client_stream in listener.incoming() {
-        let client_stream: TcpStream = client_stream.unwrap();
-        debug!("Established connection with client.");
-        let job_context = job_context.clone();
-        let properties = properties.clone();
-        let num_versions_retain = properties.num_versions_retain;
-        let cache_directory = properties.cache_directory.clone();
-        debug!("All set, spawning new thread.");
-        let cache_purge_mutex = cache_purge_mutex.clone();
-        std::thread::spawn(move || {
-            debug!("Started new thread.");
-            let cache_tainted_result = serve_client(job_context, client_stream, properties);
-            match (cache_tainted_result, num_versions_retain) {
-                (Ok(true), Some(0)) => {}
-                (Ok(true), Some(v)) => {
-                    debug!("Cache tainted, waiting for lock before purging cache.");
-                    let _lock = cache_purge_mutex.lock().unwrap();
-                    debug!("Lock acquired, continue to purge cache.");
-                    purge_cache(&cache_directory, v);
-                    purge_cfs_files(&cache_directory);
+            return;
+        }
+    };
+    let mut job_context = job_context.lock().unwrap();
+    if properties.mirrors_changed(&job_context.properties) {
+        // The mirrors have already been selected again after the configuration was reloaded.
+        info!("Mirror settings have changed during the mirror selection: Discard the selected mirrors.");
+        return;
+    }
+    info!("Mirror selection has completed. Primary mirror: {:#?}", providers[0].uri);
+    job_context.set_providers(providers);
+    job_context.restore_provider_metrics(stored_provider_metrics);
+}
+
+/// The provider metrics are stored with the properties used at startup, since the path of the file only changes
+/// after a restart.
+fn store_provider_metrics_periodically(job_context: Arc<Mutex<JobContext<DownloadJob>>>, properties: MirrorConfig) {
+    std::thread::spawn(move || {
+        let mut stored = HashMap::new();
+        loop {
+            std::thread::sleep(PROVIDER_METRICS_STORE_INTERVAL);
+            let provider_metrics = job_context.lock().unwrap().provider_metrics();
+            if provider_metrics != stored {
+                store_provider_metrics(&provider_metrics, &properties);
+                stored = provider_metrics;
+            }
+        }
+    });
+}
+
+fn store_provider_metrics(provider_metrics: &HashMap<ProviderIdentifier, ProviderMetrics>, properties: &MirrorConfig) {
+    if let Err(e) = mirror_cache::store_provider_metrics(properties, provider_metrics) {
+        error!("Unable to store provider metrics: {:?}", e);
+    }
+}
+
+/// Reloading may involve latency tests, so the configuration is reloaded on its own thread. The result is logged.
+fn reload_config_in_background(job_context: Arc<Mutex<JobContext<DownloadJob>>>) {
+    std::thread::spawn(move || {
+        let _ = reload_config(&job_context);
+    });
+}
+
+/// Loads the configuration again and applies it to the running instance. Returns the settings that have been
+/// changed, but take effect only after a restart. If the configuration is invalid, the current configuration is
+/// retained.
+fn reload_config(job_context: &Arc<Mutex<JobContext<DownloadJob>>>) -> Result<Vec<&'static str>, ConfigError> {
+    let _reload_lock = CONFIG_RELOAD_MUTEX.lock().unwrap();
+    let properties = match mirror_config::reload_config() {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Unable to reload configuration, continue with the current configuration: {}", e);
+            return Err(e);
+        }
+    };
+    let current_properties = job_context.lock().unwrap().properties.clone();
+    // Selecting the mirrors may involve latency tests, so we do not hold the lock in the meantime.
+    let providers = if properties.mirrors_changed(&current_properties) {
+        info!("Mirror settings have changed: Selecting mirrors.");
+        match select_providers(&properties) {
+            Ok(providers) => Some(providers),
+            Err(ProviderSelectionError::NoProviders) => {
+                let e = "Unable to find remote mirrors that match the selected criteria".to_owned();
+                let e = ConfigError::InvalidSetting("mirror_selection_method", e);
+                error!("Unable to reload configuration, continue with the current configuration: {}", e);
+                return Err(e);
+            }
+        }
+    } else {
+        None
+    };
+    let settings_requiring_restart = properties.settings_requiring_restart(&current_properties);
+    let mut job_context = job_context.lock().unwrap();
+    if let Some(providers) = providers {
+        if current_properties.mirrors_changed(&job_context.properties) {
+            // Do not replace the mirrors with a selection that is based on outdated settings.
+            let e = "Mirror settings have changed during the mirror selection".to_owned();
+            let e = ConfigError::InvalidSetting("mirror_selection_method", e);
+            error!("Unable to reload configuration, continue with the current configuration: {}", e);
+            return Err(e);
+        }
+        info!("Primary mirror: {:#?}", providers[0].uri);
+        job_context.set_providers(providers);
+    }
+    // Offline mode can also be changed at runtime, so we only apply the setting if it has been changed.
+    if properties.offline != current_properties.offline {
+        job_context.set_offline(properties.offline());
+    }
+    job_context.set_offline_after_failures(properties.offline_after_failures);
+    job_context.set_failure_policy(properties.failure_policy());
+    job_context.properties = properties;
+    if settings_requiring_restart.is_empty() {
+        info!("Configuration has been reloaded.");
+    } else {
+        warn!("Configuration has been reloaded, but the following settings take effect only after a restart: {}",
+              settings_requiring_restart.join(", "));
+    }
+    Ok(settings_requiring_restart)
+}
+
+/// Waits until the downloads in progress have finished. Downloads that have not finished before the deadline are
+/// cancelled, so that the data received so far is written to disk and the download can be resumed after restart.
+fn finish_downloads(job_context: &Arc<Mutex<JobContext<DownloadJob>>>, deadline: Instant) {
+    let (orders_in_progress, properties) = {
+        let job_context = job_context.lock().unwrap();
+        (job_context.orders_in_progress(), job_context.properties.clone())
+    };
+    if !orders_in_progress.is_empty() {
+        info!("Waiting for {} downloads to finish.", orders_in_progress.len());
+    }
+    let unfinished = orders_in_progress
+        .into_iter()
+        .filter(|(_, progress)| !progress.wait_until_finished(deadline))
+        .collect::<Vec<(DownloadOrder, ProgressNotifier)>>();
+    for (order, progress) in &unfinished {
+        warn!("Download of {:?} has not finished in time and will be cancelled.", order.requested_path.to_str());
+        progress.cancel();
+    }
+    let cancel_deadline = Instant::now() + TIMEOUT_CANCEL_DOWNLOADS;
+    for (order, progress) in &unfinished {
+        if progress.wait_until_finished(cancel_deadline) {
+            discard_cancelled_download(order, &properties);
+        } else {
+            error!("Unable to cancel download of {:?}.", order.requested_path.to_str());
+        }
+    }
+    purge_cfs_files(&properties.cache_directory);
+    if let Err(e) = purge_uncacheable_files() {
+        error!("Unable to purge uncacheable files: {:?}", e);
+    }
+}
+
+fn listeners(properties: &MirrorConfig) -> Vec<Listener> {
+    let mut inherited_sockets = InheritedSockets::from_env();
+    // All TLS listeners share the same certificate.
+    let mut tls_acceptor: Option<Arc<TlsAcceptor>> = None;
+    let mut listeners = Vec::new();
+    for listener_config in properties.listeners() {
+        let address = ListenAddress::parse(&listener_config.address);
+        let sockets = match listen_address::bind(&address, &mut inherited_sockets) {
+            Ok(s) => s,
+            Err(e) => panic!("Unable to listen on address {}: {:?}", &address, e),
+        };
+        let policy = ListenerPolicy {
+            admin_endpoints: listener_config.admin_endpoints(),
+        };
+        for socket in sockets {
+            debug!("Listen on address {} with policy {:?}", &address, policy);
+            if listener_config.tls() {
+                let acceptor = tls_acceptor.get_or_insert_with(|| Arc::new(tls_acceptor_from(properties))).clone();
+                match socket {
+                    ListenerSocket::Tcp(tcp) => listeners.push(Listener::tls(tcp, acceptor, policy)),
+                    ListenerSocket::Unix(_) => panic!("TLS is not supported for Unix domain sockets: {}", &address),
                 }
-                _ => {}
+            } else {
+                listeners.push(Listener::plain(socket, policy));
             }
-            match purge_uncacheable_files() {
-                Ok(()) => {}
-                Err(e) => {
-                    error!("Unable to purge uncacheable files: {:?}", e);
+        }
+    }
+    if listeners.is_empty() {
+        panic!("No listeners have been configured. Notice that the setting tls_only requires tls_port to be set.");
+    }
+    listeners
+}
+
+fn tls_acceptor_from(properties: &MirrorConfig) -> TlsAcceptor {
+    let (certificate, private_key) = match (&properties.tls_certificate, &properties.tls_private_key) {
+        (Some(c), Some(k)) => (c, k),
+        _ => panic!("The settings tls_certificate and tls_private_key are required for TLS listeners."),
+    };
+    match TlsAcceptor::new(certificate, private_key) {
+        Ok(a) => a,
+        Err(e) => panic!("Unable to load TLS certificate {}: {}", certificate, e),
+    }
+}
+
+struct FlexoHandler {
+    job_context: Arc<Mutex<JobContext<DownloadJob>>>,
+    cache_purger: CachePurger,
+    client_access: AccessControl,
+    admin_access: AccessControl,
+    authentication: TokenAuthentication,
+    access_log: Option<AccessLog>,
+    metrics: Metrics,
+}
+
+impl FlexoHandler {
+    /// The current configuration, which may change if the configuration is reloaded.
+    fn properties(&self) -> MirrorConfig {
+        self.job_context.lock().unwrap().properties.clone()
+    }
+
+    /// Replies with 404, 403 or 401 if the client is not permitted to access the endpoint.
+    fn endpoint_permitted(
+        &self,
+        client_stream: &mut ClientStream,
+        request: &Request,
+        policy: ListenerPolicy,
+    ) -> io::Result<bool> {
+        let endpoint = request.path.to_str();
+        if is_admin_endpoint(request) {
+            if !policy.admin_endpoints {
+                info!("Admin endpoint {:?} is disabled for this listener: Serve 404", endpoint);
+                serve_404_header(client_stream)?;
+                return Ok(false);
+            }
+            let permitted = match client_stream.peer_addr() {
+                Ok(Some(addr)) => self.admin_access.permits(addr.ip()),
+                // Access to Unix domain sockets is restricted by their file permissions.
+                Ok(None) => true,
+                Err(_) => false,
+            };
+            if !permitted {
+                info!("Client is not permitted to access admin endpoint {:?}: Serve 403", endpoint);
+                serve_403_header(client_stream)?;
+                return Ok(false);
+            }
+        }
+        if !self.authentication.authenticates(request.authorization.as_deref()) {
+            info!("Client has not authenticated for endpoint {:?}: Serve 401", endpoint);
+            serve_401_header(client_stream)?;
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+/// Creates the response to a request that has been served with the given result.
+fn response(
+    request_path: &StrPath,
+    result: Result<(PayloadOrigin, Option<Payload>), ClientError>,
+) -> Result<Response, ClientError> {
+    match result {
+        Ok((payload_origin, Some(Payload::Deferred(deferred)))) => {
+            debug!("Request {:?} will be served once the download has finished.", &request_path.to_str());
+            Ok(Response {
+                payload: Some(Payload::Deferred(deferred)),
+                cache_tainted: false,
+                payload_origin,
+            })
+        }
+        Ok((payload_origin, payload)) => {
+            let payload_origin_human_readable = match payload_origin {
+                PayloadOrigin::Cache => "CACHE HIT",
+                PayloadOrigin::RemoteMirror => "CACHE MISS",
+                PayloadOrigin::NoPayload => "NO PAYLOAD",
+            };
+            info!("Request served [{}]: {:?}", payload_origin_human_readable, &request_path.to_str());
+            Ok(Response {
+                payload,
+                // When the payload is downloaded from a remote mirror, a new file is stored in the cache.
+                cache_tainted: payload_origin == PayloadOrigin::RemoteMirror,
+                payload_origin,
+            })
+        }
+        Err(e) => {
+            error!("Unable to serve request {:?}: {:?}", &request_path.to_str(), e);
+            Err(e)
+        }
+    }
+}
+
+impl Handler for FlexoHandler {
+    fn permits(&self, peer: &SocketAddr) -> bool {
+        self.client_access.permits(peer.ip())
+    }
+
+    fn serve(
+        &self,
+        client_stream: &mut ClientStream,
+        request: Request,
+        policy: ListenerPolicy,
+    ) -> Result<Response, ClientError> {
+        let request_path = request.path.clone();
+        let properties = self.properties();
+        // Requests are dispatched on the path without the custom repo prefix, so the endpoints are checked on this
+        // path as well.
+        let (custom_provider, request) =
+            custom_provider_from_request(request, properties.custom_repo.as_deref().unwrap_or(&[]));
+        if !is_package_request(&request) && !self.endpoint_permitted(client_stream, &request, policy)? {
+            return Ok(Response {
+                payload: None,
+                cache_tainted: false,
+                payload_origin: PayloadOrigin::NoPayload,
+            });
+        }
+        let result = serve_request(
+            self.job_context.clone(), client_stream, properties, &self.metrics, custom_provider, request
+        );
+        response(&request_path, result)
+    }
+
+    fn handle_error(&self, client_stream: &mut ClientStream, error: ClientError) {
+        let _ = handle_client_error(client_stream, error);
+    }
+
+    fn reattach(&self, growing_file: &mut GrowingFile) -> bool {
+        let order = growing_file.order.clone();
+        let custom_provider = growing_file.custom_provider.clone();
+        let result = self.job_context.lock().unwrap().try_schedule(order, custom_provider, None);
+        match result {
+            ScheduleOutcome::AlreadyInProgress(progress) |
+            ScheduleOutcome::Scheduled(ScheduledItem { progress, .. }) => {
+                info!("Continue to download {:?}", growing_file.order.requested_path.to_str());
+                growing_file.progress = progress;
+                true
+            }
+            ScheduleOutcome::Cached => true,
+            ScheduleOutcome::Uncacheable(_) | ScheduleOutcome::Offline => false,
+        }
+    }
+
+    fn connection_opened(&self) {
+        self.metrics.connection_opened();
+    }
+
+    fn connection_closed(&self, cache_tainted: bool) {
+        self.metrics.connection_closed();
+        let properties = self.properties();
+        match (cache_tainted, properties.num_versions_retain) {
+            (true, Some(0)) => {}
+            (true, Some(v)) => {
+                debug!("Cache tainted, request to purge cache.");
+                self.cache_purger.request_purge(properties.cache_directory.clone(), v);
+            }
+            _ => {}
+        }
+        match purge_uncacheable_files() {
+            Ok(()) => {}
+            Err(e) => {
+                error!("Unable to purge uncacheable files: {:?}", e);
+            }
+        }
+    }
+
+    fn request_completed(&self, exchange: &Exchange) {
+        self.metrics.request_completed(exchange);
+        if let Some(access_log) = &self.access_log {
+            access_log.log(exchange);
+        }
+    }
+}
+
+/// Purges the cache on a dedicated thread, so that the worker threads are not blocked while the cache is purged.
+/// Only one purge runs at any given time, and the requests that arrive in the meantime are coalesced into a single
+/// purge.
+struct CachePurger {
+    tx: Sender<(String, u32)>,
+}
+
+impl CachePurger {
+    fn new() -> Self {
+        let (tx, rx) = unbounded::<(String, u32)>();
+        std::thread::spawn(move || {
+            while let Ok(mut request) = rx.recv() {
+                // A single purge covers all requests received so far, so only the most recent settings are applied.
+                while let Ok(next_request) = rx.try_recv() {
+                    request = next_request;
                 }
+                let (cache_directory, num_versions_retain) = request;
+                purge_cache(&cache_directory, num_versions_retain);
+                purge_cfs_files(&cache_directory);
             }
         });
+        CachePurger { tx }
+    }
+
+    fn request_purge(&self, cache_directory: String, num_versions_retain: u32) {
+        if self.tx.send((cache_directory, num_versions_retain)).is_err() {
+            error!("Unable to purge cache: The cache purge thread has terminated.");
+        }
     }
 }
 
@@ -220,6 +777,14 @@ fn purge_uncacheable_files() -> io::Result<()> {
     Ok(())
 }
 
+fn is_admin_endpoint(request: &Request) -> bool {
+    ADMIN_ENDPOINTS.contains(&request.path.to_str())
+}
+
+fn is_package_request(request: &Request) -> bool {
+    !NON_PACKAGE_ENDPOINTS.contains(&request.path.to_str())
+}
+
 fn str_from_vec(v: Vec<u8>) -> Option<String> {
     match String::from_utf8(v) {
         Ok(s) if !s.is_empty() => Some(s),
@@ -234,92 +799,113 @@ fn valid_path(path: &Path) -> bool {
 
 fn serve_request(
     job_context: Arc<Mutex<JobContext<DownloadJob>>>,
-    client_stream: &mut TcpStream,
+    client_stream: &mut impl Write,
     properties: MirrorConfig,
-    get_request: Request,
-) -> Result<PayloadOrigin, ClientError> {
-    let (custom_provider, request) =
-        custom_provider_from_request(get_request, &properties.custom_repo.as_ref().unwrap_or(&vec![]));
+    metrics: &Metrics,
+    custom_provider: Option<DownloadProvider>,
+    request: Request,
+) -> Result<(PayloadOrigin, Option<Payload>), ClientError> {
     if !valid_path(&request.path.as_ref()) {
         info!("Invalid path: Serve 403");
         serve_403_header(client_stream)?;
-        Ok(PayloadOrigin::NoPayload)
+        Ok((PayloadOrigin::NoPayload, None))
     } else if request.path.to_str() == "status" {
         serve_200_ok_empty(client_stream)?;
-        Ok(PayloadOrigin::NoPayload)
+        Ok((PayloadOrigin::NoPayload, None))
     } else if request.path.to_str() == "metrics" {
         let metrics_map: HashMap<String, ProviderMetrics> = job_context.lock().unwrap().provider_metrics()
             .iter()
             .map(|(k, v)| (k.identifier.clone(), *v))
             .collect();
         let serialized = serde_json::to_string_pretty(&metrics_map).unwrap();
-        serve_200_ok_body(client_stream, serialized.as_bytes())?;
-        client_stream.write_all(serialized.as_bytes())?;
-        Ok(PayloadOrigin::NoPayload)
+        if request.method == Head {
+            let header = reply_header_success(serialized.len() as u64, PayloadOrigin::NoPayload);
+            client_stream.write_all(header.as_bytes())?;
+        } else {
+            serve_200_ok_body(client_stream, serialized.as_bytes())?;
+        }
+        Ok((PayloadOrigin::NoPayload, None))
+    } else if request.path.to_str() == "metrics/prometheus" {
+        // Walk the cache directory before locking the job context, since this may take a while for large caches.
+        let cache_size = cache_size(&properties.cache_directory);
+        let snapshot = {
+            let job_context = job_context.lock().unwrap();
+            Snapshot {
+                provider_metrics: job_context.provider_metrics(),
+                providers: job_context.providers(),
+                num_orders_in_progress: job_context.orders_in_progress().len(),
+                cache_size,
+            }
+        };
+        let exposition = metrics.exposition(&snapshot);
+        let content_type = "Content-Type: text/plain; version=0.0.4".to_owned();
+        let header = reply_header("200 OK", exposition.len() as u64, &[content_type], PayloadOrigin::NoPayload);
+        client_stream.write_all(header.as_bytes())?;
+        if request.method != Head {
+            client_stream.write_all(exposition.as_bytes())?;
+        }
+        Ok((PayloadOrigin::NoPayload, None))
     } else if request.path.to_str() == "reset-metrics" && request.method == Post {
         {
             let mut jc = job_context.lock().unwrap();
             jc.reset_provider_metrics();
         }
         serve_200_ok_empty(client_stream)?;
-        Ok(PayloadOrigin::NoPayload)
+        Ok((PayloadOrigin::NoPayload, None))
+    } else if request.path.to_str() == "enable-offline-mode" && request.method == Post {
+        job_context.lock().unwrap().set_offline(true);
+        serve_200_ok_empty(client_stream)?;
+        Ok((PayloadOrigin::NoPayload, None))
+    } else if request.path.to_str() == "disable-offline-mode" && request.method == Post {
+        job_context.lock().unwrap().set_offline(false);
+        serve_200_ok_empty(client_stream)?;
+        Ok((PayloadOrigin::NoPayload, None))
+    } else if request.path.to_str() == "reload-config" && request.method == Post {
+        info!("Reloading configuration as requested by client.");
+        reload_config_in_background(job_context);
+        serve_202_accepted(client_stream)?;
+        Ok((PayloadOrigin::NoPayload, None))
+    } else if let (true, Some(ttl)) = (is_database_file(&request.path), properties.database_cache_ttl()) {
+        serve_database(job_context, client_stream, properties, custom_provider, request, ttl)
+    } else if request.method == Head {
+        let order = DownloadOrder {
+            id: Uuid::new_v4(),
+            requested_path: request.path.clone(),
+            database_refresh: false,
+        };
+        serve_head_request(job_context, client_stream, properties, order, custom_provider, &request)
     } else {
         let order = DownloadOrder {
             id: Uuid::new_v4(),
-            requested_path: request.path,
+            requested_path: request.path.clone(),
+            database_refresh: false,
         };
         debug!("Schedule new job");
-        let result = job_context.lock().unwrap().try_schedule(order.clone(), custom_provider, request.resume_from);
+        let result = {
+            let mut job_context = job_context.lock().unwrap();
+            let result = job_context.try_schedule(order.clone(), custom_provider.clone(), request.resume_from());
+            match result {
+                ScheduleOutcome::Uncacheable(_) if properties.uncacheable_files_method() == Proxy => {
+                    // The requested range cannot be downloaded on its own, so we download the entire file and
+                    // serve the requested range once it is available.
+                    debug!("Download the entire file in order to serve the requested range to the client.");
+                    job_context.try_schedule(order.clone(), custom_provider.clone(), None)
+                }
+                result => result,
+            }
+        };
         match result {
-            ScheduleOutcome::AlreadyInProgress => {
+            ScheduleOutcome::AlreadyInProgress(progress) => {
                 debug!("Job is already in progress");
-                let path = order.filepath(&properties);
-                let complete_filesize: u64 = try_complete_filesize_from_path(&path)?;
-                let content_length = complete_filesize - request.resume_from.unwrap_or(0);
-                let file = File::open(&path)?;
-                serve_from_growing_file(file, content_length, request.resume_from, client_stream)?;
-                Ok(PayloadOrigin::RemoteMirror)
+                let rx_progress = progress.subscribe();
+                let growing_file = GrowingFile { order, custom_provider, progress };
+                serve_from_job_in_progress(growing_file, &request, &properties, rx_progress, client_stream)
             }
-            ScheduleOutcome::Scheduled(ScheduledItem { rx_progress, .. }) => {
+            ScheduleOutcome::Scheduled(ScheduledItem { rx_progress, progress, .. }) => {
                 // TODO this branch is also executed when the server returns 404.
                 debug!("Job was scheduled, will serve from growing file");
-                match receive_content_length(rx_progress) {
-                    Ok(ContentLengthResult::ContentLength(content_length)) => {
-                        debug!("Received content length via channel: {}", content_length);
-                        let file = File::open(order.filepath(&properties))?;
-                        serve_from_growing_file(file, content_length, request.resume_from, client_stream)?;
-                        Ok(PayloadOrigin::RemoteMirror)
-                    }
-                    Ok(ContentLengthResult::AlreadyCached) => {
-                        debug!("File is already available in cache.");
-                        let file = File::open(order.filepath(&properties))?;
-                        serve_from_complete_file(file, request.resume_from, client_stream)?;
-                        Ok(PayloadOrigin::Cache)
-                    }
-                    Err(ContentLengthError::Unavailable) => {
-                        debug!("Will send 404 reply to client.");
-                        serve_404_header(client_stream)?;
-                        Ok(PayloadOrigin::NoPayload)
-                    }
-                    Err(ContentLengthError::OrderError) => {
-                        debug!("Will send 400 reply to client.");
-                        serve_400_header(client_stream)?;
-                        Ok(PayloadOrigin::NoPayload)
-                    }
-                    Err(ContentLengthError::TransmissionError(RecvTimeoutError::Disconnected)) => {
-                        error!("Remote server has disconnected unexpectedly.");
-                        serve_500_header(client_stream)?;
-                        Ok(PayloadOrigin::NoPayload)
-                    }
-                    Err(ContentLengthError::TransmissionError(RecvTimeoutError::Timeout)) => {
-                        // TODO we should not immediately return 500, and instead try another mirror.
-                        // TODO the problem is that the entire logic about retrying other mirrors is
-                        // inside lib.rs
-                        error!("Timeout: Unable to obtain content length.");
-                        serve_500_header(client_stream)?;
-                        Ok(PayloadOrigin::NoPayload)
-                    }
-                }
+                let growing_file = GrowingFile { order, custom_provider, progress };
+                serve_from_job_in_progress(growing_file, &request, &properties, rx_progress, client_stream)
             }
             ScheduleOutcome::Cached => {
                 debug!("Cache hit for request {:?}", &order.requested_path);
@@ -331,61 +917,239 @@ fn serve_request(
                         return Err(ClientError::from(e));
                     }
                 };
-                serve_from_complete_file(file, request.resume_from, client_stream)?;
-                Ok(PayloadOrigin::Cache)
+                let transfer = transfer_from_complete_file(file, &path, &request)?;
+                Ok((PayloadOrigin::Cache, Some(Payload::Transfer(Box::new(transfer)))))
             }
             ScheduleOutcome::Uncacheable(guard) => {
                 debug!("Serve file via redirect.");
                 let uri_string = uri_from_components(&guard.guarded_provider.uri, order.requested_path.to_str());
                 serve_via_redirect(uri_string, client_stream)?;
-                Ok(PayloadOrigin::NoPayload)
+                Ok((PayloadOrigin::NoPayload, None))
+            }
+            ScheduleOutcome::Offline => {
+                serve_503_offline(client_stream, &request)?;
+                Ok((PayloadOrigin::NoPayload, None))
             }
         }
     }
 }
 
-fn serve_client(
+/// Serves the order from the file that is being downloaded by the job with the given progress.
+fn serve_from_job_in_progress(
+    growing_file: GrowingFile,
+    request: &Request,
+    properties: &MirrorConfig,
+    rx_progress: Receiver<FlexoProgress>,
+    client_stream: &mut impl Write,
+) -> Result<(PayloadOrigin, Option<Payload>), ClientError> {
+    match receive_content_length(rx_progress, &growing_file.progress) {
+        Ok(ContentLengthResult::ContentLength(complete_filesize)) => {
+            debug!("Received content length via channel: {}", complete_filesize);
+            let file = File::open(growing_file.order.filepath(properties))?;
+            let transfer = transfer_from_growing_file(file, complete_filesize, &request.byte_ranges, growing_file);
+            Ok((PayloadOrigin::RemoteMirror, Some(Payload::Transfer(Box::new(transfer)))))
+        }
+        Ok(ContentLengthResult::AlreadyCached) => {
+            debug!("File is already available in cache.");
+            let path = growing_file.order.filepath(properties);
+            let file = File::open(&path)?;
+            let transfer = transfer_from_complete_file(file, &path, request)?;
+            Ok((PayloadOrigin::Cache, Some(Payload::Transfer(Box::new(transfer)))))
+        }
+        Err(ContentLengthError::Unavailable) => {
+            debug!("Will send 404 reply to client.");
+            serve_404_header(client_stream)?;
+            Ok((PayloadOrigin::NoPayload, None))
+        }
+        Err(ContentLengthError::OrderError) => {
+            debug!("Will send 400 reply to client.");
+            serve_400_header(client_stream)?;
+            Ok((PayloadOrigin::NoPayload, None))
+        }
+        Err(ContentLengthError::TransmissionError(RecvTimeoutError::Disconnected)) => {
+            error!("Remote server has disconnected unexpectedly.");
+            serve_500_header(client_stream)?;
+            Ok((PayloadOrigin::NoPayload, None))
+        }
+        Err(ContentLengthError::TransmissionError(RecvTimeoutError::Timeout)) => {
+            error!("Timeout: Unable to obtain content length from {} remote mirrors.", MAX_ABANDONED_PROVIDERS + 1);
+            serve_500_header(client_stream)?;
+            Ok((PayloadOrigin::NoPayload, None))
+        }
+    }
+}
+
+/// Serves a database file from the cache. Databases that have not been validated within the TTL are still served
+/// from the cache, while a single job checks in the background if the remote mirror has a more recent version.
+fn serve_database(
     job_context: Arc<Mutex<JobContext<DownloadJob>>>,
-    mut client_stream: TcpStream,
+    client_stream: &mut impl Write,
     properties: MirrorConfig,
-) -> Result<bool, ClientError> {
-    let mut cache_tainted = false;
-    // Loop for persistent connections: Will wait for subsequent requests instead of closing immediately.
-    loop {
-        debug!("Reading header from client.");
-        match read_client_header(&mut client_stream) {
-            Ok(ClientResponse::Request(get_request)) => {
-                let request_path = get_request.path.clone();
-                match serve_request(job_context.clone(), &mut client_stream, properties.clone(), get_request) {
-                    Ok(payload_origin) => {
-                        let payload_origin_human_readable = match payload_origin {
-                            PayloadOrigin::Cache => "CACHE HIT",
-                            PayloadOrigin::RemoteMirror => {
-                                // When the payload is downloaded from a remote mirror, a new file is stored in the
-                                // cache.
-                                cache_tainted = true;
-                                "CACHE MISS"
-                            }
-                            PayloadOrigin::NoPayload => "NO PAYLOAD",
-                        };
-                        info!("Request served [{}]: {:?}", payload_origin_human_readable, &request_path.to_str())
-                    }
-                    Err(e) => {
-                        error!("Unable to serve request {:?}: {:?}", &request_path.to_str(), e);
-                        handle_client_error(&mut client_stream, e)?;
-                        return Ok(cache_tainted);
-                    }
+    custom_provider: Option<DownloadProvider>,
+    request: Request,
+    ttl: Duration,
+) -> Result<(PayloadOrigin, Option<Payload>), ClientError> {
+    let order = DownloadOrder {
+        id: Uuid::new_v4(),
+        requested_path: request.path.clone(),
+        database_refresh: true,
+    };
+    let path = order.database_path(&properties);
+    let payload_origin = match database_state(&path, ttl) {
+        DatabaseState::Fresh => {
+            debug!("Database {:?} is up to date", &path);
+            PayloadOrigin::Cache
+        }
+        DatabaseState::Stale => {
+            debug!("Database {:?} is stale: Serve from cache and refresh in background", &path);
+            // Nothing else to do if the database is already being refreshed.
+            let _ = job_context.lock().unwrap().try_schedule(order, custom_provider, None);
+            PayloadOrigin::Cache
+        }
+        DatabaseState::Missing if request.method == Head => {
+            let order = DownloadOrder { database_refresh: false, ..order };
+            return serve_head_request(job_context, client_stream, properties, order, custom_provider, &request);
+        }
+        DatabaseState::Missing => {
+            debug!("Database {:?} is not cached yet, wait until the download has completed", &path);
+            let result = job_context.lock().unwrap().try_schedule(order, custom_provider, None);
+            match result {
+                ScheduleOutcome::Scheduled(ScheduledItem { progress, .. }) |
+                ScheduleOutcome::AlreadyInProgress(progress) => {
+                    let deferred = DeferredReply::new(progress.clone(), move |client_stream| {
+                        let result = serve_downloaded_database(&progress, &path, &request, client_stream);
+                        response(&request.path, result)
+                    });
+                    return Ok((PayloadOrigin::RemoteMirror, Some(Payload::Deferred(deferred))));
+                }
+                ScheduleOutcome::Offline => {
+                    serve_503_offline(client_stream, &request)?;
+                    return Ok((PayloadOrigin::NoPayload, None));
+                }
+                ScheduleOutcome::Cached | ScheduleOutcome::Uncacheable(_) => {
+                    unreachable!("Database refreshes are always scheduled")
                 }
             }
-            Ok(ClientResponse::SocketClosed) => {
-                return Ok(cache_tainted);
+        }
+    };
+    if request.method == Head {
+        serve_header_only_from_cache(&path, &request, client_stream)?;
+        Ok((PayloadOrigin::NoPayload, None))
+    } else {
+        let file = File::open(&path)?;
+        let transfer = transfer_from_complete_file(file, &path, &request)?;
+        Ok((payload_origin, Some(Payload::Transfer(Box::new(transfer)))))
+    }
+}
+
+/// Serves a database that was not cached when it was requested, after the job with the given progress has attempted
+/// to download it.
+fn serve_downloaded_database(
+    progress: &ProgressNotifier,
+    path: &Path,
+    request: &Request,
+    client_stream: &mut impl Write,
+) -> Result<(PayloadOrigin, Option<Payload>), ClientError> {
+    if path.is_file() {
+        let file = File::open(path)?;
+        let transfer = transfer_from_complete_file(file, path, request)?;
+        return Ok((PayloadOrigin::RemoteMirror, Some(Payload::Transfer(Box::new(transfer)))));
+    }
+    // The job has finished, so the receiver only replays the messages that have been sent before.
+    if progress.subscribe().try_iter().any(|p| p == FlexoProgress::Unavailable) {
+        debug!("Will send 404 reply to client.");
+        serve_404_header(client_stream)?;
+    } else {
+        error!("Unable to download database {:?}", path);
+        serve_500_header(client_stream)?;
+    }
+    Ok((PayloadOrigin::NoPayload, None))
+}
+
+/// Sends the same headers that a GET request would have received, but without the payload.
+fn serve_head_request(
+    job_context: Arc<Mutex<JobContext<DownloadJob>>>,
+    client_stream: &mut impl Write,
+    properties: MirrorConfig,
+    order: DownloadOrder,
+    custom_provider: Option<DownloadProvider>,
+    request: &Request,
+) -> Result<(PayloadOrigin, Option<Payload>), ClientError> {
+    let byte_ranges = &request.byte_ranges;
+    let resume_from = request.resume_from();
+    let peek_outcome = job_context.lock().unwrap().peek(&order, custom_provider.clone(), resume_from);
+    match peek_outcome {
+        PeekOutcome::InProgress => {
+            debug!("Job is already in progress");
+            let complete_filesize = try_complete_filesize_from_path(&order.filepath(&properties))?;
+            serve_header_only(complete_filesize, byte_ranges, PayloadOrigin::RemoteMirror, client_stream)?;
+        }
+        PeekOutcome::Cached => {
+            debug!("Cache hit for request {:?}", &order.requested_path);
+            serve_header_only_from_cache(&order.filepath(&properties), request, client_stream)?;
+        }
+        PeekOutcome::Offline => {
+            serve_503_offline(client_stream, request)?;
+        }
+        PeekOutcome::Uncacheable(guard) if properties.uncacheable_files_method() == Redirect => {
+            debug!("Serve file via redirect.");
+            let uri_string = uri_from_components(&guard.guarded_provider.uri, order.requested_path.to_str());
+            serve_via_redirect(uri_string, client_stream)?;
+        }
+        PeekOutcome::NotCached(_) if properties.prefetch_on_head_request() => {
+            debug!("Schedule new job in order to prefetch {:?}", &order.requested_path);
+            let result = job_context.lock().unwrap().try_schedule(order.clone(), custom_provider, resume_from);
+            match result {
+                ScheduleOutcome::Scheduled(ScheduledItem { rx_progress, progress, .. }) => {
+                    match receive_content_length(rx_progress, &progress) {
+                        Ok(ContentLengthResult::ContentLength(complete_filesize)) => {
+                            let origin = PayloadOrigin::RemoteMirror;
+                            serve_header_only(complete_filesize, byte_ranges, origin, client_stream)?;
+                        }
+                        Ok(ContentLengthResult::AlreadyCached) => {
+                            serve_header_only_from_cache(&order.filepath(&properties), request, client_stream)?;
+                        }
+                        Err(ContentLengthError::Unavailable) => {
+                            serve_404_header(client_stream)?;
+                        }
+                        Err(ContentLengthError::OrderError) => {
+                            serve_400_header(client_stream)?;
+                        }
+                        Err(ContentLengthError::TransmissionError(e)) => {
+                            error!("Unable to obtain content length: {:?}", e);
+                            serve_500_header(client_stream)?;
+                        }
+                    }
+                }
+                _ => {
+                    // The state has changed since we have peeked: Just let the client try again.
+                    serve_500_header(client_stream)?;
+                }
             }
-            Err(e) => {
-                handle_client_error(&mut client_stream, e)?;
-                return Ok(cache_tainted);
+        }
+        PeekOutcome::NotCached(guard) | PeekOutcome::Uncacheable(guard) => {
+            let uri_string = uri_from_components(&guard.guarded_provider.uri, order.requested_path.to_str());
+            match fetch_remote_head(&uri_string, &properties) {
+                Ok(RemoteHeadOutcome::Available(content_length)) => {
+                    serve_header_only(content_length, byte_ranges, PayloadOrigin::RemoteMirror, client_stream)?;
+                }
+                Ok(RemoteHeadOutcome::Unavailable) => {
+                    debug!("Will send 404 reply to client.");
+                    serve_404_header(client_stream)?;
+                }
+                Ok(RemoteHeadOutcome::Failure(response_code)) => {
+                    warn!("Remote mirror replied to HEAD request with unexpected status code {}", response_code);
+                    serve_500_header(client_stream)?;
+                }
+                Err(e) => {
+                    warn!("Unable to send HEAD request to {}: {:?}", &uri_string, e);
+                    serve_500_header(client_stream)?;
+                }
             }
-        };
+        }
     }
+    // No payload is ever sent in reply to a HEAD request.
+    Ok((PayloadOrigin::NoPayload, None))
 }
 
 /// Returns the custom provider, if a custom provider needs to be used, and the GetRequest. The GetRequest
@@ -413,9 +1177,12 @@ fn custom_provider_from_request(
                 country_code: "Unknown".to_string(),
             };
             let new_get_request = Request {
-                resume_from: get_request.resume_from,
+                byte_ranges: get_request.byte_ranges,
+                conditional_headers: get_request.conditional_headers,
                 method: get_request.method,
                 path,
+                authorization: get_request.authorization,
+                user_agent: get_request.user_agent,
             };
             (Some(provider), new_get_request)
         }
@@ -423,7 +1190,7 @@ fn custom_provider_from_request(
 }
 
 /// Returns Ok if it is save to continue serving requests to this client, or Err otherwise.
-fn handle_client_error(mut client_stream: &mut TcpStream, client_error: ClientError) -> Result<(), ClientError> {
+fn handle_client_error(mut client_stream: &mut ClientStream, client_error: ClientError) -> Result<(), ClientError> {
     let result = match client_error {
         ClientError::Other(kind) if kind == ErrorKind::ConnectionReset => {
             debug!("Socket closed by client.");
@@ -448,6 +1215,10 @@ fn handle_client_error(mut client_stream: &mut TcpStream, client_error: ClientEr
             }
             Ok(())
         }
+        ClientError::Forbidden => {
+            serve_403_header(&mut client_stream)?;
+            Ok(())
+        }
         ClientError::IoError(error_kind) => {
             error!("Input/Output Error: {:?}", error_kind);
             Err(client_error)
@@ -491,42 +1262,79 @@ pub enum ProviderSelectionError {
 }
 
 fn initialize_job_context(properties: MirrorConfig) -> Result<JobContext<DownloadJob>, ProviderSelectionError> {
-    let providers: Vec<DownloadProvider> = rated_providers(&properties);
+    let providers = select_providers(&properties)?;
+    info!("Primary mirror: {:#?}", providers[0].uri);
+    Ok(JobContext::new(providers, properties))
+}
+
+fn select_providers(properties: &MirrorConfig) -> Result<Vec<DownloadProvider>, ProviderSelectionError> {
+    let providers: Vec<DownloadProvider> = rated_providers(properties);
     if providers.is_empty() {
         return Err(ProviderSelectionError::NoProviders);
     }
-    info!("Primary mirror: {:#?}", providers[0].uri);
     let providers = match properties.mirror_selection_method {
+        MirrorSelectionMethod::Auto if properties.offline() =>
+            // No latency tests have been run, the providers were obtained from the previous latency test.
+            providers,
         MirrorSelectionMethod::Auto =>
             // With this mirror selection method, latency test have been run, so we store the results
             // in order to be able to choose fast mirrors next time without running them again.
-            mirror_cache::store_latency_test_results(&properties, providers),
+            mirror_cache::store_latency_test_results(properties, providers),
         MirrorSelectionMethod::Predefined =>
             providers,
     };
-
-    Ok(JobContext::new(providers, properties))
+    Ok(providers)
 }
 
 fn rated_providers(mirror_config: &MirrorConfig) -> Vec<DownloadProvider> {
-    if mirror_config.mirror_selection_method == MirrorSelectionMethod::Auto {
+    if mirror_config.mirror_selection_method == MirrorSelectionMethod::Auto && mirror_config.offline() {
+        info!("Offline mode is enabled: Use the results of the previous latency test.");
+        match mirror_cache::fetch_download_providers(mirror_config) {
+            Ok(v) => v.download_providers,
+            Err(e) => {
+                error!("Unable to fetch the results of the previous latency test: {:?}", e);
+                vec![]
+            }
+        }
+    } else if mirror_config.mirror_selection_method == MirrorSelectionMethod::Auto {
         let providers = fetch_auto(mirror_config);
         debug!("Mirror latency test results: {:#?}", providers);
         providers
     } else {
-        let default_mirror_result: MirrorResults = Default::default();
-        let mirrors_predefined = mirror_config.mirrors_predefined.clone();
-        mirrors_predefined.into_iter().map(|uri| {
-            DownloadProvider {
-                uri: uri.clone(),
-                name: uri,
-                mirror_results: default_mirror_result,
-                country_code: "Unknown".to_owned(),
-            }
-        }).collect()
+        predefined_providers(mirror_config.mirrors_predefined.clone())
     }
 }
 
+fn predefined_providers(uris: Vec<String>) -> Vec<DownloadProvider> {
+    let default_mirror_result: MirrorResults = Default::default();
+    uris.into_iter().map(|uri| {
+        DownloadProvider {
+            uri: uri.clone(),
+            name: uri,
+            mirror_results: default_mirror_result,
+            country_code: "Unknown".to_owned(),
+        }
+    }).collect()
+}
+
+/// Returns the providers that serve requests until the mirrors have been selected, so that requests can be served
+/// without waiting for the latency tests. The mirrors are taken from the results of the previous latency test, the
+/// mirrorlist fallback file or the predefined mirrors, in that order. Returns an empty vector if none of them is
+/// available.
+fn interim_providers(properties: &MirrorConfig) -> Vec<DownloadProvider> {
+    match mirror_cache::fetch_download_providers(properties) {
+        Ok(v) if !v.download_providers.is_empty() => return v.download_providers,
+        Ok(_) => {}
+        Err(e) => debug!("The results of the previous latency test are not available: {:?}", e),
+    }
+    match mirror_cache::fetch_mirrorlist(properties) {
+        Ok(uris) if !uris.is_empty() => return predefined_providers(uris),
+        Ok(_) => {}
+        Err(e) => debug!("The mirrorlist fallback file is not available: {:?}", e),
+    }
+    predefined_providers(properties.mirrors_predefined.clone())
+}
+
 fn fetch_auto(mirror_config: &MirrorConfig) -> Vec<DownloadProvider> {
     let country_codes = mirror_config.mirrors_auto.as_ref()
         .map(|ma| ma.allowed_countries.clone());
@@ -677,8 +1485,14 @@ enum ContentLengthResult {
     AlreadyCached,
 }
 
-fn receive_content_length(rx: Receiver<FlexoProgress>) -> Result<ContentLengthResult, ContentLengthError> {
+fn receive_content_length(
+    rx: Receiver<FlexoProgress>,
+    progress: &ProgressNotifier,
+) -> Result<ContentLengthResult, ContentLengthError> {
+    let mut num_abandoned_providers = 0;
     loop {
+        // Only the provider that has failed to reply during the entire timeout is abandoned.
+        let provider = progress.provider();
         match rx.recv_timeout(TIMEOUT_RECEIVE_CONTENT_LENGTH) {
             Ok(FlexoProgress::JobSize(content_length)) => {
                 break Ok(ContentLengthResult::ContentLength(content_length));
@@ -695,6 +1509,13 @@ fn receive_content_length(rx: Receiver<FlexoProgress>) -> Result<ContentLengthRe
             Ok(msg) => {
                 panic!("Unexpected message: {:?}", msg);
             }
+            Err(RecvTimeoutError::Timeout) if num_abandoned_providers < MAX_ABANDONED_PROVIDERS => {
+                info!("Content length not received in time, try another remote mirror.");
+                num_abandoned_providers += 1;
+                if let Some(provider) = provider {
+                    progress.abandon_provider(&provider);
+                }
+            }
             Err(e) => break Err(ContentLengthError::TransmissionError(e)),
         }
     }
@@ -720,117 +1541,245 @@ fn try_complete_filesize_from_path(path: &Path) -> Result<u64, FileAttrError> {
     Err(FileAttrError::TimeoutError)
 }
 
-fn serve_from_growing_file(
-    mut file: File,
-    content_length: u64,
-    resume_from: Option<u64>,
-    client_stream: &mut TcpStream,
-) -> io::Result<()> {
-    let header = match resume_from {
-        None => reply_header_success(content_length, PayloadOrigin::RemoteMirror),
-        Some(r) => reply_header_partial(content_length, r, PayloadOrigin::RemoteMirror)
-    };
-    client_stream.write_all(header.as_bytes())?;
-    debug!("Header was sent to the client.");
-    let resume_from = resume_from.unwrap_or(0);
-    let mut client_received = resume_from;
-    let complete_filesize = content_length + resume_from;
-    while client_received < complete_filesize {
-        let filesize = file.metadata()?.len();
-        if filesize > client_received {
-            // TODO note that this while loop runs indefinitely if the file stops growing for whatever reason.
-            let result = send_payload_and_flush(&mut file, filesize, client_received as i64, client_stream);
-            match result {
-                Ok(size) => {
-                    client_received = size as u64;
+/// Describes which parts of the file are sent to the client.
+enum PayloadSelection {
+    /// The client has not sent a Range header, so the complete file is sent.
+    CompleteFile,
+    SingleRange(SatisfiableRange),
+    /// Multiple ranges are sent as multipart/byteranges, separated by the given boundary.
+    MultipleRanges(Vec<SatisfiableRange>, String),
+    NotSatisfiable,
+}
+
+impl PayloadSelection {
+    fn new(byte_ranges: &Option<Vec<ByteRange>>, complete_filesize: u64) -> Self {
+        match byte_ranges {
+            None => PayloadSelection::CompleteFile,
+            Some(byte_ranges) => match resolve_byte_ranges(byte_ranges, complete_filesize) {
+                None => PayloadSelection::NotSatisfiable,
+                Some(mut ranges) if ranges.len() == 1 => PayloadSelection::SingleRange(ranges.remove(0)),
+                Some(ranges) if ranges.len() > MAX_BYTE_RANGES => {
+                    debug!("Client has requested {} ranges: Send the complete file instead.", ranges.len());
+                    PayloadSelection::CompleteFile
                 }
-                Err(e) => {
-                    if e.kind() == ErrorKind::BrokenPipe || e.kind() == ErrorKind::ConnectionReset {
-                        debug!("Broken Pipe or Connection reset. Connection closed by client?");
-                    } else {
-                        error!("Failed to send payload: An unexpected I/O error has occurred: {:?}", e);
-                    }
-                    return Err(e);
+                Some(ranges) if ranges.iter().map(SatisfiableRange::len).sum::<u64>() > complete_filesize / 2 => {
+                    // Most of the file is sent anyway, so the complete file is cheaper than a multipart payload.
+                    PayloadSelection::CompleteFile
                 }
+                Some(ranges) => PayloadSelection::MultipleRanges(ranges, Uuid::new_v4().to_simple().to_string()),
             }
         }
-        if client_received < content_length {
-            std::thread::sleep(std::time::Duration::from_micros(500));
+    }
+
+    fn header(
+        &self,
+        complete_filesize: u64,
+        payload_origin: PayloadOrigin,
+        validators: Option<&Validators>,
+    ) -> String {
+        let mut headers = validators.map(|v| v.headers()).unwrap_or_default();
+        match self {
+            PayloadSelection::CompleteFile =>
+                reply_header("200 OK", complete_filesize, &headers, payload_origin),
+            PayloadSelection::SingleRange(range) => {
+                headers.push(format!("Content-Range: bytes {}-{}/{}", range.first, range.last, complete_filesize));
+                reply_header("206 Partial Content", range.len(), &headers, payload_origin)
+            }
+            PayloadSelection::MultipleRanges(ranges, boundary) => {
+                let content_length = multipart_content_length(ranges, boundary, complete_filesize);
+                headers.push(format!("Content-Type: multipart/byteranges; boundary={}", boundary));
+                reply_header("206 Partial Content", content_length, &headers, payload_origin)
+            }
+            PayloadSelection::NotSatisfiable =>
+                reply_header_range_not_satisfiable(complete_filesize),
         }
     }
-    debug!("File completely served from growing file.");
+
+    /// Returns the header and the payload that need to be sent to the client.
+    fn transfer(
+        &self,
+        complete_filesize: u64,
+        payload_origin: PayloadOrigin,
+        validators: Option<&Validators>,
+        file: File,
+        growing_file: Option<GrowingFile>,
+    ) -> Transfer {
+        let mut transfer = Transfer::new(file, growing_file);
+        transfer.push_bytes(self.header(complete_filesize, payload_origin, validators).into_bytes());
+        match self {
+            PayloadSelection::CompleteFile => transfer.push_file_range(0, complete_filesize),
+            PayloadSelection::SingleRange(range) => transfer.push_file_range(range.first, range.last + 1),
+            PayloadSelection::MultipleRanges(ranges, boundary) => {
+                for range in ranges {
+                    transfer.push_bytes(multipart_part_header(*range, boundary, complete_filesize).into_bytes());
+                    transfer.push_file_range(range.first, range.last + 1);
+                    transfer.push_bytes(b"\r\n".to_vec());
+                }
+                transfer.push_bytes(multipart_closing_delimiter(boundary).into_bytes());
+            }
+            PayloadSelection::NotSatisfiable => {}
+        }
+        transfer
+    }
+}
+
+fn multipart_part_header(range: SatisfiableRange, boundary: &str, complete_filesize: u64) -> String {
+    format!("--{}\r\n\
+        Content-Type: application/octet-stream\r\n\
+        Content-Range: bytes {}-{}/{}\r\n\r\n", boundary, range.first, range.last, complete_filesize)
+}
+
+fn multipart_closing_delimiter(boundary: &str) -> String {
+    format!("--{}--\r\n", boundary)
+}
+
+fn multipart_content_length(ranges: &[SatisfiableRange], boundary: &str, complete_filesize: u64) -> u64 {
+    let parts_length: u64 = ranges.iter().map(|range| {
+        let part_header_length = multipart_part_header(*range, boundary, complete_filesize).len() as u64;
+        // Each part is terminated by CRLF.
+        part_header_length + range.len() + 2
+    }).sum();
+    parts_length + multipart_closing_delimiter(boundary).len() as u64
+}
+
+fn transfer_from_growing_file(
+    file: File,
+    complete_filesize: u64,
+    byte_ranges: &Option<Vec<ByteRange>>,
+    growing_file: GrowingFile,
+) -> Transfer {
+    let payload_selection = PayloadSelection::new(byte_ranges, complete_filesize);
+    payload_selection.transfer(complete_filesize, PayloadOrigin::RemoteMirror, None, file, Some(growing_file))
+}
+
+fn serve_header_only(
+    complete_filesize: u64,
+    byte_ranges: &Option<Vec<ByteRange>>,
+    payload_origin: PayloadOrigin,
+    client_stream: &mut impl Write,
+) -> Result<(), ClientError> {
+    let payload_selection = PayloadSelection::new(byte_ranges, complete_filesize);
+    let header = payload_selection.header(complete_filesize, payload_origin, None);
+    client_stream.write_all(header.as_bytes())?;
     Ok(())
 }
 
-fn serve_404_header(client_stream: &mut TcpStream) -> io::Result<()> {
+/// Like serve_header_only, but for files that are completely available in the cache directory.
+fn serve_header_only_from_cache(path: &Path, request: &Request, client_stream: &mut impl Write) -> io::Result<()> {
+    let filesize = fs::metadata(path)?.len();
+    let validators = Validators::from_cached_file(path);
+    let header = match &validators {
+        Some(v) if request.conditional_headers.is_not_modified(v) => reply_header_not_modified(filesize, v),
+        _ => {
+            let payload_selection = PayloadSelection::new(&request.byte_ranges, filesize);
+            payload_selection.header(filesize, PayloadOrigin::Cache, validators.as_ref())
+        }
+    };
+    client_stream.write_all(header.as_bytes())
+}
+
+fn serve_404_header(client_stream: &mut impl Write) -> io::Result<()> {
     let header = reply_header_not_found();
     client_stream.write_all(header.as_bytes())
 }
 
-fn serve_400_header(client_stream: &mut TcpStream) -> io::Result<()> {
+fn serve_400_header(client_stream: &mut impl Write) -> io::Result<()> {
     let header = reply_header_bad_request();
     client_stream.write_all(header.as_bytes())
 }
 
-fn serve_500_header(client_stream: &mut TcpStream) -> io::Result<()> {
+fn serve_500_header(client_stream: &mut impl Write) -> io::Result<()> {
     let header = reply_header_internal_server_error();
     client_stream.write_all(header.as_bytes())
 }
 
-fn serve_403_header(client_stream: &mut TcpStream) -> io::Result<()> {
+fn serve_401_header(client_stream: &mut impl Write) -> io::Result<()> {
+    let header = reply_header_unauthorized();
+    client_stream.write_all(header.as_bytes())
+}
+
+fn serve_403_header(client_stream: &mut impl Write) -> io::Result<()> {
     let header = reply_header_forbidden();
     client_stream.write_all(header.as_bytes())
 }
 
-fn serve_200_ok_empty(client_stream: &mut TcpStream) -> io::Result<()> {
+/// Sent if a file needs to be downloaded from a remote mirror while we're in offline mode.
+fn serve_503_offline(client_stream: &mut impl Write, request: &Request) -> io::Result<()> {
+    debug!("Offline mode is enabled: Will send 503 reply to client.");
+    let body = format!("Flexo is in offline mode and {} is not available in the cache.\n", request.path.to_str());
+    let header = reply_header("503 Service Unavailable", body.len() as u64, &[], PayloadOrigin::NoPayload);
+    client_stream.write_all(header.as_bytes())?;
+    if request.method != Head {
+        client_stream.write_all(body.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn serve_200_ok_empty(client_stream: &mut impl Write) -> io::Result<()> {
     let header = reply_header_success(0, PayloadOrigin::NoPayload);
     client_stream.write_all(header.as_bytes())
 }
 
-fn serve_200_ok_body(client_stream: &mut TcpStream, body: &[u8]) -> io::Result<()> {
+fn serve_200_ok_body(client_stream: &mut impl Write, body: &[u8]) -> io::Result<()> {
     let content_length = body.len() as u64;
     let header = reply_header_success(content_length, PayloadOrigin::NoPayload);
     client_stream.write_all(header.as_bytes())?;
     client_stream.write_all(body)
 }
 
+/// Sent if the request has been accepted, but will be processed in the background.
+fn serve_202_accepted(client_stream: &mut impl Write) -> io::Result<()> {
+    let header = reply_header("202 Accepted", 0, &[], PayloadOrigin::NoPayload);
+    client_stream.write_all(header.as_bytes())
+}
+
 fn reply_header_success(content_length: u64, payload_origin: PayloadOrigin) -> String {
-    reply_header("200 OK", content_length, None, payload_origin)
+    reply_header("200 OK", content_length, &[], payload_origin)
+}
+
+fn reply_header_not_modified(complete_filesize: u64, validators: &Validators) -> String {
+    // No payload is sent with 304 responses. But if the Content-Length header is included, it must be set to the
+    // value that would have been sent in a 200 response, see RFC 7230, section 3.3.2.
+    reply_header("304 Not Modified", complete_filesize, &validators.headers(), PayloadOrigin::Cache)
 }
 
-fn reply_header_partial(content_length: u64, resume_from: u64, payload_origin: PayloadOrigin) -> String {
-    reply_header("206 Partial Content", content_length, Some(resume_from), payload_origin)
+fn reply_header_range_not_satisfiable(complete_filesize: u64) -> String {
+    let content_range = format!("Content-Range: bytes */{}", complete_filesize);
+    reply_header("416 Range Not Satisfiable", 0, &[content_range], PayloadOrigin::NoPayload)
 }
 
 fn reply_header_not_found() -> String {
-    reply_header("404 Not Found", 0, None, PayloadOrigin::NoPayload)
+    reply_header("404 Not Found", 0, &[], PayloadOrigin::NoPayload)
 }
 
 fn reply_header_bad_request() -> String {
-    reply_header("400 Bad Request", 0, None, PayloadOrigin::NoPayload)
+    reply_header("400 Bad Request", 0, &[], PayloadOrigin::NoPayload)
 }
 
 fn reply_header_internal_server_error() -> String {
-    reply_header("500 Internal Server Error", 0, None, PayloadOrigin::NoPayload)
+    reply_header("500 Internal Server Error", 0, &[], PayloadOrigin::NoPayload)
+}
+
+fn reply_header_unauthorized() -> String {
+    let authenticate = "WWW-Authenticate: Basic realm=\"flexo\"".to_owned();
+    reply_header("401 Unauthorized", 0, &[authenticate], PayloadOrigin::NoPayload)
 }
 
 fn reply_header_forbidden() -> String {
-    reply_header("403 Forbidden", 0, None, PayloadOrigin::NoPayload)
+    reply_header("403 Forbidden", 0, &[], PayloadOrigin::NoPayload)
 }
 
 fn reply_header(
     status_line: &str,
     content_length: u64,
-    resume_from: Option<u64>,
+    additional_headers: &[String],
     payload_origin: PayloadOrigin,
 ) -> String {
     let now = time::now_utc();
     let timestamp = now.rfc822();
-    let content_range_header = resume_from.map(|r| {
-        let complete_size = content_length + r;
-        let last_byte = complete_size - 1;
-        format!("Content-Range: bytes {}-{}/{}\r\n", r, last_byte, complete_size)
-    }).unwrap_or_else(|| "".to_owned());
+    let additional_headers = additional_headers.iter()
+        .map(|h| format!("{}\r\n", h))
+        .collect::<String>();
     let header = format!("\
         HTTP/1.1 {}\r\n\
         Server: flexo\r\n\
@@ -841,7 +1790,7 @@ fn reply_header(
                          status_line,
                          timestamp,
                          payload_origin,
-                         content_range_header,
+                         additional_headers,
                          content_length
     );
     debug!("Sending header to client: {:?}", &header);
@@ -862,84 +1811,62 @@ fn redirect_header(path: &str) -> String {
     header
 }
 
-fn serve_from_complete_file(
-    mut file: File,
-    resume_from: Option<u64>,
-    client_stream: &mut TcpStream,
-) -> io::Result<i64> {
+fn transfer_from_complete_file(file: File, path: &Path, request: &Request) -> io::Result<Transfer> {
     let filesize = file.metadata()?.len();
-    let content_length = filesize - resume_from.unwrap_or(0);
-    let header = match resume_from {
-        None => reply_header_success(content_length, PayloadOrigin::Cache),
-        Some(r) => reply_header_partial(content_length, r, PayloadOrigin::Cache)
-    };
-    client_stream.write_all(header.as_bytes())?;
-    let bytes_sent = resume_from.unwrap_or(0) as i64;
-    let result = send_payload_and_flush(&mut file, filesize, bytes_sent, client_stream);
-    match &result {
-        Ok(s) => debug!("{} bytes have been transmitted to the client.", s),
-        Err(e) => warn!("Error while sending payload: {:?}", e),
+    let validators = Validators::from_cached_file(path);
+    if let Some(v) = &validators {
+        if request.conditional_headers.is_not_modified(v) {
+            debug!("The client's copy of {:?} is up to date.", path);
+            let mut transfer = Transfer::new(file, None);
+            transfer.push_bytes(reply_header_not_modified(filesize, v).into_bytes());
+            return Ok(transfer);
+        }
     }
-    result
+    let payload_selection = PayloadSelection::new(&request.byte_ranges, filesize);
+    Ok(payload_selection.transfer(filesize, PayloadOrigin::Cache, validators.as_ref(), file, None))
 }
 
-fn serve_via_redirect(uri: String, client_stream: &mut TcpStream) -> io::Result<()> {
+fn serve_via_redirect(uri: String, client_stream: &mut impl Write) -> io::Result<()> {
     debug!("Attempting to serve from {}", &uri);
     let header = redirect_header(&uri);
     client_stream.write_all(header.as_bytes())
 }
 
-fn send_payload_and_flush(
-    mut source: &mut File,
-    filesize: u64,
-    bytes_sent: i64,
-    receiver: &mut TcpStream,
-) -> io::Result<i64> {
-    let result = send_payload(&mut source, filesize, bytes_sent, receiver);
-    // Enabling and then disabling the nodelay option results in a flush.
-    // For some reason, receiver.flush() does not have this effect.
-    receiver.set_nodelay(true)?;
-    receiver.set_nodelay(false)?;
-
-    result
-}
-
-fn send_payload<T>(source: &mut File, filesize: u64, bytes_sent: i64, receiver: &mut T) -> io::Result<i64>
-    where T: AsRawFd {
-    let fd = source.as_raw_fd();
-    let sfd = receiver.as_raw_fd();
-    let size = unsafe {
-        let mut offset = bytes_sent as off64_t;
-        while (offset as u64) < filesize {
-            let size: isize = libc::sendfile64(sfd, fd, &mut offset, MAX_SENDFILE_COUNT);
-            if size == -1 {
-                return Err(std::io::Error::last_os_error());
-            }
-        }
-        offset
-    };
-
-    Ok(size)
+#[test]
+fn test_multipart_content_length() {
+    let ranges = vec![SatisfiableRange { first: 0, last: 9 }, SatisfiableRange { first: 20, last: 29 }];
+    let boundary = "3d6b6a416f9b5";
+    let expected = format!("{}{}\r\n{}{}\r\n{}",
+                           multipart_part_header(ranges[0], boundary, 100), "a".repeat(10),
+                           multipart_part_header(ranges[1], boundary, 100), "a".repeat(10),
+                           multipart_closing_delimiter(boundary));
+    assert_eq!(multipart_content_length(&ranges, boundary, 100), expected.len() as u64);
 }
 
 #[test]
-fn test_filesize_exceeds_sendfile_count() {
-    let mut source: File = tempfile().unwrap();
-    let mut receiver: File = tempfile().unwrap();
-    let array: [u8; MAX_SENDFILE_COUNT * 3] = [b'a'; MAX_SENDFILE_COUNT * 3];
-    source.write(&array).unwrap();
-    source.flush().unwrap();
-    let filesize = source.metadata().unwrap().len();
-    let size = send_payload(&mut source, filesize, 0, &mut receiver).unwrap();
-    assert_eq!(size, (MAX_SENDFILE_COUNT * 3) as i64);
+fn test_payload_selection_limits_ranges() {
+    let ranges = |specs: &[(u64, u64)]| Some(specs.iter()
+        .map(|&(first, last)| ByteRange::FromOffset { first, last: Some(last) })
+        .collect::<Vec<ByteRange>>());
+    let few_ranges = ranges(&[(0, 9), (20, 29), (40, 49)]);
+    assert!(matches!(PayloadSelection::new(&few_ranges, 1000), PayloadSelection::MultipleRanges(r, _) if r.len() == 3));
+    let too_many_ranges = ranges(&(0..17).map(|i| (i * 20, i * 20 + 9)).collect::<Vec<(u64, u64)>>());
+    assert!(matches!(PayloadSelection::new(&too_many_ranges, 1000), PayloadSelection::CompleteFile));
+    let most_of_the_file = ranges(&[(0, 299), (400, 999)]);
+    assert!(matches!(PayloadSelection::new(&most_of_the_file, 1000), PayloadSelection::CompleteFile));
+    let overlapping = ranges(&[(0, 99), (0, 99), (50, 149)]);
+    assert!(matches!(PayloadSelection::new(&overlapping, 1000), PayloadSelection::SingleRange(r) if r.last == 149));
 }
 
 #[test]
 fn custom_provider_from_request_test() {
     let request = Request {
-        resume_from: None,
+        byte_ranges: None,
+        conditional_headers: Default::default(),
         path: StrPath::new("/custom_repo/archzfs/foo/bar/baz".to_owned()),
-        method: RequestMethod::Get
+        method: RequestMethod::Get,
+        authorization: None,
+        user_agent: None,
     };
     let custom_repo = CustomRepo {
         name: "archzfs".to_owned(),
@@ -954,12 +1881,58 @@ fn custom_provider_from_request_test() {
         country_code: "Unknown".to_string(),
     };
     let expected_get_request = Request {
-        resume_from: None,
+        byte_ranges: None,
+        conditional_headers: Default::default(),
         path: StrPath::new("/foo/bar/baz".to_owned()),
-        method: RequestMethod::Get
+        method: RequestMethod::Get,
+        authorization: None,
+        user_agent: None,
     };
 
     assert_eq!(provider, Some(expected_provider));
     assert_eq!(new_get_request, expected_get_request);
 }
 
+#[test]
+fn custom_repo_admin_endpoint_test() {
+    let request = Request {
+        byte_ranges: None,
+        conditional_headers: Default::default(),
+        path: StrPath::new("/custom_repo/archzfs/metrics".to_owned()),
+        method: RequestMethod::Get,
+        authorization: None,
+        user_agent: None,
+    };
+    let custom_repo = CustomRepo {
+        name: "archzfs".to_owned(),
+        url: "https://archzfs.com".to_owned(),
+    };
+    let (_, request) = custom_provider_from_request(request, &[custom_repo]);
+    assert!(is_admin_endpoint(&request));
+    assert!(!is_package_request(&request));
+}
+
+#[test]
+fn test_serve_downloaded_database_unavailable() {
+    let request = Request {
+        byte_ranges: None,
+        conditional_headers: Default::default(),
+        path: StrPath::new("core/os/x86_64/core.db".to_owned()),
+        method: RequestMethod::Get,
+        authorization: None,
+        user_agent: None,
+    };
+    let path = Path::new("/nonexistent/core/os/x86_64/core.db");
+    let progress = ProgressNotifier::default();
+    let mut client_stream = Vec::new();
+    let (payload_origin, payload) =
+        serve_downloaded_database(&progress, path, &request, &mut client_stream).ok().unwrap();
+    assert_eq!(payload_origin, PayloadOrigin::NoPayload);
+    assert!(payload.is_none());
+    assert!(client_stream.starts_with(b"HTTP/1.1 500"));
+
+    progress.send(FlexoProgress::Unavailable);
+    let mut client_stream = Vec::new();
+    let _ = serve_downloaded_database(&progress, path, &request, &mut client_stream).ok().unwrap();
+    assert!(client_stream.starts_with(b"HTTP/1.1 404"));
+}
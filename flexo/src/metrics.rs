@@ -0,0 +1,259 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use flexo::{ProviderIdentifier, ProviderMetrics};
+
+use crate::event_loop::Exchange;
+use crate::mirror_flexo::DownloadProvider;
+
+/// The upper bounds of the buckets of the request duration histogram, in seconds. Requests for large packages may
+/// take minutes if the package has to be downloaded first.
+const DURATION_BUCKETS: [f64; 14] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 300.0];
+
+/// Counters and histograms of the requests served. Exported in the Prometheus text format, together with the metrics
+/// of the providers.
+#[derive(Default)]
+pub struct Metrics {
+    num_connections: AtomicUsize,
+    requests: Mutex<RequestMetrics>,
+}
+
+#[derive(Default)]
+struct RequestMetrics {
+    /// The number of requests by payload origin and status.
+    num_requests: BTreeMap<(&'static str, Option<u16>), u64>,
+    /// The number of bytes sent to the clients by payload origin.
+    size_sent: BTreeMap<&'static str, u64>,
+    durations: BTreeMap<&'static str, Histogram>,
+}
+
+struct Histogram {
+    /// The cumulative count for each bucket in DURATION_BUCKETS.
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            buckets: [0; DURATION_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, upper_bound) in self.buckets.iter_mut().zip(DURATION_BUCKETS.iter()) {
+            if value <= *upper_bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The name, the help text and the value of a counter that is exported for each provider.
+type ProviderCounter = (&'static str, &'static str, fn(&ProviderMetrics) -> f64);
+
+/// The state of Flexo at the time the metrics are exported.
+pub struct Snapshot {
+    pub provider_metrics: HashMap<ProviderIdentifier, ProviderMetrics>,
+    pub providers: Vec<DownloadProvider>,
+    pub num_orders_in_progress: usize,
+    pub cache_size: u64,
+}
+
+impl Metrics {
+    pub fn connection_opened(&self) {
+        self.num_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_closed(&self) {
+        self.num_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn request_completed(&self, exchange: &Exchange) {
+        let payload_origin = exchange.payload_origin.name();
+        let mut requests = self.requests.lock().unwrap();
+        *requests.num_requests.entry((payload_origin, exchange.reply.status)).or_insert(0) += 1;
+        *requests.size_sent.entry(payload_origin).or_insert(0) += exchange.reply.bytes_sent;
+        requests.durations.entry(payload_origin)
+            .or_default()
+            .observe(exchange.duration.as_secs_f64());
+    }
+
+    /// Returns the metrics in the Prometheus text format.
+    pub fn exposition(&self, snapshot: &Snapshot) -> String {
+        let mut exposition = Exposition::default();
+        {
+            let requests = self.requests.lock().unwrap();
+            exposition.family("flexo_requests_total", "counter", "Requests served, by payload origin and status.");
+            for ((payload_origin, status), value) in &requests.num_requests {
+                let status = status.map(|s| s.to_string()).unwrap_or_else(|| "none".to_owned());
+                exposition.sample("flexo_requests_total", &[("payload_origin", payload_origin), ("status", &status)], value);
+            }
+            exposition.family("flexo_sent_bytes_total", "counter", "Bytes sent to clients, by payload origin.");
+            for (payload_origin, value) in &requests.size_sent {
+                exposition.sample("flexo_sent_bytes_total", &[("payload_origin", payload_origin)], value);
+            }
+            exposition.family("flexo_request_duration_seconds", "histogram",
+                              "Time from receiving a request until the reply was sent, by payload origin.");
+            for (payload_origin, histogram) in &requests.durations {
+                exposition.histogram("flexo_request_duration_seconds", ("payload_origin", payload_origin), histogram);
+            }
+        }
+
+        exposition.family("flexo_client_connections", "gauge", "Connections to clients that are currently open.");
+        exposition.sample("flexo_client_connections", &[], self.num_connections.load(Ordering::Relaxed));
+        exposition.family("flexo_orders_in_progress", "gauge", "Files that are currently being downloaded.");
+        exposition.sample("flexo_orders_in_progress", &[], snapshot.num_orders_in_progress);
+        exposition.family("flexo_cache_size_bytes", "gauge", "Total size of the files in the cache directory.");
+        exposition.sample("flexo_cache_size_bytes", &[], snapshot.cache_size);
+
+        let mut provider_metrics = snapshot.provider_metrics.iter()
+            .map(|(provider, metrics)| (provider.identifier.as_str(), metrics))
+            .collect::<Vec<(&str, &ProviderMetrics)>>();
+        provider_metrics.sort_by_key(|(provider, _)| *provider);
+        let provider_counters: [ProviderCounter; 6] = [
+            ("flexo_provider_attempts_total", "Attempts to download a file from the provider.",
+             |m| m.num_usages.into()),
+            ("flexo_provider_failures_total", "Downloads that have failed before any data was received.",
+             |m| m.num_errors.into()),
+            ("flexo_provider_partial_downloads_total", "Downloads that have failed after some data was received.",
+             |m| m.num_partials.into()),
+            ("flexo_provider_not_found_total", "Files that were not available at the provider.",
+             |m| m.num_unavailable.into()),
+            ("flexo_provider_downloaded_bytes_total", "Bytes downloaded from the provider.",
+             |m| m.size_downloaded as f64),
+            ("flexo_provider_download_seconds_total", "Time spent downloading from the provider.",
+             |m| Duration::from_millis(m.download_time_millis).as_secs_f64()),
+        ];
+        for (name, help, value) in provider_counters.iter() {
+            exposition.family(name, "counter", help);
+            for (provider, metrics) in &provider_metrics {
+                exposition.sample(name, &[("provider", provider)], value(metrics));
+            }
+        }
+
+        exposition.family("flexo_provider_throughput_bytes_per_second", "gauge",
+                          "Moving average of the throughput of downloads from the provider.");
+        for (provider, metrics) in &provider_metrics {
+            if let Some(throughput) = metrics.throughput {
+                exposition.sample("flexo_provider_throughput_bytes_per_second", &[("provider", provider)], throughput);
+            }
+        }
+
+        exposition.family("flexo_latency_test_seconds", "gauge",
+                          "Results of the latency test of the providers in use, by phase of the request.");
+        for provider in &snapshot.providers {
+            let results = &provider.mirror_results;
+            // Predefined mirrors and custom repositories are not tested.
+            if results.total_time == Duration::default() {
+                continue;
+            }
+            let phases = [
+                ("connect", results.connect_duration),
+                ("pretransfer", results.pretransfer_time),
+                ("starttransfer", results.starttransfer_time),
+                ("total", results.total_time),
+            ];
+            for (phase, duration) in phases.iter() {
+                let labels = [("provider", provider.uri.as_str()), ("phase", phase)];
+                exposition.sample("flexo_latency_test_seconds", &labels, duration.as_secs_f64());
+            }
+        }
+
+        exposition.family("flexo_bandwidth_probe_bytes_per_second", "gauge",
+                          "Throughput measured by the bandwidth probe of the providers in use.");
+        for provider in &snapshot.providers {
+            if let Some(throughput) = provider.mirror_results.throughput {
+                let labels = [("provider", provider.uri.as_str())];
+                exposition.sample("flexo_bandwidth_probe_bytes_per_second", &labels, throughput);
+            }
+        }
+        exposition.text
+    }
+}
+
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, metric_type: &str, help: &str) {
+        self.text.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, metric_type));
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let labels = labels.iter()
+            .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+            .collect::<Vec<String>>();
+        if labels.is_empty() {
+            self.text.push_str(&format!("{} {}\n", name, value));
+        } else {
+            self.text.push_str(&format!("{}{{{}}} {}\n", name, labels.join(","), value));
+        }
+    }
+
+    fn histogram(&mut self, name: &str, label: (&str, &str), histogram: &Histogram) {
+        let bucket_name = format!("{}_bucket", name);
+        for (upper_bound, count) in DURATION_BUCKETS.iter().zip(histogram.buckets.iter()) {
+            self.sample(&bucket_name, &[label, ("le", &upper_bound.to_string())], count);
+        }
+        self.sample(&bucket_name, &[label, ("le", "+Inf")], histogram.count);
+        self.sample(&format!("{}_sum", name), &[label], histogram.sum);
+        self.sample(&format!("{}_count", name), &[label], histogram.count);
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[test]
+fn test_histogram_is_cumulative() {
+    let mut histogram = Histogram::default();
+    histogram.observe(0.02);
+    histogram.observe(0.7);
+    histogram.observe(1000.0);
+    assert_eq!(histogram.buckets[1], 0);
+    assert_eq!(histogram.buckets[2], 1);
+    assert_eq!(histogram.buckets[7], 2);
+    assert_eq!(histogram.buckets[DURATION_BUCKETS.len() - 1], 2);
+    assert_eq!(histogram.count, 3);
+}
+
+#[test]
+fn test_exposition() {
+    let metrics = Metrics::default();
+    metrics.connection_opened();
+    let provider_metrics = ProviderMetrics {
+        num_usages: 2,
+        num_partials: 1,
+        size_downloaded: 1000,
+        download_time_millis: 1500,
+        ..ProviderMetrics::default()
+    };
+    let identifier = ProviderIdentifier { identifier: "https://mirror.example.org/\"".to_owned() };
+    let snapshot = Snapshot {
+        provider_metrics: vec![(identifier, provider_metrics)].into_iter().collect(),
+        providers: vec![],
+        num_orders_in_progress: 1,
+        cache_size: 4096,
+    };
+    let exposition = metrics.exposition(&snapshot);
+    let lines = exposition.lines().collect::<Vec<&str>>();
+    assert!(lines.contains(&"# TYPE flexo_requests_total counter"));
+    assert!(lines.contains(&"flexo_client_connections 1"));
+    assert!(lines.contains(&"flexo_cache_size_bytes 4096"));
+    assert!(lines.contains(&"flexo_provider_partial_downloads_total{provider=\"https://mirror.example.org/\\\"\"} 1"));
+    assert!(lines.contains(&"flexo_provider_download_seconds_total{provider=\"https://mirror.example.org/\\\"\"} 1.5"));
+}
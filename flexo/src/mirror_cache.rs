@@ -2,19 +2,30 @@
 // so that we can simply retrieve and reuse the previously selected mirrors from this file, instead of fetching
 // the mirrors from the JSON endpoint.
 
+use std::collections::HashMap;
 use std::fs;
 use std::io;
+use std::path::{Path, PathBuf};
 
+use flexo::{ProviderIdentifier, ProviderMetrics};
 use serde::{Deserialize, Serialize};
 
 use crate::mirror_config::MirrorConfig;
-use crate::mirror_flexo::DownloadProvider;
+use crate::mirror_flexo::{DownloadProvider, uri_from_components};
 
 const DEFAULT_LATENCY_TEST_RESULTS_FILE: &str = "/var/cache/flexo/state/latency_test_results.json";
 
+const PROVIDER_METRICS_FILE_NAME: &str = "provider_metrics.json";
+
+// The suffix of the server URIs in pacman's mirrorlist format.
+const MIRRORLIST_SERVER_SUFFIX: &str = "$repo/os/$arch";
+
 // Bump this version if a non-backwards compatible change has occurred.
 const TIMESTAMPED_DOWNLOAD_PROVIDERS_VERSION: u32 = 3;
 
+// Bump this version if a non-backwards compatible change has occurred.
+const TIMESTAMPED_PROVIDER_METRICS_VERSION: u32 = 1;
+
 #[derive(Deserialize, Serialize)]
 pub struct TimestampedDownloadProviders {
     pub version: Option<u32>,
@@ -22,6 +33,15 @@ pub struct TimestampedDownloadProviders {
     pub download_providers: Vec<DownloadProvider>,
 }
 
+/// The metrics of the providers, so that providers that have failed repeatedly are not preferred again after a
+/// restart.
+#[derive(Deserialize, Serialize)]
+pub struct TimestampedProviderMetrics {
+    pub version: Option<u32>,
+    pub timestamp: String,
+    pub provider_metrics: HashMap<String, ProviderMetrics>,
+}
+
 #[derive(Deserialize, Serialize)]
 pub struct VersionOnly {
     pub version: Option<u32>,
@@ -53,12 +73,47 @@ pub fn store_latency_test_results(
     let file_path = latency_test_results_file(properties);
     fs::write(file_path, serialized)
         .unwrap_or_else(|_| panic!("Unable to write file: {}", file_path));
+    if let Err(e) = store_mirrorlist(properties, &timestamped.download_providers) {
+        error!("Unable to write file {}: {:?}", properties.mirrorlist_fallback_file, e);
+    }
 
     // Return the providers so that ownership is given back to the caller. This way, we can avoid
     // copying the providers.
     timestamped.download_providers
 }
 
+/// Stores the providers in the format of pacman's mirrorlist, so that they can still be used if the latency test
+/// results are not available.
+fn store_mirrorlist(properties: &MirrorConfig, download_providers: &[DownloadProvider]) -> io::Result<()> {
+    let mirrorlist = download_providers.iter()
+        .map(|provider| format!("Server = {}\n", uri_from_components(&provider.uri, MIRRORLIST_SERVER_SUFFIX)))
+        .collect::<String>();
+    fs::write(&properties.mirrorlist_fallback_file, mirrorlist)
+}
+
+/// Returns the URIs of the mirrors listed in the mirrorlist fallback file, in the order of their appearance.
+pub fn fetch_mirrorlist(properties: &MirrorConfig) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(&properties.mirrorlist_fallback_file)?;
+    Ok(uris_from_mirrorlist(&contents))
+}
+
+fn uris_from_mirrorlist(mirrorlist: &str) -> Vec<String> {
+    let mut uris: Vec<String> = Vec::new();
+    for line in mirrorlist.lines() {
+        let uri = line.trim()
+            .strip_prefix("Server")
+            .map(|value| value.trim_start())
+            .and_then(|value| value.strip_prefix('='))
+            .map(|uri| uri.trim().trim_end_matches(MIRRORLIST_SERVER_SUFFIX));
+        if let Some(uri) = uri {
+            if !uri.is_empty() && !uris.iter().any(|u| u == uri) {
+                uris.push(uri.to_owned());
+            }
+        }
+    }
+    uris
+}
+
 #[derive(Debug)]
 pub enum DemarshallError {
     VersionMismatch,
@@ -91,3 +146,57 @@ pub fn fetch_download_providers(
 
     }
 }
+
+/// The provider metrics are stored in the same directory as the latency test results.
+fn provider_metrics_file(properties: &MirrorConfig) -> PathBuf {
+    let latency_test_results_file = properties.mirrorlist_latency_test_results_file.as_deref()
+        .unwrap_or(DEFAULT_LATENCY_TEST_RESULTS_FILE);
+    Path::new(latency_test_results_file).with_file_name(PROVIDER_METRICS_FILE_NAME)
+}
+
+pub fn store_provider_metrics(
+    properties: &MirrorConfig,
+    provider_metrics: &HashMap<ProviderIdentifier, ProviderMetrics>,
+) -> io::Result<()> {
+    let timestamped = TimestampedProviderMetrics {
+        version: Some(TIMESTAMPED_PROVIDER_METRICS_VERSION),
+        timestamp: format!("{:?}", chrono::Utc::now()),
+        provider_metrics: provider_metrics.iter()
+            .map(|(provider, metrics)| (provider.identifier.clone(), *metrics))
+            .collect(),
+    };
+    let serialized = serde_json::to_string_pretty(&timestamped).unwrap();
+    let file_path = provider_metrics_file(properties);
+    // The metrics are stored while Flexo is running, so we make sure that an interrupted write does not leave a
+    // truncated file behind.
+    let tmp_file_path = file_path.with_extension("json.tmp");
+    fs::write(&tmp_file_path, serialized)?;
+    fs::rename(&tmp_file_path, &file_path)
+}
+
+pub fn fetch_provider_metrics(
+    properties: &MirrorConfig
+) -> Result<HashMap<ProviderIdentifier, ProviderMetrics>, DemarshallError> {
+    let contents = fs::read_to_string(provider_metrics_file(properties))?;
+    match serde_json::from_str::<VersionOnly>(&contents)? {
+        VersionOnly { version: Some(TIMESTAMPED_PROVIDER_METRICS_VERSION) } => {
+            let timestamped = serde_json::from_str::<TimestampedProviderMetrics>(&contents)?;
+            let provider_metrics = timestamped.provider_metrics.into_iter()
+                .map(|(identifier, metrics)| (ProviderIdentifier { identifier }, metrics))
+                .collect();
+            Ok(provider_metrics)
+        },
+        _ => Err(DemarshallError::VersionMismatch),
+    }
+}
+
+#[test]
+fn test_uris_from_mirrorlist() {
+    let mirrorlist = "## Germany\n\
+    Server = https://mirror.example.org/archlinux/$repo/os/$arch\n\
+    #Server = https://disabled.example.org/$repo/os/$arch\n\
+    Server=https://mirror.example.com/$repo/os/$arch\n\
+    Server = https://mirror.example.org/archlinux/$repo/os/$arch\n";
+    let expected = vec!["https://mirror.example.org/archlinux/", "https://mirror.example.com/"];
+    assert_eq!(uris_from_mirrorlist(mirrorlist), expected);
+}
@@ -2,15 +2,37 @@ static CONFIG_FILE: &str = "/etc/flexo/flexo.toml";
 
 extern crate serde;
 
-use std::fs;
+use std::{fmt, fs, io};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
 use serde::Deserialize;
-use flexo::Properties;
+use flexo::{FailurePolicy, Properties};
+use std::net::Ipv6Addr;
 use std::time::Duration;
 
 static DEFAULT_JSON_URI: &str = "https://archlinux.org/mirrors/status/json/";
 
 static DEFAULT_REFRESH_AFTER_SECONDS: u64 = 3600 * 24 * 14;
 
+static DEFAULT_PORT: u16 = 7878;
+
+static DEFAULT_LISTEN_IP_ADDRESS: &str = "0.0.0.0";
+
+static DEFAULT_NUM_WORKER_THREADS: usize = 64;
+
+static DEFAULT_STALLED_TRANSFER_TIMEOUT_SECONDS: u64 = 8;
+
+static DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+
+// A file of several megabytes that is available on all mirrors.
+static DEFAULT_BANDWIDTH_PROBE_PATH: &str = "extra/os/x86_64/extra.files";
+
+static DEFAULT_BANDWIDTH_PROBE_SIZE: u64 = 8 * 1024 * 1024;
+
+static DEFAULT_LATENCY_TEST_PARALLELISM: usize = 16;
+
+static DEFAULT_LATENCY_TEST_DEADLINE_SECONDS: u64 = 10;
+
 #[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum MirrorSelectionMethod {
@@ -18,6 +40,27 @@ pub enum MirrorSelectionMethod {
     Predefined,
 }
 
+/// How to serve files that cannot be served from the cache, e.g. if the client requests only a part of a
+/// database file.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UncacheableFilesMethod {
+    /// Redirect the client to a remote mirror.
+    Redirect,
+    /// Download the file and forward it to the client.
+    Proxy,
+}
+
+/// The format of the access log.
+#[derive(Deserialize, Debug, Copy, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    /// The Combined Log Format, extended by the payload origin, the provider and the duration.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
 fn quote_str(s: String) -> String {
     format!("\"{}\"", s)
 }
@@ -38,6 +81,7 @@ impl TomlValue for u64 { }
 impl TomlValue for u32 { }
 impl TomlValue for u16 { }
 impl TomlValue for Vec<String> { }
+impl TomlValue for Vec<ListenerConfig> { }
 impl TomlValue for String {
     fn toml_value_from_str(s: String) -> String {
         quote_str(s)
@@ -54,6 +98,16 @@ impl TomlValue for MirrorSelectionMethod {
         quote_str(s)
     }
 }
+impl TomlValue for UncacheableFilesMethod {
+    fn toml_value_from_str(s: String) -> String {
+        quote_str(s)
+    }
+}
+impl TomlValue for AccessLogFormat {
+    fn toml_value_from_str(s: String) -> String {
+        quote_str(s)
+    }
+}
 
 #[derive(Deserialize, Debug, PartialEq, Eq, Copy, Clone)]
 #[serde(rename_all = "lowercase")]
@@ -62,7 +116,7 @@ pub enum MirrorsRandomOrSort {
     Random,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct MirrorsAutoConfig {
     pub mirrors_status_json_endpoint: String,
     #[serde(default)]
@@ -78,6 +132,11 @@ pub struct MirrorsAutoConfig {
     pub timeout: u64,
     #[serde(default)]
     pub allowed_countries: Vec<String>,
+    pub latency_test_parallelism: Option<usize>,
+    pub latency_test_deadline: Option<String>,
+    pub bandwidth_probe_num_mirrors: Option<usize>,
+    pub bandwidth_probe_path: Option<String>,
+    pub bandwidth_probe_size: Option<u64>,
 }
 
 impl MirrorsAutoConfig {
@@ -87,17 +146,63 @@ impl MirrorsAutoConfig {
         relaxed.timeout += 100;
         relaxed
     }
+
+    pub fn latency_test_parallelism(&self) -> usize {
+        self.latency_test_parallelism.unwrap_or(DEFAULT_LATENCY_TEST_PARALLELISM)
+    }
+
+    pub fn latency_test_deadline(&self) -> Duration {
+        let default = Duration::from_secs(DEFAULT_LATENCY_TEST_DEADLINE_SECONDS);
+        duration_or_default(&self.latency_test_deadline, default)
+    }
+
+    pub fn bandwidth_probe_path(&self) -> &str {
+        self.bandwidth_probe_path.as_deref().unwrap_or(DEFAULT_BANDWIDTH_PROBE_PATH)
+    }
+
+    pub fn bandwidth_probe_size(&self) -> u64 {
+        self.bandwidth_probe_size.unwrap_or(DEFAULT_BANDWIDTH_PROBE_SIZE)
+    }
 }
 
 impl Properties for MirrorConfig {}
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(io::Error),
+    TomlError(toml::de::Error),
+    InvalidSetting(&'static str, String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::IoError(e) => write!(f, "Unable to read file {}: {}", CONFIG_FILE, e),
+            ConfigError::TomlError(e) => write!(f, "Unable to parse file {}: {}", CONFIG_FILE, e),
+            ConfigError::InvalidSetting(setting, e) => write!(f, "Invalid setting {}: {}", setting, e),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::IoError(error)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(error: toml::de::Error) -> Self {
+        ConfigError::TomlError(error)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
 pub struct MirrorConfig {
     pub cache_directory: String,
     pub mirrorlist_fallback_file: String,
     pub mirrorlist_latency_test_results_file: Option<String>,
     pub refresh_latency_tests_after: Option<String>,
-    pub port: u16,
+    pub port: Option<u16>,
     pub listen_ip_address: Option<String>,
     pub mirror_selection_method: MirrorSelectionMethod,
     pub mirrors_predefined: Vec<String>,
@@ -107,16 +212,250 @@ pub struct MirrorConfig {
     pub connect_timeout: Option<u64>,
     pub max_speed_limit: Option<u64>,
     pub num_versions_retain: Option<u32>,
+    pub prefetch_on_head_request: Option<bool>,
+    pub database_cache_ttl: Option<String>,
+    pub uncacheable_files_method: Option<UncacheableFilesMethod>,
+    pub offline: Option<bool>,
+    pub offline_after_failures: Option<u32>,
+    pub failure_penalty_half_life: Option<String>,
+    pub circuit_breaker_threshold: Option<u32>,
+    pub circuit_breaker_cooldown: Option<String>,
+    pub num_worker_threads: Option<usize>,
+    pub stalled_transfer_timeout: Option<String>,
+    pub shutdown_timeout: Option<String>,
+    pub allowed_clients: Option<Vec<String>>,
+    pub denied_clients: Option<Vec<String>>,
+    pub admin_allowed_clients: Option<Vec<String>>,
+    pub admin_denied_clients: Option<Vec<String>>,
+    pub admin_tokens: Option<Vec<String>>,
+    pub tls_port: Option<u16>,
+    pub tls_certificate: Option<String>,
+    pub tls_private_key: Option<String>,
+    pub tls_only: Option<bool>,
+    pub listeners: Option<Vec<ListenerConfig>>,
+    pub access_log: Option<String>,
+    pub access_log_format: Option<AccessLogFormat>,
     pub mirrors_auto: Option<MirrorsAutoConfig>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ListenerConfig {
+    /// Either an IP address and port, "unix:" followed by the path of a Unix domain socket, or "systemd" for
+    /// sockets passed by systemd socket activation.
+    pub address: String,
+    pub tls: Option<bool>,
+    pub admin_endpoints: Option<bool>,
+}
+
+impl ListenerConfig {
+    pub fn tls(&self) -> bool {
+        self.tls.unwrap_or(false)
+    }
+
+    pub fn admin_endpoints(&self) -> bool {
+        self.admin_endpoints.unwrap_or(true)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct CustomRepo {
     pub name: String,
     pub url: String,
 }
 
 impl MirrorConfig {
+    /// Checks the settings which would otherwise only be reported once they are used.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let durations = [
+            ("refresh_latency_tests_after", &self.refresh_latency_tests_after),
+            ("database_cache_ttl", &self.database_cache_ttl),
+            ("stalled_transfer_timeout", &self.stalled_transfer_timeout),
+            ("shutdown_timeout", &self.shutdown_timeout),
+            ("failure_penalty_half_life", &self.failure_penalty_half_life),
+            ("circuit_breaker_cooldown", &self.circuit_breaker_cooldown),
+            ("latency_test_deadline", &self.mirrors_auto.as_ref().and_then(|m| m.latency_test_deadline.clone())),
+        ];
+        for (setting, duration) in durations.iter() {
+            if let Some(d) = duration.as_deref() {
+                if let Err(e) = humantime::parse_duration(d) {
+                    return Err(ConfigError::InvalidSetting(setting, e.to_string()));
+                }
+            }
+        }
+        match self.mirror_selection_method {
+            MirrorSelectionMethod::Predefined if self.mirrors_predefined.is_empty() => {
+                return Err(ConfigError::InvalidSetting("mirrors_predefined", "No mirrors specified".to_owned()));
+            }
+            MirrorSelectionMethod::Predefined => {
+                let mut mirrors = HashSet::new();
+                if let Some(duplicate) = self.mirrors_predefined.iter().find(|m| !mirrors.insert(*m)) {
+                    let e = format!("Mirror {} is specified more than once", duplicate);
+                    return Err(ConfigError::InvalidSetting("mirrors_predefined", e));
+                }
+            }
+            MirrorSelectionMethod::Auto if self.mirrors_auto.is_none() => {
+                let e = "Required if mirror_selection_method is auto".to_owned();
+                return Err(ConfigError::InvalidSetting("mirrors_auto", e));
+            }
+            MirrorSelectionMethod::Auto => {}
+        }
+        if let Some(mirrors_auto) = &self.mirrors_auto {
+            let positive_settings = [
+                ("latency_test_parallelism", mirrors_auto.latency_test_parallelism.map(|p| p as u64)),
+                ("bandwidth_probe_size", mirrors_auto.bandwidth_probe_size),
+            ];
+            for (setting, value) in positive_settings.iter() {
+                if let Some(0) = value {
+                    return Err(ConfigError::InvalidSetting(setting, "Must be greater than 0".to_owned()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the mirrors need to be selected again in order to apply the other configuration.
+    pub fn mirrors_changed(&self, other: &MirrorConfig) -> bool {
+        self.mirror_selection_method != other.mirror_selection_method ||
+            self.mirrors_predefined != other.mirrors_predefined ||
+            self.mirrors_auto != other.mirrors_auto
+    }
+
+    /// Returns the settings that differ in the other configuration, but take effect only after Flexo has been
+    /// restarted. All other settings are applied while Flexo is running.
+    pub fn settings_requiring_restart(&self, other: &MirrorConfig) -> Vec<&'static str> {
+        let settings = [
+            ("cache_directory", self.cache_directory != other.cache_directory),
+            ("mirrorlist_fallback_file", self.mirrorlist_fallback_file != other.mirrorlist_fallback_file),
+            ("mirrorlist_latency_test_results_file",
+             self.mirrorlist_latency_test_results_file != other.mirrorlist_latency_test_results_file),
+            ("port", self.port != other.port),
+            ("listen_ip_address", self.listen_ip_address != other.listen_ip_address),
+            ("listeners", self.listeners != other.listeners),
+            ("tls_port", self.tls_port != other.tls_port),
+            ("tls_certificate", self.tls_certificate != other.tls_certificate),
+            ("tls_private_key", self.tls_private_key != other.tls_private_key),
+            ("tls_only", self.tls_only != other.tls_only),
+            ("num_worker_threads", self.num_worker_threads != other.num_worker_threads),
+            ("stalled_transfer_timeout", self.stalled_transfer_timeout != other.stalled_transfer_timeout),
+            ("shutdown_timeout", self.shutdown_timeout != other.shutdown_timeout),
+            ("allowed_clients", self.allowed_clients != other.allowed_clients),
+            ("denied_clients", self.denied_clients != other.denied_clients),
+            ("admin_allowed_clients", self.admin_allowed_clients != other.admin_allowed_clients),
+            ("admin_denied_clients", self.admin_denied_clients != other.admin_denied_clients),
+            ("admin_tokens", self.admin_tokens != other.admin_tokens),
+            ("access_log", self.access_log != other.access_log),
+            ("access_log_format", self.access_log_format != other.access_log_format),
+        ];
+        settings.iter().filter(|(_, changed)| *changed).map(|(setting, _)| *setting).collect()
+    }
+
+    pub fn prefetch_on_head_request(&self) -> bool {
+        self.prefetch_on_head_request.unwrap_or(false)
+    }
+
+    pub fn offline(&self) -> bool {
+        self.offline.unwrap_or(false)
+    }
+
+    pub fn tls_only(&self) -> bool {
+        self.tls_only.unwrap_or(false)
+    }
+
+    pub fn access_log_format(&self) -> AccessLogFormat {
+        self.access_log_format.unwrap_or(AccessLogFormat::Combined)
+    }
+
+    /// If the setting listeners is not set, the listeners are derived from the settings listen_ip_address, port,
+    /// tls_port and tls_only.
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        if let Some(listeners) = &self.listeners {
+            return listeners.clone();
+        }
+        let listen_ip_address = match self.listen_ip_address.as_deref().unwrap_or(DEFAULT_LISTEN_IP_ADDRESS) {
+            // IPv6 addresses need to be enclosed in brackets when followed by the port.
+            a if a.parse::<Ipv6Addr>().is_ok() => format!("[{}]", a),
+            a => a.to_owned(),
+        };
+        let mut listeners = Vec::new();
+        if !self.tls_only() {
+            listeners.push(ListenerConfig {
+                address: format!("{}:{}", listen_ip_address, self.port.unwrap_or(DEFAULT_PORT)),
+                tls: None,
+                admin_endpoints: None,
+            });
+        }
+        if let Some(tls_port) = self.tls_port {
+            listeners.push(ListenerConfig {
+                address: format!("{}:{}", listen_ip_address, tls_port),
+                tls: Some(true),
+                admin_endpoints: None,
+            });
+        }
+        listeners
+    }
+
+    pub fn num_worker_threads(&self) -> usize {
+        self.num_worker_threads.unwrap_or(DEFAULT_NUM_WORKER_THREADS).max(1)
+    }
+
+    /// Transfers of files that are being downloaded are aborted if the download has not made any progress for this
+    /// duration.
+    pub fn stalled_transfer_timeout(&self) -> Duration {
+        let default = Duration::from_secs(DEFAULT_STALLED_TRANSFER_TIMEOUT_SECONDS);
+        match &self.stalled_transfer_timeout {
+            None => default,
+            Some(s) => match humantime::parse_duration(s) {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("Unable to parse duration {:?}: {:?}", s, e);
+                    default
+                }
+            }
+        }
+    }
+
+    /// On shutdown, clients and downloads in progress are given this duration to finish.
+    pub fn shutdown_timeout(&self) -> Duration {
+        let default = Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT_SECONDS);
+        match &self.shutdown_timeout {
+            None => default,
+            Some(s) => match humantime::parse_duration(s) {
+                Ok(d) => d,
+                Err(e) => {
+                    error!("Unable to parse duration {:?}: {:?}", s, e);
+                    default
+                }
+            }
+        }
+    }
+
+    pub fn failure_policy(&self) -> FailurePolicy {
+        let default = FailurePolicy::default();
+        FailurePolicy {
+            penalty_half_life: duration_or_default(&self.failure_penalty_half_life, default.penalty_half_life),
+            circuit_breaker_threshold: self.circuit_breaker_threshold.unwrap_or(default.circuit_breaker_threshold),
+            circuit_breaker_cooldown:
+                duration_or_default(&self.circuit_breaker_cooldown, default.circuit_breaker_cooldown),
+        }
+    }
+
+    pub fn uncacheable_files_method(&self) -> UncacheableFilesMethod {
+        self.uncacheable_files_method.unwrap_or(UncacheableFilesMethod::Redirect)
+    }
+
+    /// Returns None if database files should not be cached, i.e., if clients should be redirected to a remote
+    /// mirror instead.
+    pub fn database_cache_ttl(&self) -> Option<Duration> {
+        let s = self.database_cache_ttl.as_ref()?;
+        match humantime::parse_duration(s) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                error!("Unable to parse duration {:?}: {:?}", s, e);
+                None
+            }
+        }
+    }
+
     pub fn refresh_latency_tests_after(&self) -> Duration {
         match &self.refresh_latency_tests_after {
             None => Duration::from_secs(DEFAULT_REFRESH_AFTER_SECONDS),
@@ -134,14 +473,9 @@ impl MirrorConfig {
     }
 }
 
-fn mirror_config_from_toml() -> MirrorConfig {
-    let config_contents = fs::read_to_string(CONFIG_FILE)
-        .unwrap_or_else(|_| panic!("Unable to read file: {}", CONFIG_FILE));
-    match toml::from_str(&config_contents) {
-        Ok(v) => v,
-        Err(e) => panic!("Unable to parse file {}: {:?}\nPlease make sure that the file contains \
-        valid TOML syntax and that all required attributes are set.", CONFIG_FILE, e)
-    }
+fn mirror_config_from_toml() -> Result<MirrorConfig, ConfigError> {
+    let config_contents = fs::read_to_string(CONFIG_FILE)?;
+    Ok(toml::from_str(&config_contents)?)
 }
 
 #[derive(Deserialize)]
@@ -181,6 +515,11 @@ fn mirrors_auto_config_from_env() -> MirrorsAutoConfig {
         .unwrap_or_default();
     let mirrors_blacklist =
         parse_env_toml::<Vec<String>>("FLEXO_MIRRORS_AUTO_MIRRORS_BLACKLIST").unwrap_or_else(Vec::new);
+    let latency_test_parallelism = parse_env_toml::<usize>("FLEXO_MIRRORS_AUTO_LATENCY_TEST_PARALLELISM");
+    let latency_test_deadline = parse_env_toml::<String>("FLEXO_MIRRORS_AUTO_LATENCY_TEST_DEADLINE");
+    let bandwidth_probe_num_mirrors = parse_env_toml::<usize>("FLEXO_MIRRORS_AUTO_BANDWIDTH_PROBE_NUM_MIRRORS");
+    let bandwidth_probe_path = parse_env_toml::<String>("FLEXO_MIRRORS_AUTO_BANDWIDTH_PROBE_PATH");
+    let bandwidth_probe_size = parse_env_toml::<u64>("FLEXO_MIRRORS_AUTO_BANDWIDTH_PROBE_SIZE");
     MirrorsAutoConfig {
         mirrors_status_json_endpoint,
         mirrors_status_json_endpoint_fallbacks,
@@ -192,7 +531,12 @@ fn mirrors_auto_config_from_env() -> MirrorsAutoConfig {
         num_mirrors,
         mirrors_random_or_sort,
         timeout,
-        allowed_countries
+        allowed_countries,
+        latency_test_parallelism,
+        latency_test_deadline,
+        bandwidth_probe_num_mirrors,
+        bandwidth_probe_path,
+        bandwidth_probe_size,
     }
 }
 
@@ -201,7 +545,7 @@ fn mirror_config_from_env() -> MirrorConfig {
     let mirrorlist_fallback_file = parse_env_toml::<String>("FLEXO_MIRRORLIST_FALLBACK_FILE").unwrap();
     let mirrorlist_latency_test_results_file = parse_env_toml::<String>("FLEXO_MIRRORLIST_LATENCY_TEST_RESULTS_FILE");
     let listen_ip_address = parse_env_toml::<String>("FLEXO_LISTEN_IP_ADDRESS");
-    let port = parse_env_toml::<u16>("FLEXO_PORT").unwrap();
+    let port = parse_env_toml::<u16>("FLEXO_PORT");
     let mirror_selection_method = parse_env_toml::<MirrorSelectionMethod>("FLEXO_MIRROR_SELECTION_METHOD").unwrap();
     let mirrors_predefined = parse_env_toml::<Vec<String>>("FLEXO_MIRRORS_PREDEFINED").unwrap();
     let connect_timeout = parse_env_toml::<u64>("FLEXO_CONNECT_TIMEOUT");
@@ -211,6 +555,29 @@ fn mirror_config_from_env() -> MirrorConfig {
     let refresh_latency_tests_after = parse_env_toml::<String>("FLEXO_REFRESH_LATENCY_TESTS_AFTER");
     let custom_repo_env = parse_env_toml::<String>("FLEXO_CUSTOM_REPO");
     let num_versions_retain = parse_env_toml::<u32>("FLEXO_NUM_VERSIONS_RETAIN");
+    let prefetch_on_head_request = parse_env_toml::<bool>("FLEXO_PREFETCH_ON_HEAD_REQUEST");
+    let database_cache_ttl = parse_env_toml::<String>("FLEXO_DATABASE_CACHE_TTL");
+    let uncacheable_files_method = parse_env_toml::<UncacheableFilesMethod>("FLEXO_UNCACHEABLE_FILES_METHOD");
+    let offline = parse_env_toml::<bool>("FLEXO_OFFLINE");
+    let offline_after_failures = parse_env_toml::<u32>("FLEXO_OFFLINE_AFTER_FAILURES");
+    let failure_penalty_half_life = parse_env_toml::<String>("FLEXO_FAILURE_PENALTY_HALF_LIFE");
+    let circuit_breaker_threshold = parse_env_toml::<u32>("FLEXO_CIRCUIT_BREAKER_THRESHOLD");
+    let circuit_breaker_cooldown = parse_env_toml::<String>("FLEXO_CIRCUIT_BREAKER_COOLDOWN");
+    let num_worker_threads = parse_env_toml::<usize>("FLEXO_NUM_WORKER_THREADS");
+    let stalled_transfer_timeout = parse_env_toml::<String>("FLEXO_STALLED_TRANSFER_TIMEOUT");
+    let shutdown_timeout = parse_env_toml::<String>("FLEXO_SHUTDOWN_TIMEOUT");
+    let allowed_clients = parse_env_toml::<Vec<String>>("FLEXO_ALLOWED_CLIENTS");
+    let denied_clients = parse_env_toml::<Vec<String>>("FLEXO_DENIED_CLIENTS");
+    let admin_allowed_clients = parse_env_toml::<Vec<String>>("FLEXO_ADMIN_ALLOWED_CLIENTS");
+    let admin_denied_clients = parse_env_toml::<Vec<String>>("FLEXO_ADMIN_DENIED_CLIENTS");
+    let admin_tokens = parse_env_toml::<Vec<String>>("FLEXO_ADMIN_TOKENS");
+    let tls_port = parse_env_toml::<u16>("FLEXO_TLS_PORT");
+    let tls_certificate = parse_env_toml::<String>("FLEXO_TLS_CERTIFICATE");
+    let tls_private_key = parse_env_toml::<String>("FLEXO_TLS_PRIVATE_KEY");
+    let tls_only = parse_env_toml::<bool>("FLEXO_TLS_ONLY");
+    let listeners = parse_env_toml::<Vec<ListenerConfig>>("FLEXO_LISTENERS");
+    let access_log = parse_env_toml::<String>("FLEXO_ACCESS_LOG");
+    let access_log_format = parse_env_toml::<AccessLogFormat>("FLEXO_ACCESS_LOG_FORMAT");
     let custom_repo = custom_repos_from_env(custom_repo_env);
 
     let mirrors_auto = match mirror_selection_method {
@@ -232,10 +599,46 @@ fn mirror_config_from_env() -> MirrorConfig {
         connect_timeout,
         max_speed_limit,
         num_versions_retain,
+        prefetch_on_head_request,
+        database_cache_ttl,
+        uncacheable_files_method,
+        offline,
+        offline_after_failures,
+        failure_penalty_half_life,
+        circuit_breaker_threshold,
+        circuit_breaker_cooldown,
+        num_worker_threads,
+        stalled_transfer_timeout,
+        shutdown_timeout,
+        allowed_clients,
+        denied_clients,
+        admin_allowed_clients,
+        admin_denied_clients,
+        admin_tokens,
+        tls_port,
+        tls_certificate,
+        tls_private_key,
+        tls_only,
+        listeners,
+        access_log,
+        access_log_format,
         mirrors_auto
     }
 }
 
+fn duration_or_default(duration: &Option<String>, default: Duration) -> Duration {
+    match duration {
+        None => default,
+        Some(s) => match humantime::parse_duration(s) {
+            Ok(d) => d,
+            Err(e) => {
+                error!("Unable to parse duration {:?}: {:?}", s, e);
+                default
+            }
+        }
+    }
+}
+
 fn comma_separated_to_vec(comma_separated: String) -> Vec<String> {
     comma_separated
         .split(',')
@@ -271,10 +674,32 @@ pub fn split_once<'a>(s: &'a str, delimiter: &'a str) -> Option<(&'a str, &'a st
     }
 }
 
+fn config_from_env() -> bool {
+    std::env::vars().any(|(key, _value)| key.starts_with("FLEXO_"))
+}
+
 pub fn load_config() -> MirrorConfig {
-    if std::env::vars().any(|(key, _value)| key.starts_with("FLEXO_")) {
+    if config_from_env() {
         mirror_config_from_env()
     } else {
-        mirror_config_from_toml()
+        match mirror_config_from_toml() {
+            Ok(v) => v,
+            Err(ConfigError::IoError(_)) => panic!("Unable to read file: {}", CONFIG_FILE),
+            Err(e) => panic!("Unable to parse file {}: {:?}\nPlease make sure that the file contains \
+            valid TOML syntax and that all required attributes are set.", CONFIG_FILE, e)
+        }
     }
 }
+
+/// Loads and validates the configuration while Flexo is running. Unlike load_config, errors are returned instead of
+/// causing a panic. Notice that environment variables cannot change while Flexo is running, so reloading only
+/// has an effect if the configuration is read from the TOML file.
+pub fn reload_config() -> Result<MirrorConfig, ConfigError> {
+    let config = if config_from_env() {
+        mirror_config_from_env()
+    } else {
+        mirror_config_from_toml()?
+    };
+    config.validate()?;
+    Ok(config)
+}
@@ -2,7 +2,7 @@ extern crate serde;
 use serde::Deserialize;
 use crate::mirror_config::MirrorsAutoConfig;
 use curl::easy::{Easy, HttpVersion};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::str;
 use crate::MirrorResults;
 use crate::mirror_fetch::MirrorFetchError::{CurlError, DemarshallError, Utf8Error};
@@ -235,6 +235,80 @@ pub fn measure_latency(url: &str, timeout: Duration) -> Result<MirrorResults, cu
         pretransfer_time: easy.pretransfer_time()?,
         total_time: easy.total_time()?,
         starttransfer_time: easy.starttransfer_time()?,
+        throughput: None,
+        ranking_latency: None,
     })
 }
 
+/// Downloads up to `size` bytes of the file at the given path and returns the throughput in bytes per second.
+/// The time until the first chunk has been received is excluded, since it depends on the latency rather than on the
+/// bandwidth. Returns None if the mirror did not send enough data to estimate the throughput.
+pub fn measure_throughput(url: &str, path: &str, size: u64, timeout: Duration) -> Result<Option<u64>, curl::Error> {
+    let mut easy = Easy::new();
+    easy.url(&(url.to_owned() + path.trim_start_matches('/')))?;
+    easy.follow_location(true)?;
+    easy.timeout(timeout)?;
+    easy.http_version(HttpVersion::V11)?;
+    easy.fail_on_error(true)?;
+    easy.range(&format!("0-{}", size - 1))?;
+    let mut size_received: u64 = 0;
+    let mut first_chunk: Option<(Instant, u64)> = None;
+    let mut last_chunk_received = Instant::now();
+    let result = {
+        let mut transfer = easy.transfer();
+        transfer.write_function(|data| {
+            size_received += data.len() as u64;
+            last_chunk_received = Instant::now();
+            if first_chunk.is_none() {
+                first_chunk = Some((last_chunk_received, size_received));
+            }
+            // Some servers ignore the range header: Abort the transfer once enough data has been received.
+            if size_received >= size {
+                Ok(0)
+            } else {
+                Ok(data.len())
+            }
+        })?;
+        transfer.perform()
+    };
+    match result {
+        Ok(()) => {},
+        // A slow mirror is not considered a failure: We still use the data it has sent until the timeout.
+        Err(e) if e.is_write_error() || e.is_operation_timedout() => {},
+        Err(e) => return Err(e),
+    }
+    let throughput = first_chunk.and_then(|(first_chunk_received, first_chunk_size)| {
+        let elapsed = last_chunk_received.duration_since(first_chunk_received).as_secs_f64();
+        let size = size_received - first_chunk_size;
+        if size > 0 && elapsed > 0.0 {
+            Some((size as f64 / elapsed) as u64)
+        } else {
+            None
+        }
+    });
+    Ok(throughput)
+}
+
+
+#[test]
+fn test_measure_throughput() {
+    use std::io::{Read, Write};
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = [0; 1024];
+        let _ = stream.read(&mut request).unwrap();
+        // The server ignores the range header, so the transfer needs to be aborted by the client.
+        stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 1048576\r\n\r\n").unwrap();
+        for _ in 0..64 {
+            if stream.write_all(&[0; 16384]).is_err() {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    });
+    let throughput = measure_throughput(&url, "extra.files", 262144, Duration::from_secs(10)).unwrap().unwrap();
+    // About 16 KiB are sent every 5 milliseconds.
+    assert!(throughput > 100_000 && throughput < 10_000_000, "unexpected throughput: {}", throughput);
+}
@@ -1,31 +1,34 @@
 extern crate flexo;
 
 use std::{fs, str};
-use std::cmp::Ordering;
+use std::cmp::{Ordering, Reverse};
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::BufWriter;
-use std::io::{ErrorKind, Read, Write};
+use std::io::{ErrorKind, Write};
 use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
 use std::string::FromUtf8Error;
-use std::time::Duration;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::os::unix::ffi::OsStrExt;
 
-use crossbeam::channel::Sender;
-use curl::easy::{Easy2, Handler, HttpVersion, WriteError};
+use curl::easy::{Easy, Easy2, Handler, HttpVersion, List, WriteError};
 use httparse::{Header, Status};
 use serde::{Deserialize, Serialize};
 use walkdir::WalkDir;
 
 use flexo::*;
 
-use crate::mirror_config::{MirrorConfig, MirrorsAutoConfig};
+use crate::mirror_config::{MirrorConfig, MirrorsAutoConfig, split_once};
 use crate::mirror_fetch;
 use crate::mirror_fetch::{MirrorProtocol, Mirror};
 use crate::str_path::StrPath;
 use uuid::Uuid;
-use crate::mirror_flexo::RequestMethod::{Get, Post};
+use crate::mirror_flexo::RequestMethod::{Get, Head, Post};
 
 // Since a restriction for the size of header fields is also implemented by web servers like NGINX or Apache,
 // we keep things simple by just setting a fixed buffer length.
@@ -34,6 +37,10 @@ const MAX_HEADER_SIZE: usize = 8192;
 
 const MAX_HEADER_COUNT: usize = 64;
 
+/// Endpoints that accept POST requests.
+const POST_ENDPOINTS: [&str; 4] =
+    ["/reset-metrics", "/enable-offline-mode", "/disable-offline-mode", "/reload-config"];
+
 #[cfg(test)]
 const TEST_CHUNK_SIZE: usize = 128;
 
@@ -48,10 +55,17 @@ const MAX_REDIRECTIONS: u32 = 3;
 
 const LATENCY_TEST_NUM_ATTEMPTS: u32 = 5;
 
+// The bandwidth probe of a single mirror is aborted after this period of time. The throughput is then estimated from
+// the data received so far.
+const BANDWIDTH_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub const UNCACHEABLE_DIRECTORY: &str = "/tmp/flexo/uncacheable";
 
 const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_millis(3000);
 
+// A HEAD request to a mirror that accepts the connection but never replies is aborted after this period of time.
+const HEAD_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum ClientError {
     BufferSizeExceeded,
@@ -61,6 +75,8 @@ pub enum ClientError {
     InvalidHeader(ClientStatus),
     Other(ErrorKind),
     FileAttrError(FileAttrError),
+    /// The client is not permitted to access Flexo.
+    Forbidden,
 }
 
 impl From<std::io::Error> for ClientError {
@@ -111,66 +127,263 @@ impl CountryFilter {
     }
 }
 
-fn parse_range_header_value(s: &str) -> Result<u64, ClientError> {
-    let s = s.to_lowercase();
-    // We ignore everything after the - sign: We assume that pacman will never request only up to a certain size,
-    // pacman will only skip the beginning of a file if the file has already been partially downloaded.
-    match s.split('-').next() {
-        None => {
-            debug!("Unable to read the range header from the HTTP request.");
-            Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
+/// A single byte-range-spec as submitted by the client in the Range header, see RFC 7233, section 2.1.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ByteRange {
+    /// A range starting at the given offset, e.g. "bytes=100-199" or "bytes=100-". Both offsets are inclusive.
+    FromOffset { first: u64, last: Option<u64> },
+    /// The final bytes of the file, e.g. "bytes=-500" for the last 500 bytes.
+    Suffix(u64),
+}
+
+/// A byte range that has been resolved against the complete size of the file. Both offsets are inclusive and
+/// refer to bytes that exist in the complete file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct SatisfiableRange {
+    pub first: u64,
+    pub last: u64,
+}
+
+impl SatisfiableRange {
+    pub fn len(&self) -> u64 {
+        self.last - self.first + 1
+    }
+}
+
+impl ByteRange {
+    /// Returns None if the range is not satisfiable for a file of the given size.
+    pub fn resolve(&self, complete_size: u64) -> Option<SatisfiableRange> {
+        if complete_size == 0 {
+            return None;
         }
-        Some(range_start) => {
-            match range_start.replace("bytes=", "").parse::<u64>() {
-                Ok(v) => Ok(v),
-                Err(_) => {
-                    error!("Invalid range start submitted by client: {}", range_start);
-                    Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
-                }
+        match *self {
+            ByteRange::FromOffset { first, .. } if first >= complete_size => None,
+            ByteRange::FromOffset { first, last } => {
+                let last = last.map(|l| l.min(complete_size - 1)).unwrap_or(complete_size - 1);
+                Some(SatisfiableRange { first, last })
+            }
+            ByteRange::Suffix(0) => None,
+            ByteRange::Suffix(suffix_length) => {
+                let first = complete_size.saturating_sub(suffix_length);
+                Some(SatisfiableRange { first, last: complete_size - 1 })
             }
         }
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum ClientResponse {
-    Request(Request),
-    SocketClosed,
+/// Resolves all ranges requested by the client. Ranges that cannot be satisfied are omitted, None is returned
+/// if none of the ranges can be satisfied. The ranges are sorted, and overlapping or adjacent ranges are coalesced,
+/// so that no byte is sent more than once, see RFC 7233, section 6.1.
+pub fn resolve_byte_ranges(byte_ranges: &[ByteRange], complete_size: u64) -> Option<Vec<SatisfiableRange>> {
+    let mut satisfiable = byte_ranges.iter()
+        .filter_map(|r| r.resolve(complete_size))
+        .collect::<Vec<SatisfiableRange>>();
+    satisfiable.sort_by_key(|r| r.first);
+    let mut coalesced: Vec<SatisfiableRange> = Vec::with_capacity(satisfiable.len());
+    for range in satisfiable {
+        match coalesced.last_mut() {
+            Some(previous) if range.first <= previous.last.saturating_add(1) => {
+                previous.last = previous.last.max(range.last);
+            }
+            _ => coalesced.push(range),
+        }
+    }
+    if coalesced.is_empty() {
+        None
+    } else {
+        Some(coalesced)
+    }
+}
+
+fn parse_range_header_value(s: &str) -> Result<Vec<ByteRange>, ClientError> {
+    let s = s.to_lowercase();
+    let byte_range_set = match s.trim().strip_prefix("bytes=") {
+        None => {
+            warn!("Unsupported range unit submitted by client: {}", s);
+            return Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()));
+        }
+        Some(b) => b,
+    };
+    let byte_ranges = byte_range_set.split(',')
+        .map(|spec| spec.trim())
+        .filter(|spec| !spec.is_empty())
+        .map(parse_byte_range_spec)
+        .collect::<Result<Vec<ByteRange>, ClientError>>()?;
+    if byte_ranges.is_empty() {
+        debug!("Unable to read the range header from the HTTP request.");
+        Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
+    } else {
+        Ok(byte_ranges)
+    }
+}
+
+fn parse_byte_range_spec(spec: &str) -> Result<ByteRange, ClientError> {
+    let invalid = || {
+        warn!("Invalid range submitted by client: {}", spec);
+        ClientError::InvalidHeader(ClientStatus::no_response_headers_sent())
+    };
+    let (first, last) = split_once(spec, "-").ok_or_else(invalid)?;
+    match (first.trim(), last.trim()) {
+        ("", suffix_length) => {
+            let suffix_length = suffix_length.parse::<u64>().map_err(|_| invalid())?;
+            Ok(ByteRange::Suffix(suffix_length))
+        }
+        (first, "") => {
+            let first = first.parse::<u64>().map_err(|_| invalid())?;
+            Ok(ByteRange::FromOffset { first, last: None })
+        }
+        (first, last) => {
+            let first = first.parse::<u64>().map_err(|_| invalid())?;
+            let last = last.parse::<u64>().map_err(|_| invalid())?;
+            if last < first {
+                Err(invalid())
+            } else {
+                Ok(ByteRange::FromOffset { first, last: Some(last) })
+            }
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Request {
-    pub resume_from: Option<u64>,
+    pub byte_ranges: Option<Vec<ByteRange>>,
+    pub conditional_headers: ConditionalHeaders,
     pub path: StrPath,
     pub method: RequestMethod,
+    /// The value of the Authorization header.
+    pub authorization: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+fn header_value<'a>(headers: &[Header<'a>], name: &str) -> Result<Option<&'a str>, ClientError> {
+    let header = headers.iter().find(|h| h.name.eq_ignore_ascii_case(name));
+    match header {
+        None => Ok(None),
+        Some(h) => match str::from_utf8(h.value) {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => {
+                error!("Unable to parse header value to UTF8");
+                Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
+            }
+        }
+    }
+}
+
+/// The headers sent by the client to make the request conditional, see RFC 7232.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct ConditionalHeaders {
+    pub if_none_match: Option<String>,
+    pub if_modified_since: Option<String>,
+}
+
+impl ConditionalHeaders {
+    /// Returns true if the client's copy of the file is still up to date, so that 304 can be returned.
+    pub fn is_not_modified(&self, validators: &Validators) -> bool {
+        match (&self.if_none_match, &self.if_modified_since) {
+            // If-Modified-Since is ignored if If-None-Match is present, see RFC 7232, section 3.3.
+            (Some(if_none_match), _) => {
+                if_none_match.split(',').map(|etag| etag.trim()).any(|etag| {
+                    etag == "*" || weak_etag(etag) == weak_etag(&validators.etag)
+                })
+            }
+            (None, Some(if_modified_since)) => {
+                match chrono::DateTime::parse_from_rfc2822(if_modified_since) {
+                    Ok(dt) => validators.last_modified.timestamp() <= dt.timestamp(),
+                    Err(e) => {
+                        debug!("Ignoring invalid If-Modified-Since header {:?}: {:?}", if_modified_since, e);
+                        false
+                    }
+                }
+            }
+            (None, None) => false,
+        }
+    }
+}
+
+/// The ETag without the prefix that marks it as a weak validator: If-None-Match uses the weak comparison
+/// function, see RFC 7232, section 2.3.2.
+fn weak_etag(etag: &str) -> &str {
+    etag.strip_prefix("W/").unwrap_or(etag)
+}
+
+/// Validators sent to the client for files that are served from the cache, see RFC 7232.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Validators {
+    pub last_modified: chrono::DateTime<chrono::Utc>,
+    pub etag: String,
+}
+
+impl Validators {
+    pub fn new(modified: std::time::SystemTime, complete_size: u64) -> Self {
+        let last_modified = chrono::DateTime::<chrono::Utc>::from(modified);
+        let etag = format!("\"{:x}-{:x}\"", last_modified.timestamp(), complete_size);
+        Validators {
+            last_modified,
+            etag,
+        }
+    }
+
+    /// Returns the validators of a file stored in the cache directory, or None if the file is not stored in cache.
+    pub fn from_cached_file(path: &Path) -> Option<Self> {
+        let metadata = match fs::metadata(path) {
+            Ok(m) => m,
+            Err(e) => {
+                warn!("Unable to fetch metadata for file {:?}: {:?}", path, e);
+                return None;
+            }
+        };
+        let complete_size = get_complete_size_from_cfs_file(path).unwrap_or(metadata.len());
+        let modified = metadata.modified().ok()?;
+        Some(Validators::new(modified, complete_size))
+    }
+
+    pub fn headers(&self) -> Vec<String> {
+        vec![
+            format!("Last-Modified: {}", http_date(&self.last_modified)),
+            format!("ETag: {}", self.etag),
+        ]
+    }
+}
+
+fn http_date(date_time: &chrono::DateTime<chrono::Utc>) -> String {
+    date_time.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum RequestMethod {
     Get,
+    Head,
     Post,
 }
 
+impl RequestMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Get => "GET",
+            Head => "HEAD",
+            Post => "POST",
+        }
+    }
+}
+
 impl Request {
     fn new(request: httparse::Request) -> Result<Self, ClientError> {
-        let range_header = request.headers
-            .iter()
-            .find(|h| h.name.eq_ignore_ascii_case("range"));
-        let range_header_value = range_header.map(|h| {
-            match str::from_utf8(h.value) {
-                Ok(v) => Ok(v),
-                Err(_) => {
-                    error!("Unable to parse header value to UTF8");
-                    Err(ClientError::InvalidHeader(ClientStatus::no_response_headers_sent()))
-                }
-            }
-        });
-        let resume_from = match range_header_value {
-            None => None,
-            Some(v) => {
-                Some(parse_range_header_value(v?)?)
-            }
+        // An invalid Range header is ignored, so that the complete file is served, see RFC 7233, section 3.1.
+        let byte_ranges = match header_value(request.headers, "range") {
+            Ok(Some(v)) => parse_range_header_value(v).ok(),
+            Ok(None) | Err(_) => None,
         };
+        let conditional_headers = ConditionalHeaders {
+            if_none_match: header_value(request.headers, "if-none-match")?.map(str::to_owned),
+            if_modified_since: header_value(request.headers, "if-modified-since")?.map(str::to_owned),
+        };
+        // An invalid Authorization header is treated as absent: The header is irrelevant unless tokens are configured,
+        // in which case the client is asked to authenticate.
+        let authorization = header_value(request.headers, "authorization").ok().flatten().map(str::to_owned);
+        // The User-Agent is only used for logging, so it must not cause the request to be rejected.
+        let user_agent = request.headers.iter()
+            .find(|h| h.name.eq_ignore_ascii_case("user-agent"))
+            .map(|h| String::from_utf8_lossy(h.value).into_owned());
         let path = match request.path {
             None => {
                 let client_status = ClientStatus { response_headers_sent: false };
@@ -180,7 +393,8 @@ impl Request {
         }?;
         let request_method = match request.method {
             Some("GET") => Get,
-            Some("POST") if path == "/reset-metrics" => Post,
+            Some("HEAD") => Head,
+            Some("POST") if POST_ENDPOINTS.contains(&path) => Post,
             Some(method) => {
                 error!("Unsupported HTTP method: {}", method);
                 return Err(ClientError::UnsupportedHttpMethod(ClientStatus::no_response_headers_sent()));
@@ -194,9 +408,26 @@ impl Request {
         Ok(Self {
             path: request_path,
             method: request_method,
-            resume_from,
+            byte_ranges,
+            conditional_headers,
+            authorization,
+            user_agent,
         })
     }
+
+    /// The first byte the client is interested in, or None if the client requires the file from the beginning.
+    pub fn resume_from(&self) -> Option<u64> {
+        let byte_ranges = self.byte_ranges.as_ref()?;
+        if byte_ranges.iter().any(|r| matches!(r, ByteRange::Suffix(_))) {
+            // The offset of a suffix range is unknown until we know the complete size of the file, so we
+            // need to be able to serve the file from the beginning.
+            return None;
+        }
+        byte_ranges.iter().filter_map(|r| match r {
+            ByteRange::FromOffset { first, .. } => Some(*first),
+            ByteRange::Suffix(_) => None,
+        }).min()
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Debug)]
@@ -242,16 +473,31 @@ pub struct MirrorResults {
     pub connect_duration: Duration,
     pub pretransfer_time: Duration,
     pub starttransfer_time: Duration,
+    /// The throughput in bytes per second measured by the bandwidth probe, if the mirror was probed.
+    #[serde(default)]
+    pub throughput: Option<u64>,
+    /// Set by the bandwidth probe: The mirrors with a measured throughput swap their positions among each other, so
+    /// each of them is ranked as if it had the latency of the position it has been moved to.
+    #[serde(default)]
+    pub ranking_latency: Option<Duration>,
 }
 
-impl Ord for MirrorResults {
-    fn cmp(&self, other: &Self) -> Ordering {
+impl MirrorResults {
+    fn latency(&self) -> Duration {
         // namelookup_duration is excluded for performance comparisons, because DNS lookups are usually
         // cached, so we can assume that slow DNS lookups usually will not affect the latency experienced
         // by the user.
-        let self_latency = self.total_time - self.namelookup_duration;
-        let other_latency = other.total_time - other.namelookup_duration;
-        self_latency.cmp(&other_latency)
+        self.total_time - self.namelookup_duration
+    }
+
+    fn ranking_key(&self) -> (Duration, Reverse<Option<u64>>, Duration) {
+        (self.ranking_latency.unwrap_or_else(|| self.latency()), Reverse(self.throughput), self.latency())
+    }
+}
+
+impl Ord for MirrorResults {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ranking_key().cmp(&other.ranking_key())
     }
 }
 
@@ -267,6 +513,50 @@ pub enum DownloadJobError {
     HttpFailureStatus(u32),
 }
 
+/// The outcome of a HEAD request sent to a remote mirror.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RemoteHeadOutcome {
+    /// The file is available at the remote mirror, with the given content length.
+    Available(u64),
+    /// The remote mirror has returned 404.
+    Unavailable,
+    /// The remote mirror has returned an unexpected status code.
+    Failure(u32),
+}
+
+/// Fetches the headers of the given file from the remote mirror, without downloading the payload.
+pub fn fetch_remote_head(uri: &str, properties: &MirrorConfig) -> Result<RemoteHeadOutcome, curl::Error> {
+    debug!("Send HEAD request to {}", uri);
+    let mut easy = Easy::new();
+    easy.url(uri)?;
+    easy.nobody(true)?;
+    easy.follow_location(true)?;
+    easy.max_redirections(MAX_REDIRECTIONS)?;
+    easy.http_version(HttpVersion::V11)?;
+    let connect_timeout = match properties.connect_timeout {
+        None => DEFAULT_CONNECT_TIMEOUT,
+        Some(timeout) => Duration::from_millis(timeout),
+    };
+    easy.connect_timeout(connect_timeout)?;
+    easy.timeout(HEAD_REQUEST_TIMEOUT)?;
+    easy.perform()?;
+    let response_code = easy.response_code()?;
+    debug!("{} replied with status code {}.", uri, response_code);
+    if (200..300).contains(&response_code) {
+        let content_length = easy.content_length_download()?;
+        if content_length < 0.0 {
+            // curl returns -1 if the content length is unknown.
+            Ok(RemoteHeadOutcome::Failure(response_code))
+        } else {
+            Ok(RemoteHeadOutcome::Available(content_length as u64))
+        }
+    } else if response_code == 404 {
+        Ok(RemoteHeadOutcome::Unavailable)
+    } else {
+        Ok(RemoteHeadOutcome::Failure(response_code))
+    }
+}
+
 #[derive(Debug)]
 pub struct DownloadJob {
     provider: DownloadProvider,
@@ -357,8 +647,11 @@ impl Job for DownloadJob {
             Some(timeout) => Duration::from_millis(timeout),
         };
         channel.handle.connect_timeout(connect_timeout).unwrap();
+        // Channels are reused, so limits that have been removed by reloading the configuration need to be reset.
         match properties.low_speed_limit {
-            None => {},
+            None => {
+                channel.handle.low_speed_limit(0).unwrap();
+            },
             Some(speed) => {
                 channel.handle.low_speed_limit(speed).unwrap();
                 let low_speed_time_secs = properties.low_speed_time_secs.unwrap_or(DEFAULT_LOW_SPEED_TIME_SECS);
@@ -368,7 +661,8 @@ impl Job for DownloadJob {
         }
         match properties.max_speed_limit {
             None => {
-                debug!("No speed limit was set.")
+                debug!("No speed limit was set.");
+                channel.handle.max_recv_speed(0).unwrap();
             },
             Some(speed) => {
                 info!("Apply speed limit of {}/s", size_to_human_readable(speed));
@@ -377,6 +671,19 @@ impl Job for DownloadJob {
         }
         channel.handle.follow_location(true).unwrap();
         channel.handle.max_redirections(MAX_REDIRECTIONS).unwrap();
+        // Required to abandon providers that take too long to reply, see DownloadState::progress.
+        channel.handle.progress(true).unwrap();
+        // Channels are reused for subsequent orders, so the headers need to be reset for all other orders.
+        let mut headers = List::new();
+        if self.order.database_refresh {
+            if let Some(if_modified_since) = database_if_modified_since(&self.order.database_path(properties)) {
+                debug!("Revalidate database with If-Modified-Since: {}", &if_modified_since);
+                headers.append(&format!("If-Modified-Since: {}", if_modified_since)).unwrap();
+            }
+        }
+        channel.handle.http_headers(headers).unwrap();
+        // The Last-Modified header of a database is stored as the modification time of the cached copy.
+        channel.handle.fetch_filetime(self.order.database_refresh).unwrap();
         match channel.progress_indicator() {
             None => {},
             Some(start) => {
@@ -390,8 +697,19 @@ impl Job for DownloadJob {
                 debug!("{} replied with status code {}.", self.provider.identifier(), response_code);
                 if (200..300).contains(&response_code) {
                     let size = channel.progress_indicator().unwrap();
+                    if self.order.database_refresh {
+                        install_database(&mut channel, &self.order, properties, size);
+                    }
                     JobResult::Complete(JobCompleted::new(channel, self.provider, size as i64))
+                } else if response_code == 304 && self.order.database_refresh {
+                    debug!("Database {:?} has not been modified.", self.order.requested_path.to_str());
+                    mark_database_validated(&self.order.database_path(properties));
+                    discard_database_download(&self.order, properties);
+                    JobResult::Complete(JobCompleted::new(channel, self.provider, 0))
                 } else if response_code == 404 {
+                    if self.order.database_refresh {
+                        discard_database_download(&self.order, properties);
+                    }
                     JobResult::Unavailable(channel)
                 } else {
                     let termination = JobTerminated {
@@ -404,6 +722,11 @@ impl Job for DownloadJob {
             Err(e) => {
                 if e.code() == CURLE_OPERATION_TIMEDOUT {
                     warn!("Unable to download from {:?}: Timeout reached. Try another remote mirror.", &self.uri);
+                } else if e.is_aborted_by_callback() && channel.handle.get_ref().job_state.tx.is_cancelled() {
+                    info!("Download from {:?} has been cancelled.", &self.uri);
+                } else if e.is_aborted_by_callback() {
+                    warn!("Unable to download from {:?}: No reply received in time. Try another remote mirror.",
+                          &self.uri);
                 } else {
                     warn!("An unknown error occurred while downloading from remote mirror {:?}: {:?}", &self.uri, e);
                 }
@@ -445,7 +768,14 @@ impl Job for DownloadJob {
     ) -> std::io::Result<DownloadJobResources> {
         let path = order.filepath(&properties);
         debug!("Attempt to create file: {:?}", &path);
-        let f = match OpenOptions::new().create(true).append(true).open(&path) {
+        // Database files are always downloaded from the beginning, leftovers from a previous attempt are discarded.
+        let mut open_options = OpenOptions::new();
+        if order.database_refresh {
+            open_options.create(true).write(true).truncate(true);
+        } else {
+            open_options.create(true).append(true);
+        }
+        let f = match open_options.open(&path) {
             Ok(f) => f,
             Err(e) => {
                 warn!("Unable to create file: {:?}", e);
@@ -458,7 +788,7 @@ impl Job for DownloadJob {
                     };
                     info!("The directory {:?} will be created.", &parent);
                     fs::create_dir_all(parent)?;
-                    OpenOptions::new().create(true).append(true).open(&path)?
+                    open_options.open(&path)?
                 } else {
                     return Err(e);
                 }
@@ -502,6 +832,16 @@ pub fn inspect_and_initialize_cache(mirror_config: &MirrorConfig) {
     info!("Retrieved {} files with a total size of {} from local file system.", count_cache_items, size_formatted);
 }
 
+/// The total size of all files in the cache directory.
+pub fn cache_size(cache_directory: &str) -> u64 {
+    WalkDir::new(cache_directory).into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter_map(|entry| entry.metadata().ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
 fn persist_and_get_cache_state(path: &Path) -> Option<CachedItem> {
     debug!("Determine cache state for path {:?}", &path);
     let file = match File::open(path) {
@@ -594,11 +934,32 @@ fn cfs_path_from_pkg_path(path: &Path) -> Option<PathBuf> {
     }
 }
 
-#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct DownloadOrder {
     /// This path is relative to the given root directory.
     pub requested_path: StrPath,
     pub id: Uuid,
+    /// Set if a database file is downloaded in order to refresh the copy stored in the cache.
+    pub database_refresh: bool,
+}
+
+// Two refresh orders for the same database are equal, so that concurrent revalidations of a database are
+// served by a single job. All other orders are distinguished by their id.
+impl PartialEq for DownloadOrder {
+    fn eq(&self, other: &Self) -> bool {
+        self.requested_path == other.requested_path &&
+            self.database_refresh == other.database_refresh &&
+            (self.database_refresh || self.id == other.id)
+    }
+}
+
+impl Eq for DownloadOrder {}
+
+impl Hash for DownloadOrder {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.requested_path.hash(state);
+        self.database_refresh.hash(state);
+    }
 }
 
 impl Order for DownloadOrder {
@@ -607,7 +968,7 @@ impl Order for DownloadOrder {
     fn new_channel(
         self,
         properties: MirrorConfig,
-        tx: Sender<FlexoProgress>,
+        tx: ProgressNotifier,
         last_chance: bool,
     ) -> Result<DownloadChannel, <Self::J as Job>::OE> {
         let download_state = DownloadState::new(self, properties, tx, last_chance)?;
@@ -619,7 +980,7 @@ impl Order for DownloadOrder {
     fn reuse_channel(
         self,
         properties: MirrorConfig,
-        tx: Sender<FlexoProgress>,
+        tx: ProgressNotifier,
         last_chance: bool,
         previous_channel: DownloadChannel,
     ) -> Result<DownloadChannel, <Self::J as Job>::OE> {
@@ -632,9 +993,7 @@ impl Order for DownloadOrder {
     }
 
     fn is_cacheable(&self) -> bool {
-        !(self.requested_path.to_str().ends_with(".db") ||
-          self.requested_path.to_str().ends_with(".files") ||
-          self.requested_path.to_str().ends_with(".db.sig"))
+        !is_database_file(&self.requested_path)
     }
 
     fn retryable(&self) -> bool {
@@ -655,10 +1014,23 @@ impl Order for DownloadOrder {
 
 }
 
+/// Database files change whenever the repository is updated, so they are not cached like packages are.
+pub fn is_database_file(path: &StrPath) -> bool {
+    path.to_str().ends_with(".db") ||
+        path.to_str().ends_with(".files") ||
+        path.to_str().ends_with(".db.sig")
+}
+
 impl DownloadOrder {
 
     pub fn filepath(&self, properties: &MirrorConfig) -> PathBuf {
-        if self.is_cacheable() {
+        if self.database_refresh {
+            // The download is stored in a temporary file next to the cached copy, which is replaced only after
+            // the download has completed: This way, clients never receive an incomplete database.
+            let path = self.database_path(properties);
+            let filename = path.file_name().unwrap().to_str().expect("Expected all file names to be valid UTF-8");
+            path.with_file_name(format!(".{}.part", filename))
+        } else if self.is_cacheable() {
             Path::new(&properties.cache_directory).join(&self.requested_path)
         } else {
             let path = Path::join(Path::new(UNCACHEABLE_DIRECTORY), &self.requested_path);
@@ -673,6 +1045,102 @@ impl DownloadOrder {
             Path::new(UNCACHEABLE_DIRECTORY).join(filename)
         }
     }
+
+    /// The path where the database file is stored in the cache.
+    pub fn database_path(&self, properties: &MirrorConfig) -> PathBuf {
+        Path::new(&properties.cache_directory).join(&self.requested_path)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DatabaseState {
+    /// The database is stored in the cache and has been validated within the configured TTL.
+    Fresh,
+    /// The database is stored in the cache, but needs to be revalidated.
+    Stale,
+    /// The database is not stored in the cache.
+    Missing,
+}
+
+/// The CFS file of a database file is rewritten each time the database has been validated with a remote
+/// mirror, so its modification time tells us when the database was last known to be up to date.
+pub fn database_state(path: &Path, ttl: Duration) -> DatabaseState {
+    if !path.is_file() {
+        return DatabaseState::Missing;
+    }
+    let validated = cfs_path_from_pkg_path(path)
+        .and_then(|cfs_path| fs::metadata(cfs_path).ok())
+        .and_then(|metadata| metadata.modified().ok());
+    match validated.map(|v| SystemTime::now().duration_since(v)) {
+        Some(Ok(age)) if age < ttl => DatabaseState::Fresh,
+        // A modification time in the future, e.g. due to a clock change, is not trusted.
+        _ => DatabaseState::Stale,
+    }
+}
+
+/// The modification time of a cached database is the Last-Modified time sent by the mirror it was downloaded from,
+/// so the database is revalidated against the mirror's clock, not against the time it was downloaded.
+fn database_if_modified_since(path: &Path) -> Option<String> {
+    let modified = fs::metadata(path).ok()?.modified().ok()?;
+    Some(http_date(&chrono::DateTime::<chrono::Utc>::from(modified)))
+}
+
+fn install_database(channel: &mut DownloadChannel, order: &DownloadOrder, properties: &MirrorConfig, size: u64) {
+    let last_modified = channel.handle.filetime().ok().flatten()
+        .filter(|secs| *secs >= 0)
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs as u64));
+    let job_resources = channel.handle.get_mut().job_state.job_resources.as_mut().unwrap();
+    if let Err(e) = job_resources.file_state.buf_writer.flush() {
+        error!("Unable to flush database file {:?}: {:?}", order.filepath(properties), e);
+        return;
+    }
+    match last_modified {
+        None => debug!("Mirror has not sent the modification time of database {:?}", order.requested_path),
+        Some(last_modified) => {
+            if let Err(e) = job_resources.file_state.buf_writer.get_ref().set_modified(last_modified) {
+                warn!("Unable to set modification time of database {:?}: {:?}", order.filepath(properties), e);
+            }
+        }
+    }
+    let database_path = order.database_path(properties);
+    match fs::rename(order.filepath(properties), &database_path) {
+        Ok(()) => {
+            debug!("Database {:?} has been stored in cache", &database_path);
+            create_cfs_file(&database_path, size);
+        }
+        Err(e) => {
+            error!("Unable to store database {:?} in cache: {:?}", &database_path, e);
+        }
+    }
+}
+
+fn discard_database_download(order: &DownloadOrder, properties: &MirrorConfig) {
+    let path = order.filepath(properties);
+    if let Err(e) = fs::remove_file(&path) {
+        warn!("Unable to remove file {:?}: {:?}", &path, e);
+    }
+}
+
+/// Called for orders that have been cancelled. Only files that can be resumed later are kept: Empty files,
+/// database downloads and uncacheable files are removed.
+pub fn discard_cancelled_download(order: &DownloadOrder, properties: &MirrorConfig) {
+    let path = order.filepath(properties);
+    let is_empty = fs::metadata(&path).map(|m| m.len() == 0).unwrap_or(false);
+    if order.database_refresh || !order.is_cacheable() || is_empty {
+        debug!("Remove incomplete file {:?}", &path);
+        if let Err(e) = fs::remove_file(&path) {
+            warn!("Unable to remove file {:?}: {:?}", &path, e);
+        }
+    }
+}
+
+fn mark_database_validated(path: &Path) {
+    let complete_size = get_complete_size_from_cfs_file(path)
+        .or_else(|| fs::metadata(path).ok().map(|m| m.len()));
+    match complete_size {
+        None => warn!("Unable to determine size of database {:?}", path),
+        Some(size) => create_cfs_file(path, size),
+    }
 }
 
 
@@ -713,7 +1181,7 @@ impl DownloadState {
     pub fn new(
         order: DownloadOrder,
         properties: MirrorConfig,
-        tx: Sender<FlexoProgress>,
+        tx: ProgressNotifier,
         last_chance: bool,
     ) -> std::io::Result<Self> {
         let download_job_resources = DownloadJob::acquire_resources(&order, &properties, last_chance)?;
@@ -753,7 +1221,8 @@ impl Handler for DownloadState {
         match job_resources.file_state.buf_writer.write(data) {
             Ok(size) => {
                 let len = job_resources.file_state.buf_writer.get_ref().metadata().unwrap().len();
-                let _result = self.job_state.tx.send(FlexoProgress::Progress(len));
+                self.job_state.tx.send(FlexoProgress::Progress(len));
+                self.job_state.tx.record_transfer(size as u64);
                 Ok(size)
             },
             Err(e) => {
@@ -763,6 +1232,18 @@ impl Handler for DownloadState {
         }
     }
 
+    fn progress(&mut self, _dltotal: f64, _dlnow: f64, _ultotal: f64, _ulnow: f64) -> bool {
+        let header_received = match &self.job_state.job_resources {
+            Some(job_resources) => job_resources.header_state.header_success.is_some(),
+            None => false,
+        };
+        if self.job_state.tx.is_cancelled() {
+            return false;
+        }
+        // Returning false aborts the transfer, so that the next provider is attempted.
+        header_received || !self.job_state.tx.is_provider_abandoned()
+    }
+
     fn header(&mut self, data: &[u8]) -> bool {
         let job_resources = self.job_state.job_resources.as_mut().unwrap();
         job_resources.header_state.received_header.extend(data);
@@ -801,20 +1282,24 @@ impl Handler for DownloadState {
                         create_cfs_file(&path, client_content_length);
                     }
                     debug!("Sending content length: {}", client_content_length);
-                    let _ = self.job_state.tx.send(FlexoProgress::JobSize(client_content_length));
+                    self.job_state.tx.send(FlexoProgress::JobSize(client_content_length));
+                } else if code == 304 && self.job_state.order.database_refresh {
+                    // We have sent If-Modified-Since and the database stored in cache is still up to date.
+                    job_resources.header_state.header_success = Some(HeaderOutcome::Unavailable);
+                    self.job_state.tx.send(FlexoProgress::Completed);
                 }  else if code == 416 {
                     // If the requested file was already cached, but we don't know if the cached file has been
                     // downloaded completely or only partially, we send the Content-Range header in order to not
                     // download anything we already have available in cache.
                     // If the server responds with 416, we assume that the cached file was already complete.
                     job_resources.header_state.header_success = Some(HeaderOutcome::Unavailable);
-                    let _ = self.job_state.tx.send(FlexoProgress::Completed);
+                    self.job_state.tx.send(FlexoProgress::Completed);
                 } else if !job_resources.last_chance {
                     job_resources.header_state.header_success = Some(HeaderOutcome::Unavailable);
                 } else if job_resources.last_chance {
                     job_resources.header_state.header_success = Some(HeaderOutcome::Unavailable);
                     debug!("Sending FlexoProgress::Unavailable");
-                    let _ = self.job_state.tx.send(FlexoProgress::Unavailable);
+                    self.job_state.tx.send(FlexoProgress::Unavailable);
                 }
             }
             Ok(Status::Partial) => {
@@ -917,37 +1402,14 @@ pub fn rated_providers(
         Limit::Limit(l) => filtered_mirrors_unlimited.take(l).collect(),
     };
     debug!("Running latency tests on the following mirrors: {:#?}", filtered_mirror_urls);
-    let mut mirrors_with_latencies = Vec::new();
-    let request_timeout = Duration::from_millis(mirrors_auto.timeout);
-    let mut num_successes = 0;
-    let mut num_failures = 0;
-    for mirror in filtered_mirror_urls.into_iter() {
-        let is_success = match mirror_fetch::measure_latency(&mirror.url, request_timeout) {
-            Err(e) => {
-                num_failures += 1;
-                if e.code() == CURLE_OPERATION_TIMEDOUT {
-                    debug!("Skip mirror {} due to timeout.", mirror.url);
-                } else {
-                    debug!("Skip mirror {}: Latency test did not succeed: {:?}", mirror.url, e);
-                }
-                false
-            }
-            Ok(mirror_results) => {
-                mirrors_with_latencies.push((mirror, mirror_results));
-                true
-            }
-        };
-        if is_success {
-            num_successes += 1;
-        } else {
-            num_failures += 1;
-        }
-    }
-    debug!("Ran latency test on {} mirrors with {} successes and {} failures.",
-           num_successes + num_failures, num_successes, num_failures);
+    let deadline = Instant::now() + mirrors_auto.latency_test_deadline();
+    let mut mirrors_with_latencies = measure_latencies(filtered_mirror_urls, mirrors_auto, deadline);
     mirrors_with_latencies.sort_unstable_by_key(|(_, mirror_result)| {
         *mirror_result
     });
+    if let Some(num_mirrors) = mirrors_auto.bandwidth_probe_num_mirrors {
+        probe_bandwidth(&mut mirrors_with_latencies, num_mirrors, mirrors_auto, deadline);
+    }
 
     mirrors_with_latencies.into_iter().map(|(mirror, mirror_results)| {
         DownloadProvider {
@@ -959,48 +1421,135 @@ pub fn rated_providers(
     }).collect()
 }
 
-pub fn read_client_header<T>(client_stream: &mut T) -> Result<ClientResponse, ClientError> where T: Read {
-    let mut buf = [0; MAX_HEADER_SIZE + 1];
-    let mut size_read_all = 0;
-
-    loop {
-        if size_read_all >= MAX_HEADER_SIZE {
-            return Err(ClientError::BufferSizeExceeded);
-        }
-        let size = match client_stream.read(&mut buf[size_read_all..]) {
-            Ok(0) => {
-                // we need this branch in case the socket is closed: Otherwise, we would read a size of 0 indefinitely.
-                return Ok(ClientResponse::SocketClosed);
+/// Runs the latency tests on up to latency_test_parallelism mirrors at a time. Mirrors that have not been tested when
+/// the deadline expires are skipped, so that a few unresponsive mirrors cannot delay the startup indefinitely.
+fn measure_latencies(
+    mirrors: Vec<Mirror>,
+    mirrors_auto: &MirrorsAutoConfig,
+    deadline: Instant,
+) -> Vec<(Mirror, MirrorResults)> {
+    let num_mirrors = mirrors.len();
+    let request_timeout = Duration::from_millis(mirrors_auto.timeout);
+    let mirrors = Arc::new(Mutex::new(mirrors.into_iter()));
+    let (tx, rx) = mpsc::channel();
+    for _ in 0..mirrors_auto.latency_test_parallelism().min(num_mirrors) {
+        let mirrors = Arc::clone(&mirrors);
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            let mirror = mirrors.lock().unwrap().next();
+            let mirror = match mirror {
+                Some(mirror) if Instant::now() < deadline => mirror,
+                _ => break,
+            };
+            let result = mirror_fetch::measure_latency(&mirror.url, request_timeout);
+            if tx.send((mirror, result)).is_err() {
+                // The deadline has expired, so the results are no longer needed.
+                break;
             }
-            Ok(s) if s > MAX_HEADER_SIZE => return Err(ClientError::BufferSizeExceeded),
-            Ok(s) => s,
-            Err(e) => {
-                let error = match e.kind() {
-                    ErrorKind::TimedOut => ClientError::TimedOut,
-                    ErrorKind::WouldBlock => ClientError::TimedOut,
-                    other => ClientError::Other(other),
-                };
-                return Err(error);
+        });
+    }
+    drop(tx);
+    let mut mirrors_with_latencies = Vec::new();
+    let mut num_failures = 0;
+    loop {
+        match rx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((mirror, Ok(mirror_results))) => {
+                mirrors_with_latencies.push((mirror, mirror_results));
             }
-        };
-        size_read_all += size;
-
-        let mut headers: [Header; 64] = [httparse::EMPTY_HEADER; MAX_HEADER_COUNT];
-        let mut req: httparse::Request = httparse::Request::new(&mut headers);
-        let res: std::result::Result<httparse::Status<usize>, httparse::Error> = req.parse(&buf[..size_read_all]);
-
-        match res {
-            Ok(Status::Complete(_result)) => {
-                debug!("Received header from client");
-                break(Ok(ClientResponse::Request(Request::new(req)?)))
+            Ok((mirror, Err(e))) => {
+                num_failures += 1;
+                if e.code() == CURLE_OPERATION_TIMEDOUT {
+                    debug!("Skip mirror {} due to timeout.", mirror.url);
+                } else {
+                    debug!("Skip mirror {}: Latency test did not succeed: {:?}", mirror.url, e);
+                }
             }
-            Ok(Status::Partial) => {
-                {}
+            Err(RecvTimeoutError::Timeout) => {
+                let num_skipped = num_mirrors - mirrors_with_latencies.len() - num_failures;
+                warn!("The latency test did not complete within {:?}: {} mirrors have been skipped.",
+                      mirrors_auto.latency_test_deadline(), num_skipped);
+                break;
             }
-            Err(_) => {
-                let client_status = ClientStatus { response_headers_sent: false };
-                break Err(ClientError::InvalidHeader(client_status))
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    debug!("Ran latency test on {} mirrors with {} successes and {} failures.",
+           mirrors_with_latencies.len() + num_failures, mirrors_with_latencies.len(), num_failures);
+    mirrors_with_latencies
+}
+
+/// Measures the throughput of the first mirrors, which are expected to be sorted by their latency, and reorders them
+/// by their throughput. All probes run concurrently and are aborted when the deadline of the latency test expires.
+fn probe_bandwidth(
+    mirrors: &mut [(Mirror, MirrorResults)],
+    num_mirrors: usize,
+    mirrors_auto: &MirrorsAutoConfig,
+    deadline: Instant,
+) {
+    let timeout = BANDWIDTH_PROBE_TIMEOUT.min(deadline.saturating_duration_since(Instant::now()));
+    if timeout == Duration::from_secs(0) {
+        warn!("The latency test did not complete within {:?}: The bandwidth probe is skipped.",
+              mirrors_auto.latency_test_deadline());
+        return;
+    }
+    let size = mirrors_auto.bandwidth_probe_size();
+    let probes: Vec<_> = mirrors.iter().take(num_mirrors).map(|(mirror, _)| {
+        let url = mirror.url.clone();
+        let path = mirrors_auto.bandwidth_probe_path().to_owned();
+        thread::spawn(move || mirror_fetch::measure_throughput(&url, &path, size, timeout))
+    }).collect();
+    for ((mirror, mirror_results), probe) in mirrors.iter_mut().zip(probes) {
+        match probe.join().unwrap() {
+            Ok(Some(throughput)) => {
+                debug!("Bandwidth probe of mirror {}: {} bytes/s", mirror.url, throughput);
+                mirror_results.throughput = Some(throughput);
             }
+            Ok(None) => debug!("Bandwidth probe of mirror {} did not receive enough data.", mirror.url),
+            Err(e) => debug!("Bandwidth probe of mirror {} did not succeed: {:?}", mirror.url, e),
+        }
+    }
+    let mut probed_mirrors: Vec<&mut MirrorResults> = mirrors.iter_mut()
+        .map(|(_, mirror_results)| mirror_results)
+        .filter(|mirror_results| mirror_results.throughput.is_some())
+        .collect();
+    rank_by_throughput(&mut probed_mirrors);
+    mirrors.sort_by_key(|(_, mirror_results)| *mirror_results);
+}
+
+/// Assigns the positions of the given mirrors among each other by their throughput. The positions of all other
+/// mirrors, including those whose bandwidth probe did not succeed, remain unchanged.
+fn rank_by_throughput(probed_mirrors: &mut [&mut MirrorResults]) {
+    let mut latencies: Vec<Duration> = probed_mirrors.iter().map(|mirror_results| mirror_results.latency()).collect();
+    latencies.sort();
+    probed_mirrors.sort_by_key(|mirror_results| Reverse(mirror_results.throughput));
+    for (mirror_results, latency) in probed_mirrors.iter_mut().zip(latencies) {
+        mirror_results.ranking_latency = Some(latency);
+    }
+}
+
+/// Parses the header the client has sent so far. Returns None if the header is not complete yet.
+pub fn parse_client_header(buf: &[u8]) -> Result<Option<Request>, ClientError> {
+    if buf.len() > MAX_HEADER_SIZE {
+        return Err(ClientError::BufferSizeExceeded);
+    }
+    let mut headers: [Header; 64] = [httparse::EMPTY_HEADER; MAX_HEADER_COUNT];
+    let mut req: httparse::Request = httparse::Request::new(&mut headers);
+    let res: std::result::Result<httparse::Status<usize>, httparse::Error> = req.parse(buf);
+
+    match res {
+        Ok(Status::Complete(_result)) => {
+            debug!("Received header from client");
+            Ok(Some(Request::new(req)?))
+        }
+        Ok(Status::Partial) if buf.len() >= MAX_HEADER_SIZE => {
+            Err(ClientError::BufferSizeExceeded)
+        }
+        Ok(Status::Partial) => {
+            Ok(None)
+        }
+        Err(_) => {
+            let client_status = ClientStatus { response_headers_sent: false };
+            Err(ClientError::InvalidHeader(client_status))
         }
     }
 }
@@ -1033,23 +1582,10 @@ pub fn size_to_human_readable(size_in_bytes: u64) -> String {
 
 #[cfg(test)]
 mod tests {
-    use std::io::Error;
+    use std::io::{Error, Read};
 
     use super::*;
 
-    struct TooMuchDataReader {}
-    impl Read for TooMuchDataReader {
-        fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
-            // Notice that we cause an error by writing the exact amount of the maximum header size,
-            // has received this exact amount of bytes, or if it has received more than 8192 bytes but the returned
-            // value is 8192 because that is the maximum buffer size. So we cautiously assume the latter case and
-            // return an error.
-            let too_much_data = [0; MAX_HEADER_SIZE + 1];
-            buf[..too_much_data.len()].copy_from_slice(&too_much_data);
-            Ok(MAX_HEADER_SIZE + 1)
-        }
-    }
-
     // writes a single byte at a time.
     struct OneByteReader {
         size_read: usize,
@@ -1074,13 +1610,223 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_head_request_accepted() {
+        let header = "HEAD /core/os/x86_64/core.db HTTP/1.1\r\nHost: www.example.com\r\n\r\n".as_bytes();
+        let result = parse_client_header(header);
+        let expected = Request {
+            byte_ranges: None,
+            conditional_headers: Default::default(),
+            path: StrPath::new("/core/os/x86_64/core.db".to_owned()),
+            method: RequestMethod::Head,
+            authorization: None,
+            user_agent: None,
+        };
+        assert_eq!(result, Ok(Some(expected)));
+    }
+
+    #[test]
+    fn test_invalid_authorization_ignored() {
+        let header = b"GET /core/os/x86_64/core.db HTTP/1.1\r\nAuthorization: Bearer \xff\r\n\r\n";
+        let request = parse_client_header(header).unwrap().unwrap();
+        assert_eq!(request.authorization, None);
+    }
+
+    #[test]
+    fn test_invalid_user_agent_accepted() {
+        let header = b"GET /core/os/x86_64/core.db HTTP/1.1\r\nUser-Agent: pacman/\xff\r\n\r\n";
+        let request = parse_client_header(header).unwrap().unwrap();
+        assert_eq!(request.user_agent.as_deref(), Some("pacman/\u{fffd}"));
+    }
+
+    #[test]
+    fn test_invalid_range_ignored() {
+        for range in &["bytes=200-100", "items=0-1", "bytes=abc"] {
+            let header = format!("GET /core/os/x86_64/core.db HTTP/1.1\r\nRange: {}\r\n\r\n", range);
+            let request = parse_client_header(header.as_bytes()).unwrap().unwrap();
+            assert_eq!(request.byte_ranges, None);
+        }
+    }
+
+    #[test]
+    fn test_parse_range_header_value() {
+        assert_eq!(parse_range_header_value("bytes=100-"), Ok(vec![ByteRange::FromOffset { first: 100, last: None }]));
+        assert_eq!(parse_range_header_value("bytes=100-199"),
+                   Ok(vec![ByteRange::FromOffset { first: 100, last: Some(199) }]));
+        assert_eq!(parse_range_header_value("bytes=-500"), Ok(vec![ByteRange::Suffix(500)]));
+        assert_eq!(parse_range_header_value("bytes=0-0, -1"),
+                   Ok(vec![ByteRange::FromOffset { first: 0, last: Some(0) }, ByteRange::Suffix(1)]));
+        assert!(parse_range_header_value("bytes=200-100").is_err());
+        assert!(parse_range_header_value("items=0-1").is_err());
+        assert!(parse_range_header_value("bytes=").is_err());
+    }
+
+    #[test]
+    fn test_resolve_byte_ranges() {
+        let bounded = ByteRange::FromOffset { first: 100, last: Some(199) };
+        assert_eq!(bounded.resolve(1000), Some(SatisfiableRange { first: 100, last: 199 }));
+        assert_eq!(bounded.resolve(150), Some(SatisfiableRange { first: 100, last: 149 }));
+        assert_eq!(bounded.resolve(100), None);
+        assert_eq!(ByteRange::Suffix(500).resolve(1000), Some(SatisfiableRange { first: 500, last: 999 }));
+        assert_eq!(ByteRange::Suffix(5000).resolve(1000), Some(SatisfiableRange { first: 0, last: 999 }));
+        assert_eq!(ByteRange::Suffix(0).resolve(1000), None);
+        let unsatisfiable = ByteRange::FromOffset { first: 2000, last: None };
+        assert_eq!(resolve_byte_ranges(&[unsatisfiable], 1000), None);
+        assert_eq!(resolve_byte_ranges(&[unsatisfiable, bounded], 1000),
+                   Some(vec![SatisfiableRange { first: 100, last: 199 }]));
+    }
+
+    #[test]
+    fn test_overlapping_byte_ranges_coalesced() {
+        let complete_file = ByteRange::FromOffset { first: 0, last: None };
+        assert_eq!(resolve_byte_ranges(&[complete_file; 3], 1000),
+                   Some(vec![SatisfiableRange { first: 0, last: 999 }]));
+        let ranges = [
+            ByteRange::FromOffset { first: 500, last: Some(599) },
+            ByteRange::FromOffset { first: 0, last: Some(99) },
+            ByteRange::FromOffset { first: 100, last: Some(149) },
+            ByteRange::FromOffset { first: 550, last: Some(649) },
+            ByteRange::Suffix(100),
+        ];
+        assert_eq!(resolve_byte_ranges(&ranges, 1000), Some(vec![
+            SatisfiableRange { first: 0, last: 149 },
+            SatisfiableRange { first: 500, last: 649 },
+            SatisfiableRange { first: 900, last: 999 },
+        ]));
+    }
+
+    #[test]
+    fn test_conditional_headers() {
+        let modified = std::time::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let validators = Validators::new(modified, 4096);
+        assert_eq!(validators.etag, "\"5f5e1000-1000\"");
+        assert_eq!(validators.headers()[0], "Last-Modified: Sun, 13 Sep 2020 12:26:40 GMT");
+        let not_modified = |if_none_match: Option<&str>, if_modified_since: Option<&str>| {
+            let conditional_headers = ConditionalHeaders {
+                if_none_match: if_none_match.map(str::to_owned),
+                if_modified_since: if_modified_since.map(str::to_owned),
+            };
+            conditional_headers.is_not_modified(&validators)
+        };
+        assert!(!not_modified(None, None));
+        assert!(not_modified(Some("\"5f5e1000-1000\""), None));
+        assert!(not_modified(Some("\"foo\", W/\"5f5e1000-1000\""), None));
+        assert!(not_modified(Some("*"), None));
+        assert!(!not_modified(Some("\"5f5e1000-fff\""), Some("Sun, 13 Sep 2020 12:26:40 GMT")));
+        assert!(not_modified(None, Some("Sun, 13 Sep 2020 12:26:40 GMT")));
+        assert!(!not_modified(None, Some("Sun, 13 Sep 2020 12:26:39 GMT")));
+        assert!(!not_modified(None, Some("yesterday")));
+    }
+
+    #[test]
+    fn test_download_order_equality() {
+        let order = |path: &str, database_refresh: bool| DownloadOrder {
+            requested_path: StrPath::new(path.to_owned()),
+            id: Uuid::new_v4(),
+            database_refresh,
+        };
+        assert_ne!(order("core/os/x86_64/zstd-1.5.0-1-x86_64.pkg.tar.zst", false),
+                   order("core/os/x86_64/zstd-1.5.0-1-x86_64.pkg.tar.zst", false));
+        assert_ne!(order("core/os/x86_64/core.db", false), order("core/os/x86_64/core.db", false));
+        assert_eq!(order("core/os/x86_64/core.db", true), order("core/os/x86_64/core.db", true));
+        assert_ne!(order("core/os/x86_64/core.db", true), order("core/os/x86_64/core.files", true));
+    }
+
+    /// Serves a database that was last modified at the given time. Replies with 304 to clients whose copy is
+    /// not older, as a mirror would.
+    fn serve_database(content: &'static [u8], last_modified: SystemTime) -> DownloadProvider {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = Vec::new();
+                let request = loop {
+                    let mut chunk = [0; 1024];
+                    let size = stream.read(&mut chunk).unwrap();
+                    buf.extend_from_slice(&chunk[..size]);
+                    if let Some(request) = parse_client_header(&buf).unwrap() {
+                        break request;
+                    }
+                };
+                let validators = Validators::new(last_modified, content.len() as u64);
+                if request.conditional_headers.is_not_modified(&validators) {
+                    stream.write_all(b"HTTP/1.1 304 Not Modified\r\nConnection: close\r\n\r\n").unwrap();
+                } else {
+                    let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n{}\r\nConnection: close\r\n\r\n",
+                                         content.len(), validators.headers().join("\r\n"));
+                    stream.write_all(header.as_bytes()).unwrap();
+                    stream.write_all(content).unwrap();
+                }
+            }
+        });
+        DownloadProvider {
+            uri: uri.clone(),
+            name: uri,
+            mirror_results: MirrorResults::default(),
+            country_code: "Unknown".to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_database_revalidated_with_last_modified_of_mirror() {
+        let directory = tempfile::tempdir().unwrap();
+        let properties: MirrorConfig = toml::from_str(&format!(r#"
+            cache_directory = "{}"
+            mirrorlist_fallback_file = "/dev/null"
+            mirror_selection_method = "predefined"
+            mirrors_predefined = []
+        "#, directory.path().to_str().unwrap())).unwrap();
+        let now = SystemTime::now();
+        let p1 = serve_database(b"previous database", now - Duration::from_secs(7200));
+        // The second mirror has synchronized after we have downloaded the database from the first mirror, but its
+        // database is older than the time we have downloaded it.
+        let p2 = serve_database(b"current database", now - Duration::from_secs(3600));
+        let mut job_context: JobContext<DownloadJob> = JobContext::new(vec![p1.clone()], properties.clone());
+        let order = DownloadOrder {
+            requested_path: StrPath::new("core/os/x86_64/core.db".to_owned()),
+            id: Uuid::new_v4(),
+            database_refresh: true,
+        };
+        let mut refresh = |provider: &DownloadProvider| {
+            match job_context.try_schedule(order.clone(), Some(provider.clone()), None) {
+                ScheduleOutcome::Scheduled(ScheduledItem { join_handle, .. }) => join_handle.join().unwrap(),
+                _ => panic!("Expected the database refresh to be scheduled"),
+            };
+            fs::read(order.database_path(&properties)).unwrap()
+        };
+        assert_eq!(refresh(&p1), b"previous database");
+        assert_eq!(refresh(&p2), b"current database");
+        assert_eq!(refresh(&p1), b"current database");
+    }
+
+    #[test]
+    fn test_database_state() {
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("core.db");
+        let ttl = Duration::from_secs(300);
+        assert_eq!(database_state(&path, ttl), DatabaseState::Missing);
+        fs::write(&path, b"database").unwrap();
+        assert_eq!(database_state(&path, ttl), DatabaseState::Stale);
+        mark_database_validated(&path);
+        assert_eq!(get_complete_size_from_cfs_file(&path), Some(8));
+        assert_eq!(database_state(&path, ttl), DatabaseState::Fresh);
+        assert_eq!(database_state(&path, Duration::from_secs(0)), DatabaseState::Stale);
+    }
+
     #[test]
     fn test_buffer_size_exceeded() {
-        let mut stream = TooMuchDataReader {};
-        let result = read_client_header(&mut stream);
+        let too_much_data = [0; MAX_HEADER_SIZE + 1];
+        let result = parse_client_header(&too_much_data);
         assert_eq!(result, Err(ClientError::BufferSizeExceeded));
     }
 
+    #[test]
+    fn test_incomplete_header() {
+        let header = &TEST_REQUEST_HEADER[..TEST_REQUEST_HEADER.len() - 2];
+        assert_eq!(parse_client_header(header), Ok(None));
+    }
+
     #[test]
     fn test_formatting_two_kilobytes() {
         let result = size_to_human_readable(2048);
@@ -1104,4 +1850,20 @@ mod tests {
         let result = size_to_human_readable(2);
         assert_eq!(result, "2.00 B");
     }
+
+    #[test]
+    fn test_probed_mirrors_ordered_by_throughput() {
+        let latency = |millis: u64| MirrorResults {
+            total_time: Duration::from_millis(millis),
+            ..MirrorResults::default()
+        };
+        let mut slow = MirrorResults { throughput: Some(1_000_000), ..latency(50) };
+        let probe_failed = latency(60);
+        let mut fast = MirrorResults { throughput: Some(5_000_000), ..latency(70) };
+        let not_probed = latency(80);
+        rank_by_throughput(&mut [&mut slow, &mut fast]);
+        let mut results = vec![not_probed, slow, probe_failed, fast];
+        results.sort();
+        assert_eq!(results, vec![fast, probe_failed, slow, not_probed]);
+    }
 }
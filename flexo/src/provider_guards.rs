@@ -17,7 +17,12 @@ impl <P> ProviderGuards<P> where P: Debug {
         }
     }
 
-    pub fn get_provider_guard<F, O>(&self, f: F) -> (ProviderGuard<P>, usize)
+    pub fn providers(&self) -> Vec<P> where P: Clone {
+        self.guards.iter().map(|g| (*g.guarded_provider).clone()).collect()
+    }
+
+    /// Returns None if all providers are excluded.
+    pub fn get_provider_guard<F, O>(&self, f: F) -> Option<(ProviderGuard<P>, usize)>
         where F: Fn(&P) -> ProviderChoice<O>, O: Ord + Copy
     {
         let _lock = self.mutex.lock().unwrap();
@@ -30,13 +35,13 @@ impl <P> ProviderGuards<P> where P: Debug {
             }).collect::<Vec<(&ProviderGuard<P>, O)>>();
         let (guard, _) = intermediate.iter().min_by_key(|(g, o)| {
             (g.num_current_usages(), *o)
-        }).unwrap();
+        })?;
         debug!("Selected {:?}, number of usages: {} [{:?}]",
                  &guard.guarded_provider, guard.num_current_usages(), std::thread::current().id());
         let guard = ProviderGuard {
             guarded_provider: Arc::clone(&guard.guarded_provider)
         };
-        (guard, intermediate.len())
+        Some((guard, intermediate.len()))
     }
 }
 
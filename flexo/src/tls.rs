@@ -0,0 +1,201 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, ErrorKind};
+use std::io::prelude::*;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use rustls::{Certificate, PrivateKey, ServerConfig, ServerConnection};
+use rustls_pemfile::Item;
+
+#[derive(Debug)]
+pub enum TlsError {
+    IoError(io::Error),
+    NoCertificate,
+    NoPrivateKey,
+    RustlsError(rustls::Error),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsError::IoError(e) => write!(f, "{}", e),
+            TlsError::NoCertificate => write!(f, "No certificate found"),
+            TlsError::NoPrivateKey => write!(f, "No private key found"),
+            TlsError::RustlsError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for TlsError {
+    fn from(error: io::Error) -> Self {
+        TlsError::IoError(error)
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(error: rustls::Error) -> Self {
+        TlsError::RustlsError(error)
+    }
+}
+
+/// Creates the TLS sessions for new connections. The certificate and the private key are reloaded as soon as one of
+/// the files has been modified, so that renewed certificates are used without restarting Flexo.
+pub struct TlsAcceptor {
+    certificate_path: String,
+    private_key_path: String,
+    state: Mutex<TlsState>,
+}
+
+struct TlsState {
+    modified: (Option<SystemTime>, Option<SystemTime>),
+    config: Arc<ServerConfig>,
+}
+
+impl TlsAcceptor {
+    pub fn new(certificate_path: &str, private_key_path: &str) -> Result<Self, TlsError> {
+        let modified = (modified(certificate_path), modified(private_key_path));
+        let config = Arc::new(server_config(certificate_path, private_key_path)?);
+        Ok(TlsAcceptor {
+            certificate_path: certificate_path.to_owned(),
+            private_key_path: private_key_path.to_owned(),
+            state: Mutex::new(TlsState { modified, config }),
+        })
+    }
+
+    pub fn accept(&self, tcp: TcpStream) -> Result<TlsStream, TlsError> {
+        let config = self.current_config();
+        let session = ServerConnection::new(config)?;
+        Ok(TlsStream { tcp, session })
+    }
+
+    fn current_config(&self) -> Arc<ServerConfig> {
+        let mut state = self.state.lock().unwrap();
+        let modified = (modified(&self.certificate_path), modified(&self.private_key_path));
+        if modified != state.modified {
+            // Update the timestamps even if reloading fails, so that we don't attempt to reload on every connection
+            // while the files are being replaced.
+            state.modified = modified;
+            match server_config(&self.certificate_path, &self.private_key_path) {
+                Ok(config) => {
+                    info!("TLS certificate {} has been reloaded", &self.certificate_path);
+                    state.config = Arc::new(config);
+                }
+                Err(e) => {
+                    error!("Unable to reload TLS certificate, continue with the previous certificate: {}", e);
+                }
+            }
+        }
+        state.config.clone()
+    }
+}
+
+fn modified(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn server_config(certificate_path: &str, private_key_path: &str) -> Result<ServerConfig, TlsError> {
+    let mut certificate_reader = BufReader::new(File::open(certificate_path)?);
+    let certificates = rustls_pemfile::certs(&mut certificate_reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect::<Vec<Certificate>>();
+    if certificates.is_empty() {
+        return Err(TlsError::NoCertificate);
+    }
+    let mut private_key_reader = BufReader::new(File::open(private_key_path)?);
+    let private_key = rustls_pemfile::read_all(&mut private_key_reader)?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(TlsError::NoPrivateKey)?;
+    let mut config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certificates, private_key)?;
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// An encrypted connection to a client. Works with both blocking and non-blocking sockets.
+pub struct TlsStream {
+    tcp: TcpStream,
+    session: ServerConnection,
+}
+
+impl TlsStream {
+    pub fn tcp(&self) -> &TcpStream {
+        &self.tcp
+    }
+
+    /// Returns true if TLS records are waiting to be sent, e.g. because the socket would have blocked during the
+    /// handshake.
+    pub fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+
+    pub fn shutdown(&mut self, how: Shutdown) -> io::Result<()> {
+        self.session.send_close_notify();
+        let _ = self.write_buffered();
+        self.tcp.shutdown(how)
+    }
+
+    /// Sends the TLS records that have not been sent yet.
+    fn write_buffered(&mut self) -> io::Result<()> {
+        while self.session.wants_write() {
+            self.session.write_tls(&mut self.tcp)?;
+        }
+        Ok(())
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.session.reader().read(buf) {
+                Ok(size) => return Ok(size),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            // During the handshake, the client waits for our reply before it sends more data.
+            match self.write_buffered() {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+            if self.session.read_tls(&mut self.tcp)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.session.process_new_packets() {
+                // Attempt to send the alert to the client before the connection is closed.
+                let _ = self.write_buffered();
+                return Err(io::Error::new(ErrorKind::InvalidData, e));
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Previous records need to be sent before we accept more data, otherwise, the buffer would keep growing if
+        // the client is slower than we are.
+        self.write_buffered()?;
+        let size = self.session.writer().write(buf)?;
+        match self.write_buffered() {
+            Ok(()) => Ok(size),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(size),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.session.writer().flush()?;
+        self.write_buffered()?;
+        self.tcp.flush()
+    }
+}
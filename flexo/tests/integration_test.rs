@@ -3,7 +3,8 @@ extern crate rand;
 
 use flexo::*;
 use std::collections::HashMap;
-use crossbeam::channel::{Sender, Receiver};
+use std::time::{Duration, SystemTime};
+use crossbeam::channel::Receiver;
 
 static EXPECT_SCHEDULED: &str = "Expected the job to be scheduled";
 static EXPECT_SKIPPED: &str = "Expected the job to be skipped";
@@ -22,6 +23,8 @@ enum DummyProvider {
     Success(DummyProviderItem),
     PartialCompletion(DummyProviderItem),
     Failure(DummyProviderItem),
+    /// A provider which does not reply until it is abandoned or the job is cancelled.
+    Unresponsive(DummyProviderItem),
 }
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug)]
@@ -47,6 +50,7 @@ impl Provider for DummyProvider {
             DummyProvider::Success(p) => p.score,
             DummyProvider::Failure(p) => p.score,
             DummyProvider::PartialCompletion(p) => p.score,
+            DummyProvider::Unresponsive(p) => p.score,
         }
     }
 
@@ -55,6 +59,7 @@ impl Provider for DummyProvider {
             DummyProvider::Success(DummyProviderItem { identifier, .. } ) => identifier,
             DummyProvider::PartialCompletion(DummyProviderItem { identifier, .. } ) => identifier,
             DummyProvider::Failure(DummyProviderItem { identifier, .. } ) => identifier,
+            DummyProvider::Unresponsive(DummyProviderItem { identifier, .. } ) => identifier,
         };
         let identifier = format!("DummyProvider {}", i);
         ProviderIdentifier {
@@ -118,10 +123,17 @@ impl Job for DummyJob {
                 JobResult::Partial(JobPartiallyCompleted { channel, continue_at: 1 })
             },
             (DummyOrder::InfiniteBlocking(_), DummyProvider::Success(_)) => {
-                let _result = channel.collector.tx.send(FlexoProgress::Progress(0));
+                channel.collector.tx.send(FlexoProgress::Progress(0));
                 std::thread::park(); // block forever.
                 JobResult::Complete(JobCompleted::new(channel, self.provider, 1))
             }
+            (_, DummyProvider::Unresponsive(_)) => {
+                let tx = &channel.collector.tx;
+                while !tx.is_provider_abandoned() && !tx.is_cancelled() {
+                    std::thread::sleep(std::time::Duration::from_millis(1));
+                }
+                JobResult::Error(JobTerminated { channel, error: DummyJobError {} })
+            }
             (DummyOrder::Panic(_), _) => panic!("{}", ORDER_PANIC),
             _ => JobResult::Error(JobTerminated { channel, error: DummyJobError {} }),
         }
@@ -151,7 +163,7 @@ enum DummyOrder {
 impl Order for DummyOrder {
     type J = DummyJob;
 
-    fn new_channel(self, _properties: <<Self as Order>::J as Job>::PR, tx: Sender<FlexoProgress>, _last_chance: bool) -> Result<DummyChannel, DummyOrderError> {
+    fn new_channel(self, _properties: <<Self as Order>::J as Job>::PR, tx: ProgressNotifier, _last_chance: bool) -> Result<DummyChannel, DummyOrderError> {
         Ok(DummyChannel {
             handle: 1,
             collector: JobState {
@@ -163,7 +175,7 @@ impl Order for DummyOrder {
         })
     }
 
-    fn reuse_channel(self, properties: <<Self as Order>::J as Job>::PR, tx: Sender<FlexoProgress>, last_chance: bool, _channel: DummyChannel) -> Result<DummyChannel, DummyOrderError> {
+    fn reuse_channel(self, properties: <<Self as Order>::J as Job>::PR, tx: ProgressNotifier, last_chance: bool, _channel: DummyChannel) -> Result<DummyChannel, DummyOrderError> {
         self.new_channel(properties, tx, last_chance)
     }
 
@@ -227,7 +239,7 @@ fn wait_until_message_received <F, R>(
 
 fn wait_until_provider_selected(schedule_outcome: ScheduleOutcome<DummyJob>) -> ProviderIdentifier {
     match schedule_outcome {
-        ScheduleOutcome::Scheduled(ScheduledItem { join_handle: _, rx_integration_test: rx, rx_progress: _ }) => {
+        ScheduleOutcome::Scheduled(ScheduledItem { join_handle: _, rx_integration_test: rx, rx_progress: _, progress: _ }) => {
             let message_cmp = |msg: &IntegrationTestMessage| {
                 match msg {
                     IntegrationTestMessage::ProviderSelected(p) => Some(p.clone()),
@@ -242,7 +254,7 @@ fn wait_until_provider_selected(schedule_outcome: ScheduleOutcome<DummyJob>) ->
 
 fn wait_until_channel_established(schedule_outcome: ScheduleOutcome<DummyJob>) {
     match schedule_outcome {
-        ScheduleOutcome::Scheduled(ScheduledItem { join_handle: _, rx_integration_test: rx, rx_progress: _  }) => {
+        ScheduleOutcome::Scheduled(ScheduledItem { join_handle: _, rx_integration_test: rx, rx_progress: _, progress: _ }) => {
             let message_cmp = |msg: &IntegrationTestMessage| {
                 match msg {
                     IntegrationTestMessage::ChannelEstablished(_) => Some(true),
@@ -257,7 +269,7 @@ fn wait_until_channel_established(schedule_outcome: ScheduleOutcome<DummyJob>) {
 
 fn wait_until_job_completed(schedule_outcome: ScheduleOutcome<DummyJob>) -> DummyJobSuccess {
     let result = match schedule_outcome {
-        ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx_integration_test: _, rx_progress: _  }) => {
+        ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx_integration_test: _, rx_progress: _, progress: _ }) => {
             join_handle.join().unwrap()
         },
         _ => panic!("{}", EXPECT_SCHEDULED),
@@ -270,7 +282,7 @@ fn wait_until_job_completed(schedule_outcome: ScheduleOutcome<DummyJob>) -> Dumm
 
 fn wait_until_job_failed(schedule_outcome: ScheduleOutcome<DummyJob>) -> DummyJobFailure {
     let result = match schedule_outcome {
-        ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx_integration_test: _, rx_progress: _  }) => {
+        ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx_integration_test: _, rx_progress: _, progress: _ }) => {
             join_handle.join().unwrap()
         },
         _ => panic!("{}", EXPECT_SCHEDULED),
@@ -428,7 +440,7 @@ fn order_skipped_if_already_in_progress() {
     wait_until_provider_selected(job_context.try_schedule(order, None, None));
 
     match job_context.try_schedule(order, None, None) {
-        ScheduleOutcome::AlreadyInProgress =>
+        ScheduleOutcome::AlreadyInProgress(_) =>
             {}
         ScheduleOutcome::Scheduled(_) =>
             panic!("{}", EXPECT_SKIPPED),
@@ -436,6 +448,8 @@ fn order_skipped_if_already_in_progress() {
             panic!("{}", EXPECT_SKIPPED),
         ScheduleOutcome::Uncacheable(_) =>
             panic!("{}", EXPECT_SKIPPED),
+        ScheduleOutcome::Offline =>
+            panic!("{}", EXPECT_SKIPPED),
     }
 }
 
@@ -517,6 +531,113 @@ fn downgrade_provider() {
     assert_eq!(first_provider_selected, p2.identifier());
 }
 
+#[test]
+fn restored_failures_downgrade_provider() {
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
+    let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 2 });
+    let p3 = DummyProvider::Success(DummyProviderItem { identifier: 3, score: 3 });
+    let mut job_context: JobContext<DummyJob> = JobContext::new(vec![p1, p2], DummyProperties{});
+    let failures = ProviderMetrics {
+        num_usages: 1,
+        num_failures: 1,
+        failure_penalty: 1.0,
+        failure_penalty_updated: Some(SystemTime::now()),
+        ..ProviderMetrics::default()
+    };
+    let restored = vec![(p1.identifier(), failures), (p3.identifier(), failures)].into_iter().collect();
+    job_context.restore_provider_metrics(restored);
+    let metrics = job_context.provider_metrics();
+    assert!(!metrics.contains_key(&p3.identifier()));
+    let result = job_context.try_schedule(DummyOrder::Success(0), None, None);
+    assert_eq!(wait_until_provider_selected(result), p2.identifier());
+}
+
+#[test]
+fn restored_metrics_apply_to_swapped_providers() {
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
+    let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 2 });
+    let mut job_context: JobContext<DummyJob> = JobContext::new(vec![p1], DummyProperties{});
+    let result = job_context.try_schedule(DummyOrder::Success(0), None, None);
+    wait_until_job_completed(result);
+    let failures = ProviderMetrics {
+        num_usages: 5,
+        num_failures: 5,
+        ..ProviderMetrics::default()
+    };
+    let restored: HashMap<ProviderIdentifier, ProviderMetrics> =
+        vec![(p1.identifier(), failures), (p2.identifier(), failures)].into_iter().collect();
+    job_context.restore_provider_metrics(restored.clone());
+    job_context.set_providers(vec![p1, p2]);
+    job_context.restore_provider_metrics(restored);
+    let metrics = job_context.provider_metrics();
+    // The metrics recorded during this run are retained.
+    assert_eq!(metrics.get(&p1.identifier()).unwrap().num_usages, 1);
+    assert_eq!(metrics.get(&p2.identifier()).unwrap().num_failures, 5);
+}
+
+#[test]
+fn replaced_providers_do_not_disrupt_jobs_in_progress() {
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
+    let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 2 });
+    let mut job_context: JobContext<DummyJob> = JobContext::new(vec![p1], DummyProperties{});
+    let result1 = job_context.try_schedule(DummyOrder::Success(0), None, None);
+    job_context.set_providers(vec![p2]);
+    assert_eq!(wait_until_job_completed(result1).provider, p1);
+    let result2 = job_context.try_schedule(DummyOrder::Success(1), None, None);
+    assert_eq!(wait_until_provider_selected(result2), p2.identifier());
+    // The metrics of the replaced provider are retained, so that they are still available if it is selected again.
+    assert_eq!(job_context.provider_metrics().get(&p1.identifier()).unwrap().num_usages, 1);
+}
+
+#[test]
+fn circuit_breaker_excludes_provider() {
+    // Failures do not affect the ranking without a half-life, so p1 is excluded only due to its circuit breaker.
+    let p1 = DummyProvider::Failure(DummyProviderItem { identifier: 1, score: 1 });
+    let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 2 });
+    let mut job_context: JobContext<DummyJob> = JobContext::new(vec![p1, p2], DummyProperties{});
+    let failure_policy = FailurePolicy {
+        penalty_half_life: Duration::from_secs(0),
+        circuit_breaker_threshold: 1,
+        circuit_breaker_cooldown: Duration::from_secs(3600),
+    };
+    job_context.set_failure_policy(failure_policy);
+    let result1 = job_context.try_schedule(DummyOrder::Success(0), None, None);
+    wait_until_job_completed(result1);
+    let metrics = job_context.provider_metrics();
+    let p1_metrics = metrics.get(&p1.identifier()).unwrap();
+    assert_eq!(p1_metrics.circuit_state(SystemTime::now(), &failure_policy), CircuitState::Open);
+    let result2 = job_context.try_schedule(DummyOrder::Success(1), None, None);
+    assert_eq!(wait_until_provider_selected(result2), p2.identifier());
+}
+
+#[test]
+fn trial_ends_if_job_panics() {
+    let p1 = DummyProvider::Failure(DummyProviderItem { identifier: 1, score: 1 });
+    let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 2 });
+    let mut job_context: JobContext<DummyJob> = JobContext::new(vec![p1, p2], DummyProperties{});
+    let failure_policy = FailurePolicy {
+        penalty_half_life: Duration::from_secs(0),
+        circuit_breaker_threshold: 1,
+        circuit_breaker_cooldown: Duration::from_secs(0),
+    };
+    job_context.set_failure_policy(failure_policy);
+    let result1 = job_context.try_schedule(DummyOrder::Success(0), None, None);
+    wait_until_job_completed(result1);
+    let metrics = job_context.provider_metrics();
+    let p1_metrics = metrics.get(&p1.identifier()).unwrap();
+    assert_eq!(p1_metrics.circuit_state(SystemTime::now(), &failure_policy), CircuitState::HalfOpen);
+    // The trial order of p1 is neither a success nor a failure, so that another trial order is permitted.
+    let join_handle = match job_context.try_schedule(DummyOrder::Panic(1), None, None) {
+        ScheduleOutcome::Scheduled(ScheduledItem { join_handle, .. }) => join_handle,
+        _ => panic!("{}", EXPECT_SCHEDULED),
+    };
+    assert!(join_handle.join().is_err());
+    let metrics = job_context.provider_metrics();
+    let p1_metrics = metrics.get(&p1.identifier()).unwrap();
+    assert_eq!(p1_metrics.num_usages, 2);
+    assert!(!p1_metrics.trial_in_progress);
+}
+
 #[test]
 fn no_downgrade_if_all_providers_fail() {
     // Consider the case when a job cannot be completed because the file simply does not exist, or because we
@@ -536,6 +657,23 @@ fn no_downgrade_if_all_providers_fail() {
     }
 }
 
+#[test]
+fn monitoring_counters_recorded() {
+    let p1 = DummyProvider::Failure(DummyProviderItem { identifier: 1, score: 1 });
+    let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 2 });
+    let providers = vec![p1, p2];
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let result = job_context.try_schedule(DummyOrder::Success(0), None, None);
+    wait_until_job_completed(result);
+    let metrics = job_context.provider_metrics();
+    let p1_metrics = metrics.get(&p1.identifier()).unwrap();
+    assert_eq!(p1_metrics.num_errors, 1);
+    assert_eq!(p1_metrics.size_downloaded, 0);
+    let p2_metrics = metrics.get(&p2.identifier()).unwrap();
+    assert_eq!(p2_metrics.num_errors, 0);
+    assert_eq!(p2_metrics.size_downloaded, 1);
+}
+
 #[test]
 fn no_new_channel_established() {
     // channels can be reused: If a job has completed, the channel used for this job will be retained such that
@@ -559,6 +697,28 @@ fn no_new_channel_established() {
     assert_eq!(channel_establishment, ChannelEstablishment::ExistingChannel)
 }
 
+#[test]
+fn channel_retained_if_provider_selected_again() {
+    // When the mirrors are selected again, the same provider may be selected with a different score.
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 1 });
+    let mut job_context: JobContext<DummyJob> = JobContext::new(vec![p1], DummyProperties{});
+    let result1 = job_context.try_schedule(DummyOrder::Success(0), None, None);
+    wait_until_job_completed(result1);
+    job_context.set_providers(vec![DummyProvider::Success(DummyProviderItem { identifier: 1, score: 5 })]);
+    let channel_establishment = match job_context.try_schedule(DummyOrder::Success(1), None, None) {
+        ScheduleOutcome::Scheduled(p) => {
+            wait_until_message_received(p.rx_integration_test, |msg| {
+                match msg {
+                    IntegrationTestMessage::ChannelEstablished(c) => Some(*c),
+                    _ => None,
+                }
+            })
+        },
+        _ => panic!("{}", EXPECT_SCHEDULED),
+    };
+    assert_eq!(channel_establishment, ChannelEstablishment::ExistingChannel)
+}
+
 #[test]
 fn new_channel_established_because_channel_in_use() {
     // A channel can only be used for one job at any given time. If the job is still in progress,
@@ -617,3 +777,156 @@ fn read_progress() {
     };
     assert_eq!(result, FlexoProgress::Progress(0));
 }
+
+#[test]
+fn progress_broadcast_to_order_in_progress() {
+    // If an order is already in progress, the progress of the running job is also sent to all subsequent requests
+    // for the same order.
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 0 });
+    let order = DummyOrder::InfiniteBlocking(0);
+    let providers = vec![p1];
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    let rx_progress = match job_context.try_schedule(order, None, None) {
+        ScheduleOutcome::Scheduled(ScheduledItem { rx_progress, .. }) => rx_progress,
+        _ => panic!("{}", EXPECT_SCHEDULED),
+    };
+    let timeout = std::time::Duration::from_millis(50);
+    assert_eq!(rx_progress.recv_timeout(timeout).unwrap(), FlexoProgress::Progress(0));
+    let progress = match job_context.try_schedule(order, None, None) {
+        ScheduleOutcome::AlreadyInProgress(progress) => progress,
+        _ => panic!("{}", EXPECT_SKIPPED),
+    };
+    let (tx_notified, rx_notified) = crossbeam::channel::unbounded();
+    let subscription = progress.notify_on_progress(0, move || tx_notified.send(()).unwrap());
+    assert_eq!(subscription, ProgressSubscription::Subscribed);
+    let rx_subscribed = progress.subscribe();
+    progress.send(FlexoProgress::Progress(1));
+    rx_notified.recv_timeout(timeout).unwrap();
+    assert_eq!(rx_subscribed.recv_timeout(timeout).unwrap(), FlexoProgress::Progress(1));
+    assert_eq!(rx_progress.recv_timeout(timeout).unwrap(), FlexoProgress::Progress(1));
+    assert_eq!(progress.notify_on_progress(0, || {}), ProgressSubscription::ProgressMade);
+}
+
+#[test]
+fn next_provider_after_provider_abandoned() {
+    let p1 = DummyProvider::Unresponsive(DummyProviderItem { identifier: 1, score: 0 });
+    let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 1 });
+    let mut job_context: JobContext<DummyJob> = JobContext::new(vec![p1, p2], DummyProperties{});
+    let (join_handle, rx_integration_test, progress) = match job_context.try_schedule(DummyOrder::Success(0), None, None) {
+        ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx_integration_test, progress, .. }) => {
+            (join_handle, rx_integration_test, progress)
+        },
+        _ => panic!("{}", EXPECT_SCHEDULED),
+    };
+    wait_until_message_received(rx_integration_test, |msg| {
+        match msg {
+            IntegrationTestMessage::ChannelEstablished(_) => Some(()),
+            _ => None,
+        }
+    });
+    assert_eq!(progress.provider(), Some(p1.identifier()));
+    progress.abandon_provider(&p1.identifier());
+    match join_handle.join().unwrap() {
+        JobOutcome::Success(provider) => assert_eq!(provider, p2),
+        JobOutcome::Error(_) => panic!("{}", EXPECT_SUCCESS),
+    }
+    assert_eq!(progress.provider(), Some(p2.identifier()));
+    let metrics = job_context.provider_metrics();
+    assert_eq!(metrics.get(&p1.identifier()).unwrap().num_failures, 1);
+}
+
+#[test]
+fn abandon_ignored_if_provider_has_changed() {
+    let p1 = DummyProvider::Unresponsive(DummyProviderItem { identifier: 1, score: 0 });
+    let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 1 });
+    let mut job_context: JobContext<DummyJob> = JobContext::new(vec![p1, p2], DummyProperties{});
+    let (join_handle, rx_integration_test, progress) = match job_context.try_schedule(DummyOrder::Success(0), None, None) {
+        ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx_integration_test, progress, .. }) => {
+            (join_handle, rx_integration_test, progress)
+        },
+        _ => panic!("{}", EXPECT_SCHEDULED),
+    };
+    wait_until_message_received(rx_integration_test, |msg| {
+        match msg {
+            IntegrationTestMessage::ChannelEstablished(_) => Some(()),
+            _ => None,
+        }
+    });
+    assert_eq!(progress.provider(), Some(p1.identifier()));
+    // A client that has waited for another provider must not cause the current provider to be abandoned.
+    progress.abandon_provider(&p2.identifier());
+    assert!(!progress.is_provider_abandoned());
+    progress.abandon_provider(&p1.identifier());
+    match join_handle.join().unwrap() {
+        JobOutcome::Success(provider) => assert_eq!(provider, p2),
+        JobOutcome::Error(_) => panic!("{}", EXPECT_SUCCESS),
+    }
+}
+
+#[test]
+fn no_next_provider_after_cancel() {
+    let p1 = DummyProvider::Unresponsive(DummyProviderItem { identifier: 1, score: 0 });
+    let p2 = DummyProvider::Success(DummyProviderItem { identifier: 2, score: 1 });
+    let mut job_context: JobContext<DummyJob> = JobContext::new(vec![p1, p2], DummyProperties{});
+    let (join_handle, rx_integration_test, progress) = match job_context.try_schedule(DummyOrder::Success(0), None, None) {
+        ScheduleOutcome::Scheduled(ScheduledItem { join_handle, rx_integration_test, progress, .. }) => {
+            (join_handle, rx_integration_test, progress)
+        },
+        _ => panic!("{}", EXPECT_SCHEDULED),
+    };
+    wait_until_message_received(rx_integration_test, |msg| {
+        match msg {
+            IntegrationTestMessage::ChannelEstablished(_) => Some(()),
+            _ => None,
+        }
+    });
+    assert_eq!(job_context.orders_in_progress().len(), 1);
+    progress.cancel();
+    match join_handle.join().unwrap() {
+        JobOutcome::Success(_) => panic!("Expected the job to be cancelled"),
+        JobOutcome::Error(_) => {},
+    }
+    assert!(job_context.orders_in_progress().is_empty());
+    // The provider is not to blame for the cancellation.
+    let metrics = job_context.provider_metrics();
+    assert_eq!(metrics.get(&p1.identifier()).unwrap().num_failures, 0);
+}
+
+#[test]
+fn peek_does_not_schedule() {
+    // Peeking at an order does not schedule it: The order can still be scheduled afterwards, and once it has been
+    // scheduled, peeking reports that the order is in progress.
+    let p1 = DummyProvider::Success(DummyProviderItem { identifier: 1, score: 0 });
+    let order = DummyOrder::InfiniteBlocking(0);
+    let providers = vec![p1];
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    match job_context.peek(&order, None, None) {
+        PeekOutcome::NotCached(guard) => assert_eq!(guard.guarded_provider.identifier(), p1.identifier()),
+        _ => panic!("Expected the order to be neither cached nor in progress"),
+    }
+    wait_until_provider_selected(job_context.try_schedule(order, None, None));
+    match job_context.peek(&order, None, None) {
+        PeekOutcome::InProgress => {},
+        _ => panic!("Expected the order to be in progress"),
+    }
+}
+
+#[test]
+fn offline_after_consecutive_failures() {
+    // Once the configured number of consecutive orders has failed at all providers, orders that would have to
+    // be fetched from a provider are no longer scheduled until offline mode is disabled again.
+    let p1 = DummyProvider::Failure(DummyProviderItem { identifier: 1, score: 0 });
+    let providers = vec![p1];
+    let mut job_context: JobContext<DummyJob> = JobContext::new(providers, DummyProperties{});
+    job_context.set_offline_after_failures(Some(2));
+    wait_until_job_failed(job_context.try_schedule(DummyOrder::Success(0), None, None));
+    assert!(!job_context.is_offline());
+    wait_until_job_failed(job_context.try_schedule(DummyOrder::Success(1), None, None));
+    assert!(job_context.is_offline());
+    match job_context.try_schedule(DummyOrder::Success(2), None, None) {
+        ScheduleOutcome::Offline => {},
+        _ => panic!("Expected the order to be rejected in offline mode"),
+    }
+    job_context.set_offline(false);
+    wait_until_job_failed(job_context.try_schedule(DummyOrder::Success(3), None, None));
+}